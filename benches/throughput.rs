@@ -0,0 +1,194 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use plao::runtime::{Handle, PluginRuntime};
+use plao::subscribers::DEFAULT_SHARDS;
+use plao::{Plugin, PluginCallResult, PluginData};
+use std::borrow::Cow;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Callers and plugins racing for the subscribers map and the result loop.
+const CONTENDED: (usize, usize) = (8, 4);
+
+/// Callers and plugins per scenario: one caller on one plugin, then the contended case.
+const SCENARIOS: &[(&str, usize, usize)] = &[("uncontended", 1, 1), ("contended", CONTENDED.0, CONTENDED.1)];
+
+#[derive(Clone)]
+struct BenchPlugin {
+    name: String,
+}
+
+#[derive(Clone)]
+struct BenchResult {}
+
+impl PluginCallResult for BenchResult {
+    type Ok = u64;
+    type Err = String;
+}
+
+impl PluginData for BenchPlugin {
+    type PluginCall = u64;
+    type PluginCallResult = BenchResult;
+
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn name_ref(&self) -> Cow<'_, str> {
+        Cow::Borrowed(&self.name)
+    }
+}
+
+fn echo_event_loop(handle: Handle<BenchPlugin>) {
+    while let Ok(call) = handle.receive() {
+        handle.resolve(call.id(), *call.call());
+    }
+}
+
+/// A runtime with its result loop and an echoing event loop on threads of their own, so the
+/// measurements don't depend on an executor.
+struct Fixture {
+    runtime: Option<PluginRuntime<BenchPlugin>>,
+    plugins: Vec<Plugin<BenchPlugin>>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl Fixture {
+    fn new(plugins: usize) -> Self {
+        Self::with_runtime(PluginRuntime::builder().build(), plugins)
+    }
+
+    fn with_runtime(mut runtime: PluginRuntime<BenchPlugin>, plugins: usize) -> Self {
+        let (result_thread, handle) = runtime.run_threaded().unwrap();
+        let event_thread = thread::spawn(move || echo_event_loop(handle));
+        let plugins = (0..plugins)
+            .map(|i| runtime.load_plugin(BenchPlugin { name: format!("bench-{}", i) }).unwrap())
+            .collect();
+        Fixture {
+            runtime: Some(runtime),
+            plugins,
+            threads: vec![result_thread, event_thread],
+        }
+    }
+
+    /// Runs exactly `calls` calls split across `callers` threads, each caller sticking to one
+    /// plugin, and returns every call's latency when `record` is set.
+    fn drive(&self, callers: usize, calls: u64, record: bool) -> (Duration, Vec<Duration>) {
+        let (share, extra) = (calls / callers as u64, calls % callers as u64);
+        let start = Instant::now();
+        let workers: Vec<_> = (0..callers)
+            .map(|i| {
+                let per_caller = share + u64::from((i as u64) < extra);
+                let plugin = self.plugins[i % self.plugins.len()].clone();
+                thread::spawn(move || {
+                    let mut latencies = Vec::with_capacity(if record { per_caller as usize } else { 0 });
+                    for call in 0..per_caller {
+                        let sent = Instant::now();
+                        let result = plugin.execute(call).unwrap();
+                        if record {
+                            latencies.push(sent.elapsed());
+                        }
+                        assert_eq!(result, Ok(call));
+                    }
+                    latencies
+                })
+            })
+            .collect();
+        let latencies = workers.into_iter().flat_map(|worker| worker.join().unwrap()).collect();
+        (start.elapsed(), latencies)
+    }
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        self.plugins.clear();
+        self.runtime.take();
+        for thread in self.threads.drain(..) {
+            thread.join().unwrap();
+        }
+    }
+}
+
+fn throughput(c: &mut Criterion) {
+    let mut group = c.benchmark_group("execute");
+    group.throughput(Throughput::Elements(1));
+    for &(scenario, callers, plugins) in SCENARIOS {
+        let fixture = Fixture::new(plugins);
+        group.bench_function(BenchmarkId::new(scenario, format!("{}x{}", callers, plugins)), |b| {
+            b.iter_custom(|calls| fixture.drive(callers, calls, false).0)
+        });
+    }
+    group.finish();
+}
+
+/// Runs the contended scenario on each runtime of `variants`, named after its setting.
+fn compare_contended(c: &mut Criterion, name: &str, variants: Vec<(&str, PluginRuntime<BenchPlugin>)>) {
+    let mut group = c.benchmark_group(name);
+    group.throughput(Throughput::Elements(1));
+    let (callers, plugins) = CONTENDED;
+    for (setting, runtime) in variants {
+        let fixture = Fixture::with_runtime(runtime, plugins);
+        group.bench_function(BenchmarkId::new(setting, format!("{}x{}", callers, plugins)), |b| {
+            b.iter_custom(|calls| fixture.drive(callers, calls, false).0)
+        });
+    }
+    group.finish();
+}
+
+/// The sharded subscribers map against the same map behind a single lock.
+fn subscriber_shards(c: &mut Criterion) {
+    compare_contended(c, "subscribers", vec![
+        ("sharded", PluginRuntime::builder().subscriber_shards(DEFAULT_SHARDS).build()),
+        ("single-lock", PluginRuntime::builder().subscriber_shards(1).build()),
+    ]);
+}
+
+/// The result loop delivering one result per wake-up against draining whatever is queued.
+fn batch_drain(c: &mut Criterion) {
+    compare_contended(c, "batch_drain", vec![
+        ("off", PluginRuntime::builder().batch_drain(false).build()),
+        ("on", PluginRuntime::builder().batch_drain(true).build()),
+    ]);
+}
+
+fn percentile(sorted: &[Duration], percent: usize) -> Duration {
+    sorted[(sorted.len() - 1) * percent / 100]
+}
+
+/// Criterion reports per-iteration means, so the tail is taken from the latencies of every
+/// call in the largest batch it ran, printed once the scenario is done. Scenarios the filter
+/// skips never run and print nothing.
+fn latency(c: &mut Criterion) {
+    let mut group = c.benchmark_group("latency");
+    for &(scenario, callers, plugins) in SCENARIOS {
+        let fixture = Fixture::new(plugins);
+        let mut latencies = Vec::new();
+        group.bench_function(BenchmarkId::new(scenario, format!("{}x{}", callers, plugins)), |b| {
+            b.iter_custom(|calls| {
+                let (elapsed, recorded) = fixture.drive(callers, calls, true);
+                if recorded.len() > latencies.len() {
+                    latencies = recorded;
+                }
+                elapsed
+            })
+        });
+        if latencies.is_empty() {
+            continue;
+        }
+        latencies.sort();
+        println!(
+            "latency/{} {}x{}: {} calls, p50 {:?}, p90 {:?}, p99 {:?}, max {:?}",
+            scenario,
+            callers,
+            plugins,
+            latencies.len(),
+            percentile(&latencies, 50),
+            percentile(&latencies, 90),
+            percentile(&latencies, 99),
+            latencies[latencies.len() - 1],
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, throughput, subscriber_shards, batch_drain, latency);
+criterion_main!(benches);
@@ -0,0 +1,54 @@
+use std::sync::{Arc, Mutex};
+
+struct Latest<C> {
+    version: u64,
+    config: Arc<C>,
+}
+
+/// Event loop side of the configuration given to `PluginRuntime::run_configured`.
+///
+/// Holds the latest value pushed by `PluginRuntime::reconfigure`. Updates don't interrupt the
+/// loop: it picks them up with `changed` whenever it checks, typically between two calls or
+/// after a `Handle::receive_batch` timeout. Clones track what they've seen independently.
+pub struct ConfigWatch<C> {
+    latest: Arc<Mutex<Latest<C>>>,
+    seen: u64,
+}
+
+impl<C> Clone for ConfigWatch<C> {
+    fn clone(&self) -> Self {
+        ConfigWatch {
+            latest: self.latest.clone(),
+            seen: self.seen,
+        }
+    }
+}
+
+impl<C> ConfigWatch<C> {
+    pub(crate) fn new(config: C) -> Self {
+        ConfigWatch {
+            latest: Arc::new(Mutex::new(Latest { version: 0, config: Arc::new(config) })),
+            seen: 0,
+        }
+    }
+
+    pub fn current(&self) -> Arc<C> {
+        self.latest.lock().unwrap().config.clone()
+    }
+
+    /// The latest configuration if it changed since this watch last looked.
+    pub fn changed(&mut self) -> Option<Arc<C>> {
+        let latest = self.latest.lock().unwrap();
+        if latest.version == self.seen {
+            return None;
+        }
+        self.seen = latest.version;
+        Some(latest.config.clone())
+    }
+
+    pub(crate) fn publish(&self, config: C) {
+        let mut latest = self.latest.lock().unwrap();
+        latest.version += 1;
+        latest.config = Arc::new(config);
+    }
+}
@@ -0,0 +1,107 @@
+use crate::{PluginError, PluginResult};
+use crate::runtime::panic_reason;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::thread;
+
+/// Runs the work the crate spawns on its own, see `PluginRuntime::spawn_with`.
+///
+/// Everything the crate spawns blocks on channels, the result loop as well as event loops, so
+/// `spawn_blocking` is the only entry point: hand the task to a thread or a blocking pool,
+/// never to an async worker. Implemented for `tokio::runtime::Handle` with the `tokio` feature
+/// and by `ThreadExecutor` otherwise.
+pub trait Executor: Send + Sync {
+    /// Runs `task` to completion somewhere it may block. Dropping it without running it shows
+    /// up as `PluginError::Disconnected` when joining its `Task`.
+    fn spawn_blocking(&self, task: Box<dyn FnOnce() + Send>);
+}
+
+/// Runs each task on a thread of its own.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ThreadExecutor;
+
+impl Executor for ThreadExecutor {
+    fn spawn_blocking(&self, task: Box<dyn FnOnce() + Send>) {
+        thread::Builder::new()
+            .name("plao-task".to_string())
+            .spawn(task)
+            .expect("failed to spawn a thread");
+    }
+}
+
+/// Spawns on the blocking pool of the tokio runtime the handle belongs to.
+#[cfg(feature = "tokio")]
+impl Executor for tokio::runtime::Handle {
+    fn spawn_blocking(&self, task: Box<dyn FnOnce() + Send>) {
+        tokio::runtime::Handle::spawn_blocking(self, task);
+    }
+}
+
+/// Outcome of a task handed to an `Executor`.
+pub struct Task<R> {
+    result_receiver: Receiver<thread::Result<R>>,
+}
+
+impl<R: 'static + Send> Task<R> {
+    pub(crate) fn spawn<E, F>(executor: &E, task: F) -> Self
+        where E: Executor + ?Sized, F: 'static + Send + FnOnce() -> R {
+        let (result_sender, result_receiver) = channel();
+        executor.spawn_blocking(Box::new(move || {
+            let _ = result_sender.send(catch_unwind(AssertUnwindSafe(task)));
+        }));
+        Task { result_receiver }
+    }
+}
+
+impl<R> Task<R> {
+    /// Blocks until the task returns. A panic in the task is reported as a
+    /// `PluginError::RuntimeError`.
+    pub fn join(self) -> PluginResult<R> {
+        let result = self.result_receiver.recv().map_err(|_| PluginError::Disconnected)?;
+        result.map_err(|panic| PluginError::RuntimeError(format!("task panicked: {}", panic_reason(panic))))
+    }
+
+    /// The task's outcome if it already returned, the task itself otherwise.
+    pub fn try_join(self) -> Result<PluginResult<R>, Self> {
+        match self.result_receiver.try_recv() {
+            Ok(result) => Ok(result.map_err(|panic| PluginError::RuntimeError(format!("task panicked: {}", panic_reason(panic))))),
+            Err(TryRecvError::Empty) => Err(self),
+            Err(TryRecvError::Disconnected) => Ok(Err(PluginError::Disconnected)),
+        }
+    }
+}
+
+/// Both loops of a runtime spawned with `PluginRuntime::spawn_with`.
+pub struct LoopTasks<R> {
+    pub result_loop: Task<()>,
+    pub event_loop: Task<R>,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::executor::{Executor, Task, ThreadExecutor};
+    use crate::test_utils::{build_dummy_runtime, dummy_event_loop, DummyPlugin};
+    use crate::PluginError;
+
+    struct Dropping;
+
+    impl Executor for Dropping {
+        fn spawn_blocking(&self, _task: Box<dyn FnOnce() + Send>) {}
+    }
+
+    #[test]
+    fn spawn_with_executor() {
+        let mut dummy_runtime = build_dummy_runtime();
+        let tasks = dummy_runtime.spawn_with(&ThreadExecutor, dummy_event_loop);
+        let plugin = dummy_runtime.load_plugin(DummyPlugin {}).unwrap();
+        assert_eq!(plugin.execute(()), Ok(Ok("hello".to_string())));
+        drop(plugin);
+        drop(dummy_runtime);
+        assert_eq!(tasks.event_loop.join(), Ok(Ok(())));
+        assert_eq!(tasks.result_loop.join(), Ok(()));
+
+        let panicked = Task::spawn(&ThreadExecutor, || -> () { panic!("boom") });
+        assert_eq!(panicked.join(), Err(PluginError::RuntimeError("task panicked: boom".to_string())));
+        assert_eq!(Task::spawn(&Dropping, || ()).join(), Err(PluginError::Disconnected));
+    }
+}
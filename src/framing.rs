@@ -0,0 +1,92 @@
+use crate::{PluginError, PluginResult};
+use std::io::{ErrorKind, Read, Write};
+
+/// Largest frame a `FrameReader` accepts unless configured otherwise.
+pub const DEFAULT_MAX_FRAME: usize = 16 * 1024 * 1024;
+
+const PREFIX_LEN: usize = 4;
+
+/// Reads frames made of a big-endian `u32` length prefix followed by that many bytes.
+///
+/// Meant for wire transports talking to untrusted plugins: the prefix is checked against
+/// `max_frame` before anything is allocated, and short reads are reported rather than
+/// padded. Reads block like the underlying reader, set timeouts on it to bound them.
+pub struct FrameReader<R: Read> {
+    reader: R,
+    max_frame: usize,
+}
+
+impl<R: Read> FrameReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self::with_max_frame(reader, DEFAULT_MAX_FRAME)
+    }
+
+    pub fn with_max_frame(reader: R, max_frame: usize) -> Self {
+        FrameReader { reader, max_frame }
+    }
+
+    /// Next frame, or `None` when the stream ends cleanly between two frames.
+    pub fn read_frame(&mut self) -> PluginResult<Option<Vec<u8>>> {
+        let mut prefix = [0u8; PREFIX_LEN];
+        let mut filled = 0;
+        while filled < PREFIX_LEN {
+            match self.reader.read(&mut prefix[filled..]) {
+                Ok(0) if filled == 0 => return Ok(None),
+                Ok(0) => return Err(PluginError::RuntimeError(format!("truncated frame prefix: {} of {} bytes", filled, PREFIX_LEN))),
+                Ok(read) => filled += read,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(PluginError::RuntimeError(e.to_string())),
+            }
+        }
+        let len = u32::from_be_bytes(prefix) as usize;
+        if len > self.max_frame {
+            return Err(PluginError::InvalidPlugin(format!("frame of {} bytes exceeds the {} byte limit", len, self.max_frame)));
+        }
+        let mut frame = vec![0u8; len];
+        self.reader.read_exact(&mut frame).map_err(|e| match e.kind() {
+            ErrorKind::UnexpectedEof => PluginError::RuntimeError(format!("truncated frame: expected {} bytes", len)),
+            _ => PluginError::RuntimeError(e.to_string()),
+        })?;
+        Ok(Some(frame))
+    }
+}
+
+pub fn write_frame<W: Write>(writer: &mut W, frame: &[u8]) -> PluginResult<()> {
+    if frame.len() > u32::MAX as usize {
+        return Err(PluginError::RuntimeError(format!("frame of {} bytes is too large to send", frame.len())));
+    }
+    writer.write_all(&(frame.len() as u32).to_be_bytes())
+        .and_then(|_| writer.write_all(frame))
+        .map_err(|e| PluginError::RuntimeError(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::framing::{write_frame, FrameReader};
+    use crate::PluginErrorKind;
+
+    #[test]
+    fn frame_round_trip() {
+        let mut wire = Vec::new();
+        write_frame(&mut wire, b"hello").unwrap();
+        write_frame(&mut wire, b"").unwrap();
+        let mut reader = FrameReader::new(wire.as_slice());
+        assert_eq!(reader.read_frame().unwrap(), Some(b"hello".to_vec()));
+        assert_eq!(reader.read_frame().unwrap(), Some(Vec::new()));
+        assert_eq!(reader.read_frame().unwrap(), None);
+    }
+
+    #[test]
+    fn reject_bad_frames() {
+        let truncated_prefix: &[u8] = &[0, 0];
+        assert_eq!(FrameReader::new(truncated_prefix).read_frame().err().map(|e| e.kind()), Some(PluginErrorKind::RuntimeError));
+
+        let mut truncated = Vec::new();
+        write_frame(&mut truncated, b"hello").unwrap();
+        truncated.truncate(truncated.len() - 2);
+        assert_eq!(FrameReader::new(truncated.as_slice()).read_frame().err().map(|e| e.kind()), Some(PluginErrorKind::RuntimeError));
+
+        let oversized: &[u8] = &[0xff, 0xff, 0xff, 0xff];
+        assert_eq!(FrameReader::with_max_frame(oversized, 1024).read_frame().err().map(|e| e.kind()), Some(PluginErrorKind::InvalidPlugin));
+    }
+}
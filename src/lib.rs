@@ -1,28 +1,141 @@
+//! Without the default `std` feature only the plugin traits and error types are built, on
+//! `core` and `alloc`, so they can be shared with hosts that bring their own channels.
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+#[cfg(feature = "std")]
+macro_rules! trace_call {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "log")]
+        log::trace!($($arg)*);
+    };
+}
+
+#[cfg(feature = "std")]
 mod tokio_utils;
 
-#[cfg(test)]
+#[cfg(all(test, feature = "std"))]
 mod test_utils;
+#[cfg(feature = "std")]
 pub mod source;
+#[cfg(feature = "std")]
 pub mod loader;
+#[cfg(feature = "std")]
 pub mod runtime;
+#[cfg(feature = "std")]
+pub mod lifecycle;
+#[cfg(feature = "std")]
+pub mod subscribers;
+#[cfg(feature = "std")]
+pub mod config;
+#[cfg(feature = "std")]
+pub mod registry;
+#[cfg(feature = "std")]
+pub mod observer;
+#[cfg(feature = "std")]
+pub mod framing;
+#[cfg(feature = "std")]
+pub mod transport;
+pub mod operation;
+#[cfg(feature = "std")]
+pub mod executor;
+#[cfg(feature = "std")]
+pub mod supervisor;
+#[cfg(feature = "trace")]
+pub mod trace;
+#[cfg(feature = "futures")]
+pub mod stream;
+#[cfg(feature = "testing")]
+pub mod testing;
 
-use std::error::Error;
-use core::fmt::Formatter;
+use alloc::boxed::Box;
+use alloc::borrow::Cow;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::any::Any;
+use core::fmt::{Debug, Formatter};
 use core::result::Result;
-use std::sync::mpsc::{Sender, channel};
-use std::result::Result::Err;
-use crate::runtime::{PluginOpCall, RuntimeResult, PluginOpCallId};
-use std::sync::{Mutex, Arc};
-use std::collections::HashMap;
+use core::time::Duration;
+
+#[cfg(feature = "std")]
+use core::result::Result::Err;
+#[cfg(feature = "std")]
+use std::sync::mpsc::{Receiver, Sender, RecvTimeoutError, TryRecvError, channel};
+#[cfg(feature = "std")]
+use std::sync::Mutex;
+#[cfg(feature = "std")]
+use std::future::Future;
+#[cfg(feature = "std")]
+use std::pin::Pin;
+#[cfg(feature = "std")]
+use std::task::{Context, Poll, Waker};
+#[cfg(feature = "std")]
+use crate::runtime::{CallPayload, Shared, PendingLimit, PendingPermit, PluginOpCall, PluginOpCallId, RuntimeResult, CallTiming, ResultReceiver, TaggedResult, Subscribers, Topics, FailedPlugins, Metrics, Cancellations, Draining, QueueDepth};
+#[cfg(feature = "std")]
+use crate::subscribers::{Backlog, ProgressCallback, ResultSender, Slot, Subscriber, TopicSubscriber};
+#[cfg(feature = "std")]
+use crate::observer::{CallObserver, Observer};
+#[cfg(feature = "std")]
+use crate::operation::Operation;
+#[cfg(feature = "std")]
 use uuid::Uuid;
-use std::fmt::Debug;
+#[cfg(feature = "std")]
+use std::collections::HashMap;
+#[cfg(feature = "std")]
+use std::time::Instant;
+#[cfg(feature = "std")]
+use std::sync::atomic::Ordering;
+#[cfg(feature = "std")]
+use std::any::TypeId;
+#[cfg(feature = "std")]
+use std::cell::RefCell;
+#[cfg(all(feature = "std", feature = "builder"))]
+use typed_builder::TypedBuilder;
 
 pub type PluginResult<T> = Result<T, PluginError>;
 
+pub type PluginCallOutcome<P> = Result<<<P as PluginData>::PluginCallResult as PluginCallResult>::Ok, <<P as PluginData>::PluginCallResult as PluginCallResult>::Err>;
+
+pub type SharedCallOutcome<P> = Result<Arc<<<P as PluginData>::PluginCallResult as PluginCallResult>::Ok>, <<P as PluginData>::PluginCallResult as PluginCallResult>::Err>;
+
 pub trait PluginData: Clone + Send {
     type PluginCall: Send;
     type PluginCallResult: PluginCallResult;
     fn name(&self) -> String;
+
+    /// Borrowing counterpart of `name`, override it to avoid allocating on hot paths.
+    fn name_ref(&self) -> Cow<'_, str> {
+        Cow::Owned(self.name())
+    }
+
+    /// Host-side setup the `PluginLoader` runs once the plugin's load call succeeded, before it
+    /// reports the plugin as loaded.
+    fn on_load(&self) {}
+
+    /// Host-side teardown the `PluginLoader` runs when it lets go of the plugin: before the
+    /// unload call is sent, or once the plugin failed.
+    fn on_unload(&self) {}
+
+    /// Cheap checks `PluginLoader::validate` runs on a freshly opened plugin, such as whether
+    /// its manifest is complete. It must not have side effects, the plugin isn't loaded yet.
+    fn validate(&self) -> PluginResult<()> {
+        Ok(())
+    }
+
+    /// What the plugin can do, e.g. the operations or features it supports, for hosts to route
+    /// calls or gate features on. None by default.
+    fn capabilities(&self) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+/// Plugins whose load call depends on parameters supplied by the host at load time,
+/// such as a config path, so the same plugin can be loaded with different configs.
+pub trait PluginInit: PluginData {
+    type InitParams;
+    fn init_call(&self, init: Self::InitParams) -> Self::PluginCall;
 }
 
 pub trait PluginCallResult: Clone {
@@ -30,54 +143,840 @@ pub trait PluginCallResult: Clone {
     type Err: Send + Clone + ToString + Debug;
 }
 
+/// Caller side of a loaded plugin. Usually obtained from the runtime, `Plugin::builder()` or
+/// `Plugin::from_parts` assemble one from raw parts to bridge the API onto another backend: whatever receives
+/// from `call_sender` answers by removing the call's `Subscriber` and sending on it.
+#[cfg(feature = "std")]
+#[cfg_attr(feature = "builder", derive(TypedBuilder))]
 pub struct Plugin<P: PluginData> {
     plugin_data: P,
     call_sender: Sender<PluginOpCall<P>>,
-    subscribers: Arc<Mutex<HashMap<PluginOpCallId, Sender<RuntimeResult<P::PluginCallResult>>>>>,
+    #[cfg_attr(feature = "builder", builder(default))]
+    wake_sender: Option<Sender<()>>,
+    subscribers: Subscribers<P>,
+    #[cfg_attr(feature = "builder", builder(default))]
+    topics: Topics<P>,
+    #[cfg_attr(feature = "builder", builder(default))]
+    failed: FailedPlugins,
+    #[cfg_attr(feature = "builder", builder(default))]
+    metrics: Metrics,
+    #[cfg_attr(feature = "builder", builder(default))]
+    cancellations: Cancellations,
+    #[cfg_attr(feature = "builder", builder(default))]
+    draining: Draining,
+    #[cfg_attr(feature = "builder", builder(default))]
+    default_timeout: Option<Duration>,
+    #[cfg_attr(feature = "builder", builder(default))]
+    pending_limit: Option<PendingLimit>,
+    #[cfg_attr(feature = "builder", builder(default))]
+    max_buffered: Option<usize>,
+    #[cfg_attr(feature = "builder", builder(default, setter(skip)))]
+    observer: Option<Observer<P>>,
+    #[cfg_attr(feature = "builder", builder(default))]
+    queue_depth: Option<QueueDepth>,
+}
+
+#[cfg(feature = "std")]
+impl<P: PluginData> Clone for Plugin<P> {
+    fn clone(&self) -> Self {
+        Plugin {
+            plugin_data: self.plugin_data.clone(),
+            call_sender: self.call_sender.clone(),
+            wake_sender: self.wake_sender.clone(),
+            subscribers: self.subscribers.clone(),
+            topics: self.topics.clone(),
+            failed: self.failed.clone(),
+            metrics: self.metrics.clone(),
+            cancellations: self.cancellations.clone(),
+            draining: self.draining.clone(),
+            default_timeout: self.default_timeout,
+            pending_limit: self.pending_limit.clone(),
+            max_buffered: self.max_buffered,
+            observer: self.observer.clone(),
+            queue_depth: self.queue_depth.clone(),
+        }
+    }
 }
 
+#[cfg(feature = "std")]
 impl<P: PluginData> Plugin<P> {
+    /// The parts `Plugin::builder()` requires, everything else left to its default.
+    pub fn from_parts(plugin_data: P, call_sender: Sender<PluginOpCall<P>>, subscribers: Subscribers<P>) -> Self {
+        Plugin {
+            plugin_data,
+            call_sender,
+            wake_sender: None,
+            subscribers,
+            topics: Default::default(),
+            failed: Default::default(),
+            metrics: Default::default(),
+            cancellations: Default::default(),
+            draining: Default::default(),
+            default_timeout: None,
+            pending_limit: None,
+            max_buffered: None,
+            observer: None,
+            queue_depth: None,
+        }
+    }
+
     pub fn name(&self) -> String {
         self.plugin_data.name()
     }
 
-    pub fn execute(&self, plugin_call: P::PluginCall) -> PluginResult<Result<<P::PluginCallResult as PluginCallResult>::Ok, <P::PluginCallResult as PluginCallResult>::Err>> {
-        let id = Uuid::new_v4();
+    pub fn name_ref(&self) -> Cow<'_, str> {
+        self.plugin_data.name_ref()
+    }
+
+    /// The plugin's `PluginData::capabilities`, asked anew on every call: a plugin whose
+    /// capabilities are expensive to work out should do so once, e.g. when its source opens it,
+    /// and keep them in its data.
+    pub fn capabilities(&self) -> Vec<String> {
+        self.plugin_data.capabilities()
+    }
+
+    /// Timeout the `execute` variants that wait for a single result apply, the loader sets it
+    /// from `PluginDescriptor::timeout`. An explicit `execute_timeout` or `execute_batch`
+    /// deadline takes precedence; `execute_stream`, `execute_pending` and `execute_into` hand
+    /// the waiting to the caller and don't apply it.
+    pub fn with_default_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.default_timeout = timeout;
+        self
+    }
+
+    pub fn default_timeout(&self) -> Option<Duration> {
+        self.default_timeout
+    }
+
+    /// Has `observer` watch every call made through this handle from now on, replacing any
+    /// previous one. Clones made afterwards share it. Unlike a failure hook or the `trace`
+    /// feature it sees each call and result as they go, but only to look at them.
+    pub fn set_observer(&mut self, observer: Arc<dyn CallObserver<P>>) where P: 'static {
+        self.observer = Some(Observer::new(observer));
+    }
+
+    pub fn execute(&self, plugin_call: P::PluginCall) -> PluginResult<PluginCallOutcome<P>> {
+        self.execute_with_headers(plugin_call, HashMap::new())
+    }
+
+    /// Like `execute` for one typed operation: `input` becomes the call and the result comes
+    /// back as the operation's output. An event loop answering with anything `O::from_result`
+    /// rejects fails the call with `InvalidPlugin`.
+    pub fn execute_op<O: Operation<P>>(&self, input: O::Input) -> PluginResult<Result<O::Output, <P::PluginCallResult as PluginCallResult>::Err>> {
+        match self.execute(O::into_call(input))? {
+            Ok(result) => O::from_result(result).map(Ok).ok_or_else(|| {
+                PluginError::InvalidPlugin(format!("{} answered {} with another operation's result", self.name_ref(), core::any::type_name::<O>()))
+            }),
+            Err(e) => Ok(Err(e)),
+        }
+    }
+
+    pub fn execute_map<T, F: FnOnce(PluginCallOutcome<P>) -> T>(&self, plugin_call: P::PluginCall, f: F) -> PluginResult<T> {
+        self.execute(plugin_call).map(f)
+    }
+
+    pub fn execute_with_headers(&self, plugin_call: P::PluginCall, headers: HashMap<String, String>) -> PluginResult<PluginCallOutcome<P>> {
+        self.execute_payload(plugin_call, headers)
+    }
+
+    /// Like `execute` with a payload shared rather than moved, so retrying it or fanning it out
+    /// to several plugins doesn't copy it. The event loop sees it through `PluginOpCall::call`.
+    pub fn execute_arc(&self, plugin_call: Arc<P::PluginCall>) -> PluginResult<PluginCallOutcome<P>> where P::PluginCall: Sync {
+        self.execute_payload(CallPayload::Shared(Shared::new(plugin_call)), HashMap::new())
+    }
+
+    /// Like `execute`, resending the call while it fails with a retryable error, see
+    /// `PluginError::is_retryable`, up to `policy.max_retries` times. Each attempt is a new
+    /// call with its own id, the previous one having been withdrawn and cancelled. The plugin's
+    /// own `Err` is returned as is, and so is any other error: once the plugin is failed,
+    /// attempts fail with its error and the retries stop. Timeouts only happen with a default
+    /// timeout, see `with_default_timeout`. Waiting out the backoff puts the calling thread to
+    /// sleep, so don't call it from an async task.
+    pub fn execute_retry(&self, plugin_call: P::PluginCall, policy: &RetryPolicy) -> PluginResult<PluginCallOutcome<P>> where P::PluginCall: Sync {
+        let plugin_call = Arc::new(plugin_call);
+        let mut backoff = policy.backoff.min(policy.max_backoff);
+        let mut retries = 0;
+        loop {
+            match self.execute_arc(plugin_call.clone()) {
+                Err(e) if e.is_retryable() && retries < policy.max_retries => {
+                    trace_call!("retrying call to {} after {}", self.name_ref(), e);
+                    retries += 1;
+                    std::thread::sleep(backoff);
+                    backoff = policy.next_backoff(backoff);
+                }
+                res => return res,
+            }
+        }
+    }
+
+    fn execute_payload<C: Into<CallPayload<P::PluginCall>>>(&self, plugin_call: C, headers: HashMap<String, String>) -> PluginResult<PluginCallOutcome<P>> {
+        let (id, result_receiver) = self.send_until(plugin_call, headers, self.default_deadline())?;
+        self.receive(id, |timeout| recv_within(&result_receiver, timeout))?.map(Into::into)
+    }
+
+    /// Like `execute`, calling `on_progress` with every fraction the event loop reports through
+    /// `Handle::progress` until the result arrives. It runs on the result loop, keep it short.
+    pub fn execute_with_progress<F: 'static + Send + Sync + Fn(f32)>(&self, plugin_call: P::PluginCall, on_progress: F) -> PluginResult<PluginCallOutcome<P>> {
+        let (result_sender, result_receiver) = channel();
+        let id = self.dispatch(plugin_call, HashMap::new(), self.default_deadline(), ResultSender::Call(result_sender), Some(Arc::new(on_progress)))?;
+        self.receive(id, |timeout| recv_within(&result_receiver, timeout))?.map(Into::into)
+    }
+
+    /// Like `execute`, along with how long the call waited in the queue and how long it took
+    /// from there, see `CallTiming`.
+    pub fn execute_timed(&self, plugin_call: P::PluginCall) -> PluginResult<(PluginCallOutcome<P>, CallTiming)> {
+        let (result_sender, result_receiver) = channel();
+        let id = self.dispatch(plugin_call, HashMap::new(), self.default_deadline(), ResultSender::Timed(result_sender), None)?;
+        let (res, timing) = self.receive(id, |timeout| recv_within(&result_receiver, timeout))?;
+        res.map(|res| (res.into(), timing))
+    }
+
+    /// Like `execute`, but waits on a result slot cached per thread instead of allocating a
+    /// channel for every call. The slot is taken out of the cache for the call, so a nested
+    /// call on the same thread falls back to a fresh one; it is only cached again once nothing
+    /// else references it, so a panic mid-call just drops it. A progress callback runs on the
+    /// result loop and gets that thread's slot, but must not wait on its own runtime.
+    pub fn execute_pooled(&self, plugin_call: P::PluginCall) -> PluginResult<PluginCallOutcome<P>> where P::PluginCallResult: 'static {
+        thread_local! {
+            static SLOTS: RefCell<HashMap<TypeId, Box<dyn Any>>> = RefCell::new(HashMap::new());
+        }
+        let key = TypeId::of::<P::PluginCallResult>();
+        let slot = SLOTS.with(|slots| slots.borrow_mut().remove(&key))
+            .and_then(|slot| slot.downcast::<Arc<Slot<P::PluginCallResult>>>().ok())
+            .map(|slot| *slot)
+            .unwrap_or_else(Slot::new);
+        let id = self.dispatch(plugin_call, HashMap::new(), self.default_deadline(), ResultSender::Slot(slot.sender()), None)?;
+        let res = self.receive(id, |timeout| slot.wait(timeout));
+        if Arc::strong_count(&slot) == 1 {
+            SLOTS.with(|slots| slots.borrow_mut().insert(key, Box::new(slot)));
+        }
+        res?.map(Into::into)
+    }
+
+    /// Like `execute` but hands back the shared payload, avoiding a copy of large results.
+    pub fn execute_shared(&self, plugin_call: P::PluginCall) -> PluginResult<SharedCallOutcome<P>> {
+        let (id, result_receiver) = self.send_until(plugin_call, HashMap::new(), self.default_deadline())?;
+        self.receive(id, |timeout| recv_within(&result_receiver, timeout))?.map(RuntimeResult::into_shared)
+    }
+
+    /// Gives up with `Timeout` after `timeout`. The deadline also travels with the call so the
+    /// event loop can skip work it can't finish in time; loops may ignore it.
+    pub fn execute_timeout(&self, plugin_call: P::PluginCall, timeout: Duration) -> PluginResult<PluginCallOutcome<P>> {
+        let (id, result_receiver) = self.send_until(plugin_call, HashMap::new(), Some(Instant::now() + timeout))?;
+        self.wait(id, result_receiver, timeout, timeout)
+    }
+
+    /// Results in submission order, same as `execute_batch` with `ResultOrder::Submission`.
+    pub fn execute_all(&self, plugin_calls: Vec<P::PluginCall>, deadline: Duration) -> Vec<PluginResult<PluginCallOutcome<P>>> {
+        self.execute_batch(plugin_calls, deadline, ResultOrder::Submission).into_iter()
+            .map(|(_, res)| res)
+            .collect()
+    }
+
+    /// Sends every call at once and collects their results within `deadline`, each paired with
+    /// the index of its call. Calls that don't finish in time are cancelled and fail with
+    /// `Timeout`; with `ResultOrder::Completion` they come last, in submission order.
+    pub fn execute_batch(&self, plugin_calls: Vec<P::PluginCall>, deadline: Duration, order: ResultOrder) -> Vec<(usize, PluginResult<PluginCallOutcome<P>>)> {
+        match order {
+            ResultOrder::Submission => self.execute_in_order(plugin_calls, deadline),
+            ResultOrder::Completion => self.execute_as_ready(plugin_calls, deadline),
+        }
+    }
+
+    fn execute_in_order(&self, plugin_calls: Vec<P::PluginCall>, deadline: Duration) -> Vec<(usize, PluginResult<PluginCallOutcome<P>>)> {
+        let started = Instant::now();
+        let pending: Vec<_> = plugin_calls.into_iter()
+            .map(|plugin_call| self.send_until(plugin_call, HashMap::new(), Some(started + deadline)))
+            .collect();
+        pending.into_iter().enumerate().map(|(index, pending)| {
+            let res = pending.and_then(|(id, result_receiver)| {
+                let remaining = deadline.checked_sub(started.elapsed()).unwrap_or_default();
+                self.wait(id, result_receiver, remaining, deadline)
+            });
+            (index, res)
+        }).collect()
+    }
+
+    fn execute_as_ready(&self, plugin_calls: Vec<P::PluginCall>, deadline: Duration) -> Vec<(usize, PluginResult<PluginCallOutcome<P>>)> {
+        let started = Instant::now();
+        let (result_sender, result_receiver) = channel();
+        let mut results = Vec::with_capacity(plugin_calls.len());
+        let mut pending = HashMap::new();
+        for (index, plugin_call) in plugin_calls.into_iter().enumerate() {
+            match self.dispatch(plugin_call, HashMap::new(), Some(started + deadline), ResultSender::Tagged(result_sender.clone()), None) {
+                Ok(id) => {
+                    pending.insert(id, index);
+                }
+                Err(e) => results.push((index, Err(e))),
+            }
+        }
+        drop(result_sender);
+        while !pending.is_empty() {
+            let remaining = deadline.checked_sub(started.elapsed()).unwrap_or_default();
+            match result_receiver.recv_timeout(remaining) {
+                Ok((id, res)) => {
+                    if let Some(index) = pending.remove(&id) {
+                        results.push((index, res.map(Into::into)));
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => {
+                    let e = self.failure().unwrap_or(PluginError::Disconnected);
+                    let mut left: Vec<_> = pending.drain().map(|(_, index)| index).collect();
+                    left.sort_unstable();
+                    results.extend(left.into_iter().map(|index| (index, Err(e.clone()))));
+                }
+            }
+        }
+        let mut left: Vec<_> = pending.into_iter().collect();
+        left.sort_unstable_by_key(|(_, index)| *index);
+        let mut late = HashMap::new();
+        for (id, index) in left {
+            match self.timed_out(id, deadline) {
+                Some(e) => results.push((index, Err(e))),
+                None => {
+                    late.insert(id, index);
+                }
+            }
+        }
+        // Results the result loop took before the calls could be withdrawn are on their way.
+        while !late.is_empty() {
+            match result_receiver.recv() {
+                Ok((id, res)) => {
+                    if let Some(index) = late.remove(&id) {
+                        results.push((index, res.map(Into::into)));
+                    }
+                }
+                Err(_) => {
+                    let e = self.failure().unwrap_or(PluginError::Disconnected);
+                    results.extend(late.drain().map(|(_, index)| (index, Err(e.clone()))));
+                }
+            }
+        }
+        results
+    }
+
+    fn default_deadline(&self) -> Option<Instant> {
+        self.default_timeout.map(|timeout| Instant::now() + timeout)
+    }
+
+    /// Waits for the result of call `id` through `wait`, which gets how long it may block,
+    /// giving up after the default timeout if there is one.
+    fn receive<T, W: FnMut(Option<Duration>) -> Result<T, RecvTimeoutError>>(&self, id: PluginOpCallId, mut wait: W) -> PluginResult<T> {
+        let received = match self.default_timeout {
+            Some(timeout) => match wait(Some(timeout)) {
+                Err(RecvTimeoutError::Timeout) => match self.timed_out(id, timeout) {
+                    Some(e) => return Err(e),
+                    None => wait(None),
+                },
+                received => received,
+            },
+            None => wait(None),
+        };
+        received.map_err(|_| self.failure().unwrap_or(PluginError::Disconnected))
+    }
+
+    fn wait(&self, id: PluginOpCallId, result_receiver: ResultReceiver<P>, remaining: Duration, timeout: Duration) -> PluginResult<PluginCallOutcome<P>> {
+        match result_receiver.recv_timeout(remaining) {
+            Ok(res) => res.map(Into::into),
+            Err(RecvTimeoutError::Timeout) => match self.timed_out(id, timeout) {
+                Some(e) => Err(e),
+                None => match result_receiver.recv() {
+                    Ok(res) => res.map(Into::into),
+                    Err(_) => Err(self.failure().unwrap_or(PluginError::Disconnected)),
+                },
+            },
+            Err(RecvTimeoutError::Disconnected) => Err(self.failure().unwrap_or(PluginError::Disconnected)),
+        }
+    }
+
+    /// Withdraws a call its caller stopped waiting for and returns the `Timeout` to fail it
+    /// with. Returns `None` when the result loop already took the call off the subscribers, in
+    /// which case its result, or its sender being dropped, is on the way to the caller, who
+    /// should take that instead. Either way the call is counted once, as timed out or as
+    /// delivered, and a result arriving after the withdrawal is discarded by the result loop.
+    fn timed_out(&self, id: PluginOpCallId, timeout: Duration) -> Option<PluginError> {
+        let subscriber = self.withdraw(id)?;
+        if let Some(ref observer) = subscriber.observer {
+            observer(id, &Err(PluginError::Timeout(timeout)), subscriber.since.elapsed());
+        }
+        if let Ok(mut metrics) = self.metrics.lock() {
+            let plugin_metrics = metrics.entry(self.name()).or_default();
+            plugin_metrics.timed_out += 1;
+            plugin_metrics.record_latency(timeout);
+        }
+        Some(PluginError::Timeout(timeout))
+    }
+
+    fn send_until<C: Into<CallPayload<P::PluginCall>>>(&self, plugin_call: C, headers: HashMap<String, String>, deadline: Option<Instant>) -> PluginResult<(PluginOpCallId, ResultReceiver<P>)> {
+        let (result_sender, result_receiver) = channel();
+        let id = self.dispatch(plugin_call, headers, deadline, ResultSender::Call(result_sender), None)?;
+        Ok((id, result_receiver))
+    }
+
+    /// Streams the items the event loop `push`es for this call, ending with its final result if
+    /// it resolves or rejects rather than calling `end`. Dropping the stream early cancels the call.
+    pub fn execute_stream(&self, plugin_call: P::PluginCall) -> PluginResult<PluginStream<P>> {
+        let (result_sender, result_receiver) = channel();
+        let backlog = self.max_buffered.map(Backlog::new);
+        let result_sender = match backlog {
+            Some(ref backlog) => ResultSender::Stream(result_sender, backlog.clone()),
+            None => ResultSender::Call(result_sender),
+        };
+        let id = self.dispatch(plugin_call, HashMap::new(), None, result_sender, None)?;
+        Ok(PluginStream {
+            plugin: self.clone(),
+            id,
+            result_receiver,
+            backlog,
+            done: false,
+        })
+    }
+
+    /// Sends the call and returns it as a future resolving to what `execute` would return, to
+    /// poll from any executor or store in a struct. Dropping it before it resolves cancels the
+    /// call. When `max_pending` calls are already pending, the future awaits a free slot before
+    /// sending the call, or fails with `FullQueue::Fail`.
+    pub fn execute_pending(&self, plugin_call: P::PluginCall) -> PluginResult<PendingCall<P>> {
+        self.check_open()?;
         let (result_sender, result_receiver) = channel();
-        {
-            let subscribers = self.subscribers.lock();
-            if let Err(ref e) = subscribers {
-                return Err(PluginError::RuntimeError(e.to_string()));
+        let waker: Arc<Mutex<Option<Waker>>> = Arc::new(Mutex::new(None));
+        let notified = waker.clone();
+        let notify = Arc::new(move || {
+            if let Some(waker) = notified.lock().unwrap().take() {
+                waker.wake();
             }
-            subscribers.unwrap().insert(id, result_sender);
+        });
+        let mut pending = PendingCall {
+            plugin: self.clone(),
+            id: Uuid::new_v4(),
+            queued: Some((plugin_call, ResultSender::Notify(result_sender, notify))),
+            result_receiver,
+            waker,
+            done: false,
+        };
+        match self.pending_limit {
+            Some(ref limit) => if let Some(permit) = limit.try_acquire()? {
+                pending.send(Some(permit))?;
+            },
+            None => pending.send(None)?,
+        }
+        Ok(pending)
+    }
+
+    /// Sends the call without waiting, its result is later sent on `result_sender` tagged with
+    /// the returned id, so one channel can collect the results of many calls. The call stays
+    /// pending until its result is sent or it is cancelled; if `result_sender`'s receiver is
+    /// dropped by then the result is discarded.
+    pub fn execute_into(&self, plugin_call: P::PluginCall, result_sender: Sender<TaggedResult<P::PluginCallResult>>) -> PluginResult<PluginOpCallId> {
+        self.dispatch(plugin_call, HashMap::new(), None, ResultSender::Tagged(result_sender), None)
+    }
+
+    pub(crate) fn dispatch<C: Into<CallPayload<P::PluginCall>>>(&self, plugin_call: C, headers: HashMap<String, String>, deadline: Option<Instant>, result_sender: ResultSender<P::PluginCallResult>, progress: Option<ProgressCallback>) -> PluginResult<PluginOpCallId> {
+        self.check_open()?;
+        let permit = match self.pending_limit {
+            Some(ref limit) => Some(limit.acquire(deadline)?),
+            None => None,
+        };
+        let id = Uuid::new_v4();
+        self.enqueue(id, plugin_call, headers, deadline, self.subscriber(result_sender, progress, permit))?;
+        Ok(id)
+    }
+
+    fn check_open(&self) -> PluginResult<()> {
+        if let Some(e) = self.failure() {
+            return Err(e);
+        }
+        if self.draining.load(Ordering::SeqCst) {
+            return Err(PluginError::Disconnected);
+        }
+        Ok(())
+    }
+
+    /// `permit` already counts the call against the runtime's `max_pending`.
+    fn subscriber(&self, result_sender: ResultSender<P::PluginCallResult>, progress: Option<ProgressCallback>, permit: Option<PendingPermit>) -> Subscriber<P::PluginCallResult> {
+        Subscriber {
+            plugin: self.name(),
+            sender: result_sender,
+            progress,
+            since: Instant::now(),
+            received: None,
+            permit,
+            observer: self.observer.as_ref().map(|observer| observer.results.clone()),
         }
-        let res = self.call_sender.send(PluginOpCall {
+    }
+
+    /// Registers the call's subscriber under `id` and sends the call.
+    fn enqueue<C: Into<CallPayload<P::PluginCall>>>(&self, id: PluginOpCallId, plugin_call: C, headers: HashMap<String, String>, deadline: Option<Instant>, subscriber: Subscriber<P::PluginCallResult>) -> PluginResult<()> {
+        self.subscribers.insert(id, subscriber)?;
+        let call = PluginOpCall {
             plugin_data: self.plugin_data.clone(),
             call_id: id,
-            call: plugin_call
-        });
-        if let Err(ref e) = res {
-            return Err(PluginError::RuntimeError(e.to_string()));
+            call: plugin_call.into(),
+            headers,
+            deadline,
+            deferred: 0,
+        };
+        if let Some(ref observer) = self.observer {
+            observer.calls.before_send(&call);
+        }
+        // Counted before sending so the event loop never takes the call off an empty count.
+        if let Some(ref queue_depth) = self.queue_depth {
+            queue_depth.fetch_add(1, Ordering::SeqCst);
+        }
+        // Traced before sending too, the event loop may trace the receive right away.
+        trace_call!("call {} enqueued for {} at {:?}", id, self.name_ref(), std::time::SystemTime::now());
+        let res = self.call_sender.send(call);
+        let woken = match self.wake_sender {
+            Some(ref wake_sender) if res.is_ok() => wake_sender.send(()).is_ok(),
+            _ => res.is_ok(),
+        };
+        if !woken {
+            if let (Some(queue_depth), Err(_)) = (&self.queue_depth, &res) {
+                queue_depth.fetch_sub(1, Ordering::SeqCst);
+            }
+            self.forget(&id);
+            trace_call!("call {} dropped, the runtime is gone", id);
+            return Err(PluginError::Disconnected);
+        }
+        Ok(())
+    }
+
+    /// Receives whatever the event loop `publish`es to `topic` from now on, until the returned
+    /// subscription is dropped. It also ends, without an error, once this plugin fails or the
+    /// runtime stops. Topics are shared by the whole runtime, the plugin only scopes that
+    /// lifetime.
+    pub fn subscribe<T: Into<String>>(&self, topic: T) -> PluginResult<Subscription<P>> {
+        if let Some(e) = self.failure() {
+            return Err(e);
+        }
+        if self.draining.load(Ordering::SeqCst) {
+            return Err(PluginError::Disconnected);
         }
-        let res = result_receiver.recv();
-        if let Err(ref e) = res {
-            return Err(PluginError::RuntimeError(e.to_string()));
+        let topic = topic.into();
+        let id = Uuid::new_v4();
+        let (sender, result_receiver) = channel();
+        self.topics.insert(topic.clone(), id, TopicSubscriber {
+            plugin: self.name(),
+            sender,
+        })?;
+        Ok(Subscription {
+            topics: self.topics.clone(),
+            topic,
+            id,
+            result_receiver,
+        })
+    }
+
+    /// Abandons a pending call and lets the event loop know through `Handle::is_cancelled`.
+    pub fn cancel(&self, id: PluginOpCallId) {
+        self.withdraw(id);
+    }
+
+    fn withdraw(&self, id: PluginOpCallId) -> Option<Subscriber<P::PluginCallResult>> {
+        let subscriber = self.subscribers.remove(&id)?;
+        self.cancellations.lock().unwrap().insert(id);
+        Some(subscriber)
+    }
+
+    fn failure(&self) -> Option<PluginError> {
+        self.failed.lock().ok()?.get(self.name_ref().as_ref()).cloned()
+    }
+
+    fn forget(&self, id: &PluginOpCallId) {
+        self.subscribers.remove(id);
+    }
+}
+
+/// `Receiver::recv_timeout`, or a plain `recv` without a timeout.
+#[cfg(feature = "std")]
+fn recv_within<T>(receiver: &Receiver<T>, timeout: Option<Duration>) -> Result<T, RecvTimeoutError> {
+    match timeout {
+        Some(timeout) => receiver.recv_timeout(timeout),
+        None => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+    }
+}
+
+/// The plugin's own error for a rejected load call, kept so hosts can inspect why it failed.
+/// It is owned rather than shared since plugin errors don't have to be `Sync`.
+pub struct LoadCause(Box<dyn AnyClone + Send>);
+
+trait AnyClone: Any {
+    fn clone_box(&self) -> Box<dyn AnyClone + Send>;
+    fn as_any(&self) -> &dyn Any;
+}
+
+impl<T: Any + Send + Clone> AnyClone for T {
+    fn clone_box(&self) -> Box<dyn AnyClone + Send> {
+        Box::new(self.clone())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl LoadCause {
+    pub fn new<T: Any + Send + Clone>(cause: T) -> Self {
+        LoadCause(Box::new(cause))
+    }
+
+    pub fn downcast_ref<T: Any>(&self) -> Option<&T> {
+        self.0.as_any().downcast_ref()
+    }
+}
+
+impl Clone for LoadCause {
+    fn clone(&self) -> Self {
+        LoadCause(self.0.clone_box())
+    }
+}
+
+/// Causes are opaque and don't take part in comparisons, the error's message does.
+impl PartialEq for LoadCause {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
+impl Debug for LoadCause {
+    fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+        f.write_str("LoadCause(..)")
+    }
+}
+
+/// Results of a streamed call, see `Plugin::execute_stream`.
+#[cfg(feature = "std")]
+pub struct PluginStream<P: PluginData> {
+    plugin: Plugin<P>,
+    id: PluginOpCallId,
+    result_receiver: ResultReceiver<P>,
+    backlog: Option<Arc<Backlog>>,
+    done: bool,
+}
+
+#[cfg(feature = "std")]
+impl<P: PluginData> PluginStream<P> {
+    pub fn id(&self) -> PluginOpCallId {
+        self.id
+    }
+}
+
+#[cfg(feature = "std")]
+impl<P: PluginData> Iterator for PluginStream<P> {
+    type Item = PluginResult<PluginCallOutcome<P>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.result_receiver.recv() {
+            Ok(res) => {
+                if let Some(ref backlog) = self.backlog {
+                    backlog.read();
+                }
+                Some(res.map(Into::into))
+            }
+            Err(_) => {
+                self.done = true;
+                self.plugin.failure().map(Err)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<P: PluginData> Drop for PluginStream<P> {
+    fn drop(&mut self) {
+        if let Some(ref backlog) = self.backlog {
+            backlog.close();
+        }
+        if !self.done {
+            self.plugin.cancel(self.id);
+        }
+    }
+}
+
+/// Results published to a topic, in order, see `Plugin::subscribe`. Iterating blocks until
+/// the next one and ends with the subscription. Dropping it unsubscribes.
+#[cfg(feature = "std")]
+pub struct Subscription<P: PluginData> {
+    topics: Topics<P>,
+    topic: String,
+    id: PluginOpCallId,
+    result_receiver: Receiver<RuntimeResult<P::PluginCallResult>>,
+}
+
+#[cfg(feature = "std")]
+impl<P: PluginData> Subscription<P> {
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+
+    /// The next published result if one is already waiting.
+    pub fn try_next(&self) -> Option<PluginCallOutcome<P>> {
+        self.result_receiver.try_recv().ok().map(Into::into)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<P: PluginData> Iterator for Subscription<P> {
+    type Item = PluginCallOutcome<P>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.result_receiver.recv().ok().map(Into::into)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<P: PluginData> Drop for Subscription<P> {
+    fn drop(&mut self) {
+        self.topics.remove(&self.topic, &self.id);
+    }
+}
+
+/// A call in flight as a `Future`, see `Plugin::execute_pending`.
+///
+/// Resolves once the event loop answers, or with the plugin's failure or `Disconnected` if it
+/// never will. Dropping it first cancels the call like `Plugin::cancel`: the subscriber is
+/// removed, so a late result is discarded, and the event loop sees `Handle::is_cancelled`.
+#[cfg(feature = "std")]
+pub struct PendingCall<P: PluginData> {
+    plugin: Plugin<P>,
+    id: PluginOpCallId,
+    /// The call while it waits for a free slot under `max_pending`.
+    queued: Option<(P::PluginCall, ResultSender<P::PluginCallResult>)>,
+    result_receiver: Receiver<TaggedResult<P::PluginCallResult>>,
+    waker: Arc<Mutex<Option<Waker>>>,
+    done: bool,
+}
+
+#[cfg(feature = "std")]
+impl<P: PluginData> PendingCall<P> {
+    pub fn id(&self) -> PluginOpCallId {
+        self.id
+    }
+
+    fn send(&mut self, permit: Option<PendingPermit>) -> PluginResult<()> {
+        if let Some((plugin_call, result_sender)) = self.queued.take() {
+            if let Err(e) = self.plugin.check_open().and_then(|_| self.plugin.enqueue(self.id, plugin_call, HashMap::new(), None, self.plugin.subscriber(result_sender, None, permit))) {
+                self.done = true;
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    fn received(&mut self, res: Result<TaggedResult<P::PluginCallResult>, TryRecvError>) -> Poll<PluginResult<PluginCallOutcome<P>>> {
+        match res {
+            Ok((_id, res)) => {
+                self.done = true;
+                Poll::Ready(res.map(Into::into))
+            }
+            Err(TryRecvError::Disconnected) => {
+                self.done = true;
+                Poll::Ready(Err(self.plugin.failure().unwrap_or(PluginError::Disconnected)))
+            }
+            Err(TryRecvError::Empty) => Poll::Pending,
         }
-        Ok(res.unwrap().into())
     }
 }
 
+// Nothing is structurally pinned.
+#[cfg(feature = "std")]
+impl<P: PluginData> Unpin for PendingCall<P> {}
+
+#[cfg(feature = "std")]
+impl<P: PluginData> Future for PendingCall<P> {
+    type Output = PluginResult<PluginCallOutcome<P>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if self.queued.is_some() {
+            let permit = match self.plugin.pending_limit {
+                Some(ref limit) => match limit.poll_acquire(cx) {
+                    Poll::Ready(Ok(permit)) => Some(permit),
+                    Poll::Ready(Err(e)) => {
+                        self.done = true;
+                        return Poll::Ready(Err(e));
+                    }
+                    Poll::Pending => return Poll::Pending,
+                },
+                None => None,
+            };
+            if let Err(e) = self.send(permit) {
+                return Poll::Ready(Err(e));
+            }
+        }
+        let res = self.result_receiver.try_recv();
+        if let Poll::Ready(res) = self.received(res) {
+            return Poll::Ready(res);
+        }
+        *self.waker.lock().unwrap() = Some(cx.waker().clone());
+        // The result may have landed before the waker was registered.
+        let res = self.result_receiver.try_recv();
+        self.received(res)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<P: PluginData> Drop for PendingCall<P> {
+    fn drop(&mut self) {
+        if !self.done && self.queued.is_none() {
+            self.plugin.cancel(self.id);
+        }
+    }
+}
+
+/// Order in which `Plugin::execute_batch` returns results.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResultOrder {
+    /// Same order as the calls, waiting for slow calls before returning faster later ones.
+    Submission,
+    /// Order in which the results arrived.
+    Completion,
+}
+
+/// How often `Plugin::execute_retry` resends a call and how long it waits in between. The wait
+/// doubles after every retry, up to `max_backoff`.
 #[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_retries: usize,
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+#[cfg(feature = "std")]
+impl RetryPolicy {
+    fn next_backoff(&self, backoff: Duration) -> Duration {
+        backoff.saturating_mul(2).min(self.max_backoff)
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 3,
+            backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_secs(1),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum PluginError {
-    FailedToLoad(String),
+    FailedToLoad(String, Option<LoadCause>),
     InvalidPlugin(String),
     RuntimeError(String),
+    NotLoaded(String),
+    Timeout(Duration),
+    Disconnected,
+    Restarted(String),
 }
 
 impl core::fmt::Display for PluginError {
     fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
         match self {
-            PluginError::FailedToLoad(e) => {
+            PluginError::FailedToLoad(e, _) => {
                 writeln!(f, "Failed to load plugin: {}", e)
             }
             PluginError::InvalidPlugin(e) => {
@@ -86,33 +985,187 @@ impl core::fmt::Display for PluginError {
             PluginError::RuntimeError(e) => {
                 writeln!(f, "Error occured while using plugin: {}", e)
             }
+            PluginError::NotLoaded(name) => {
+                writeln!(f, "Plugin is not loaded: {}", name)
+            }
+            PluginError::Timeout(after) => {
+                writeln!(f, "Plugin call timed out after {:?}", after)
+            }
+            PluginError::Disconnected => {
+                writeln!(f, "Plugin runtime is disconnected")
+            }
+            PluginError::Restarted(reason) => {
+                writeln!(f, "Plugin event loop was restarted before answering: {}", reason)
+            }
+        }
+    }
+}
+
+/// Payload-free category of a `PluginError`, for cheap branching and assertions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PluginErrorKind {
+    FailedToLoad,
+    InvalidPlugin,
+    RuntimeError,
+    NotLoaded,
+    Timeout,
+    Disconnected,
+    Restarted,
+}
+
+impl PluginError {
+    pub fn kind(&self) -> PluginErrorKind {
+        match self {
+            PluginError::FailedToLoad(..) => PluginErrorKind::FailedToLoad,
+            PluginError::InvalidPlugin(_) => PluginErrorKind::InvalidPlugin,
+            PluginError::RuntimeError(_) => PluginErrorKind::RuntimeError,
+            PluginError::NotLoaded(_) => PluginErrorKind::NotLoaded,
+            PluginError::Timeout(_) => PluginErrorKind::Timeout,
+            PluginError::Disconnected => PluginErrorKind::Disconnected,
+            PluginError::Restarted(_) => PluginErrorKind::Restarted,
+        }
+    }
+
+    /// Whether sending the same call again may succeed: the call timed out, the runtime went
+    /// away or the event loop was restarted before answering. Errors added later default to not
+    /// retryable until they are classified here.
+    pub fn is_retryable(&self) -> bool {
+        matches!(self.kind(), PluginErrorKind::Timeout | PluginErrorKind::Disconnected | PluginErrorKind::Restarted)
+    }
+
+    /// Whether the plugin itself is unusable, so no call to it should be retried: it failed to
+    /// load or is invalid. A `RuntimeError` or `NotLoaded` is neither retryable nor fatal, it
+    /// depends on the caller.
+    pub fn is_fatal(&self) -> bool {
+        matches!(self.kind(), PluginErrorKind::FailedToLoad | PluginErrorKind::InvalidPlugin)
+    }
+
+    /// The typed error a plugin rejected its load call with, when it was of type `T`.
+    pub fn load_cause<T: Any>(&self) -> Option<&T> {
+        match self {
+            PluginError::FailedToLoad(_, Some(cause)) => cause.downcast_ref(),
+            _ => None,
         }
     }
 }
 
-impl Error for PluginError {}
+#[cfg(feature = "std")]
+impl std::error::Error for PluginError {}
 
+/// Runs without `std` too, against the traits and errors alone.
 #[cfg(test)]
-mod tests {
-    use crate::tokio_utils::create_tokio_runtime;
-    use crate::test_utils::{build_dummy_runtime, DummySource, dummy_event_loop};
-    use crate::loader::PluginLoader;
+mod core_tests {
+    use crate::{LoadCause, PluginCallResult, PluginData, PluginError, PluginErrorKind};
+    use alloc::borrow::Cow;
+    use alloc::string::{String, ToString};
+    use core::time::Duration;
 
-    #[test]
-    fn execute() {
-        let mut dummy_runtime = build_dummy_runtime();
+    #[derive(Clone)]
+    struct Sensor;
+
+    #[derive(Clone)]
+    struct Reading;
+
+    impl PluginCallResult for Reading {
+        type Ok = u32;
+        type Err = String;
+    }
+
+    impl PluginData for Sensor {
+        type PluginCall = u8;
+        type PluginCallResult = Reading;
+
+        fn name(&self) -> String {
+            "sensor".to_string()
+        }
+    }
+
+    #[test]
+    fn core_plugin_data() {
+        assert!(matches!(Sensor.name_ref(), Cow::Owned(name) if name == "sensor"));
+    }
+
+    #[test]
+    fn core_errors() {
+        let timeout = PluginError::Timeout(Duration::from_millis(5));
+        assert_eq!(timeout.kind(), PluginErrorKind::Timeout);
+        assert!(timeout.is_retryable());
+        assert_eq!(timeout.to_string(), "Plugin call timed out after 5ms\n");
+        let failed = PluginError::FailedToLoad("bad config".to_string(), Some(LoadCause::new(7u32)));
+        assert!(failed.is_fatal());
+        assert_eq!(failed.load_cause::<u32>(), Some(&7));
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use crate::tokio_utils::create_tokio_runtime;
+    use crate::test_utils::{build_dummy_runtime, DummySource, dummy_event_loop, header_event_loop, load_only_event_loop, cancellable_event_loop, deadline_event_loop, streaming_event_loop, lagging_stream_event_loop, progress_event_loop, retry_event_loop, reversing_event_loop, jittery_event_loop, late_first_event_loop};
+    use crate::{LoadCause, PluginError, PluginErrorKind, ResultOrder, RetryPolicy};
+    use std::time::Duration;
+    use std::collections::HashMap;
+    use crate::loader::PluginLoader;
+    use crate::test_utils::{DummyPlugin, KindPlugin};
+    use crate::PluginData;
+    use std::borrow::Cow;
+    use crate::runtime::{PluginOpCall, PluginOpCallId, PluginRuntime, RuntimeOptions, RuntimeResult, Subscribers};
+    use crate::observer::CallObserver;
+    use crate::test_utils::DummyResult;
+    use crate::PluginResult;
+    use crate::subscribers::SubscriberMap;
+    use crate::Plugin;
+    use std::sync::Arc;
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn name_ref() {
+        assert!(matches!(DummyPlugin {}.name_ref(), Cow::Borrowed("test")));
+        assert!(matches!(KindPlugin::Greeter.name_ref(), Cow::Owned(name) if name == "greeter"));
+        let counter = Plugin::from_parts(KindPlugin::Counter(0), channel().0, Arc::new(SubscriberMap::with_capacity(0)));
+        assert_eq!(counter.name_ref(), counter.name());
+    }
+
+    #[test]
+    fn compare_errors() {
+        assert_eq!(PluginError::Timeout(Duration::from_millis(5)), PluginError::Timeout(Duration::from_millis(5)));
+        assert_ne!(PluginError::Timeout(Duration::from_millis(5)), PluginError::Timeout(Duration::from_millis(6)));
+        assert_ne!(PluginError::NotLoaded("a".to_string()), PluginError::InvalidPlugin("a".to_string()));
+        assert_eq!(PluginError::Disconnected, PluginError::Disconnected);
+        let failed = |cause| PluginError::FailedToLoad("bad config".to_string(), Some(cause));
+        assert_eq!(failed(LoadCause::new(1u32)), failed(LoadCause::new("other")));
+    }
+
+    #[test]
+    fn error_kinds() {
+        let errors = vec![
+            (PluginError::FailedToLoad("bad config".to_string(), None), PluginErrorKind::FailedToLoad),
+            (PluginError::InvalidPlugin("unknown".to_string()), PluginErrorKind::InvalidPlugin),
+            (PluginError::RuntimeError("crashed".to_string()), PluginErrorKind::RuntimeError),
+            (PluginError::NotLoaded("test".to_string()), PluginErrorKind::NotLoaded),
+            (PluginError::Timeout(Duration::from_millis(5)), PluginErrorKind::Timeout),
+            (PluginError::Disconnected, PluginErrorKind::Disconnected),
+            (PluginError::Restarted("panicked".to_string()), PluginErrorKind::Restarted),
+        ];
+        for (error, kind) in errors {
+            assert_eq!(error.kind(), kind);
+        }
+    }
+
+    #[test]
+    fn execute() {
+        let mut dummy_runtime = build_dummy_runtime();
         let (fut1, handle) = dummy_runtime.run();
         let runtime = create_tokio_runtime();
         let handle1 = runtime.spawn(fut1);
         let handle2 = runtime.spawn(async move {
-            dummy_event_loop(handle);
+            dummy_event_loop(handle)
         });
         let mut dummy_loader = PluginLoader::new(DummySource{}, dummy_runtime);
         let plugins = dummy_loader.load_plugins(vec![]);
         let plugin = plugins.first().unwrap();
         let res = plugin.execute(());
         if let Err(e) = res {
-            panic!(e)
+            panic!("{}", e)
         }
         let res = res.unwrap();
         assert_eq!(res, Ok("hello".to_string()));
@@ -122,4 +1175,611 @@ mod tests {
             tokio::join!(handle1, handle2)
         });
     }
-}
+
+    #[test]
+    fn execute_with_headers() {
+        let mut dummy_runtime = build_dummy_runtime();
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let handle2 = runtime.spawn(async move {
+            header_event_loop(handle, "tenant")
+        });
+        let mut dummy_loader = PluginLoader::new(DummySource{}, dummy_runtime);
+        let plugins = dummy_loader.load_plugins(vec![]);
+        let plugin = plugins.first().unwrap();
+        let mut headers = HashMap::new();
+        headers.insert("tenant".to_string(), "acme".to_string());
+        assert_eq!(plugin.execute_with_headers((), headers).unwrap(), Ok("acme".to_string()));
+        assert_eq!(plugin.execute(()).unwrap(), Ok("".to_string()));
+        drop(plugins);
+        drop(dummy_loader);
+        let (_res1, _res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+    }
+
+    #[test]
+    fn execute_all() {
+        let mut dummy_runtime = build_dummy_runtime();
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let handle2 = runtime.spawn(async move {
+            dummy_event_loop(handle)
+        });
+        let mut dummy_loader = PluginLoader::new(DummySource{}, dummy_runtime);
+        let plugins = dummy_loader.load_plugins(vec![]);
+        let plugin = plugins.first().unwrap();
+        let results = plugin.execute_all(vec![(), ()], Duration::from_secs(5));
+        assert_eq!(results.len(), 2);
+        for res in results {
+            assert_eq!(res.unwrap(), Ok("hello".to_string()));
+        }
+        drop(plugins);
+        drop(dummy_loader);
+        let (_res1, _res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+    }
+
+    #[test]
+    fn error_categories() {
+        let retryable = [PluginError::Timeout(Duration::from_secs(1)), PluginError::Disconnected, PluginError::Restarted("panic".to_string())];
+        let fatal = [PluginError::FailedToLoad("load".to_string(), None), PluginError::InvalidPlugin("plugin".to_string())];
+        let undecided = [PluginError::RuntimeError("error".to_string()), PluginError::NotLoaded("plugin".to_string())];
+        assert!(retryable.iter().all(|e| e.is_retryable() && !e.is_fatal()));
+        assert!(fatal.iter().all(|e| e.is_fatal() && !e.is_retryable()));
+        assert!(undecided.iter().all(|e| !e.is_fatal() && !e.is_retryable()));
+    }
+
+    #[test]
+    fn execute_pending() {
+        let mut dummy_runtime = build_dummy_runtime();
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let handle2 = runtime.spawn(async move {
+            dummy_event_loop(handle)
+        });
+        let plugin = dummy_runtime.load_plugin(DummyPlugin {}).unwrap();
+        let pending = plugin.execute_pending(()).unwrap();
+        assert_eq!(runtime.block_on(pending), Ok(Ok("hello".to_string())));
+        drop(plugin);
+        drop(dummy_runtime);
+        let (_res1, _res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+    }
+
+    #[test]
+    fn drop_pending_call() {
+        let mut dummy_runtime = build_dummy_runtime();
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let handle2 = runtime.spawn(async move {
+            load_only_event_loop(handle)
+        });
+        let plugin = dummy_runtime.load_plugin(DummyPlugin {}).unwrap();
+        let pending = plugin.execute_pending(()).unwrap();
+        assert_eq!(dummy_runtime.inflight_ids(), vec![pending.id()]);
+        drop(pending);
+        assert!(dummy_runtime.inflight_ids().is_empty());
+        drop(plugin);
+        drop(dummy_runtime);
+        let (_res1, _res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+    }
+
+    #[test]
+    fn execute_arc() {
+        let mut dummy_runtime = build_dummy_runtime();
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let handle2 = runtime.spawn(async move {
+            dummy_event_loop(handle)
+        });
+        let plugin = dummy_runtime.load_plugin(DummyPlugin {}).unwrap();
+        let call = Arc::new(());
+        for _ in 0..2 {
+            assert_eq!(plugin.execute_arc(call.clone()).unwrap(), Ok("hello".to_string()));
+        }
+        drop(plugin);
+        drop(dummy_runtime);
+        let (_res1, _res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+    }
+
+    #[test]
+    fn default_timeout() {
+        let mut dummy_runtime = build_dummy_runtime();
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let handle2 = runtime.spawn(async move {
+            load_only_event_loop(handle)
+        });
+        let plugin = dummy_runtime.load_plugin(DummyPlugin {}).unwrap().with_default_timeout(Some(Duration::from_millis(10)));
+        assert_eq!(plugin.execute(()), Err(PluginError::Timeout(Duration::from_millis(10))));
+        assert_eq!(plugin.execute_with_progress((), |_| {}), Err(PluginError::Timeout(Duration::from_millis(10))));
+        assert_eq!(plugin.execute_pooled(()), Err(PluginError::Timeout(Duration::from_millis(10))));
+        assert_eq!(plugin.execute_timed(()).err(), Some(PluginError::Timeout(Duration::from_millis(10))));
+        assert_eq!(plugin.execute_shared(()).err(), Some(PluginError::Timeout(Duration::from_millis(10))));
+        assert_eq!(plugin.execute_timeout((), Duration::from_millis(20)), Err(PluginError::Timeout(Duration::from_millis(20))));
+        assert!(dummy_runtime.inflight_ids().is_empty());
+        drop(plugin);
+        drop(dummy_runtime);
+        let (_res1, _res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+    }
+
+    #[test]
+    fn execute_retry() {
+        let mut dummy_runtime = PluginRuntime::new();
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let handle2 = runtime.spawn(async move {
+            retry_event_loop(handle)
+        });
+        let plugin = dummy_runtime.load_plugin(DummyPlugin {}).unwrap().with_default_timeout(Some(Duration::from_millis(20)));
+        let policy = RetryPolicy { max_retries: 2, backoff: Duration::from_millis(1), ..Default::default() };
+        assert_eq!(plugin.execute_retry((), &policy), Ok(Ok("fresh".to_string())));
+        assert_eq!(plugin.execute_retry((), &policy), Ok(Err("nope".to_string())));
+        assert_eq!(plugin.execute(()), Ok(Ok("hello".to_string())));
+        assert!(dummy_runtime.inflight_ids().is_empty());
+        drop(plugin);
+        drop(dummy_runtime);
+        let (_res1, _res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+    }
+
+    #[test]
+    fn retry_backoff_is_capped() {
+        let policy = RetryPolicy { max_backoff: Duration::from_millis(30), ..Default::default() };
+        assert_eq!(policy.next_backoff(Duration::from_millis(10)), Duration::from_millis(20));
+        assert_eq!(policy.next_backoff(Duration::from_millis(20)), Duration::from_millis(30));
+        let uncapped = RetryPolicy { max_backoff: Duration::MAX, ..Default::default() };
+        assert_eq!(uncapped.next_backoff(Duration::MAX), Duration::MAX);
+    }
+
+    struct RecordingObserver(std::sync::Mutex<Vec<String>>);
+
+    impl CallObserver<DummyPlugin> for RecordingObserver {
+        fn before_send(&self, call: &PluginOpCall<DummyPlugin>) {
+            self.0.lock().unwrap().push(format!("send to {}", call.plugin_name()));
+        }
+
+        fn after_result(&self, _id: &PluginOpCallId, result: &PluginResult<RuntimeResult<DummyResult>>, _latency: Duration) {
+            self.0.lock().unwrap().push(match result {
+                Ok(RuntimeResult::Ok(value)) => format!("resolved {}", value.as_str()),
+                Ok(RuntimeResult::Err(e)) => format!("rejected {}", e),
+                Err(e) => format!("failed with {:?}", e.kind()),
+            });
+        }
+    }
+
+    #[test]
+    fn observe_calls() {
+        let mut dummy_runtime = PluginRuntime::new();
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let handle2 = runtime.spawn(async move {
+            cancellable_event_loop(handle)
+        });
+        let mut plugin = dummy_runtime.load_plugin(DummyPlugin {}).unwrap();
+        let observer = Arc::new(RecordingObserver(Default::default()));
+        plugin.set_observer(observer.clone());
+        assert_eq!(plugin.execute(()), Ok(Ok("loaded".to_string())));
+        assert_eq!(plugin.execute_timeout((), Duration::from_millis(10)), Err(PluginError::Timeout(Duration::from_millis(10))));
+        assert_eq!(*observer.0.lock().unwrap(), vec![
+            "send to test".to_string(),
+            "resolved loaded".to_string(),
+            "send to test".to_string(),
+            "failed with Timeout".to_string(),
+        ]);
+        drop(plugin);
+        drop(dummy_runtime);
+        let (_res1, _res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+    }
+
+    #[test]
+    fn execute_batch_order() {
+        let mut dummy_runtime = build_dummy_runtime();
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let handle2 = runtime.spawn(async move {
+            reversing_event_loop(handle, 3)
+        });
+        let plugin = dummy_runtime.load_plugin(DummyPlugin {}).unwrap();
+        let expected = |indices: &[usize]| indices.iter().map(|index| (*index, Ok(Ok::<String, String>(index.to_string())))).collect::<Vec<_>>();
+        let results = plugin.execute_batch(vec![(), (), ()], Duration::from_secs(5), ResultOrder::Submission);
+        assert_eq!(results, expected(&[0, 1, 2]));
+        let results = plugin.execute_batch(vec![(), (), ()], Duration::from_secs(5), ResultOrder::Completion);
+        assert_eq!(results, expected(&[2, 1, 0]));
+        drop(plugin);
+        drop(dummy_runtime);
+        let (_res1, _res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+    }
+
+    #[test]
+    fn execute_all_deadline() {
+        let mut dummy_runtime = build_dummy_runtime();
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let handle2 = runtime.spawn(async move {
+            load_only_event_loop(handle)
+        });
+        let mut dummy_loader = PluginLoader::new(DummySource{}, dummy_runtime);
+        let plugins = dummy_loader.load_plugins(vec![]);
+        let plugin = plugins.first().unwrap();
+        let results = plugin.execute_all(vec![(), ()], Duration::from_millis(50));
+        assert_eq!(results.len(), 2);
+        for res in results {
+            assert!(matches!(res, Err(PluginError::Timeout(_))));
+        }
+        drop(plugins);
+        drop(dummy_loader);
+        let (_res1, _res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+    }
+
+    #[test]
+    fn execute_from_clones() {
+        let mut dummy_runtime = build_dummy_runtime();
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let handle2 = runtime.spawn(async move {
+            dummy_event_loop(handle)
+        });
+        let mut dummy_loader = PluginLoader::new(DummySource{}, dummy_runtime);
+        let plugins = dummy_loader.load_plugins(vec![]);
+        let callers: Vec<_> = (0..2).map(|_| {
+            let plugin = plugins.first().unwrap().clone();
+            std::thread::spawn(move || {
+                (0..10).map(|_| plugin.execute(()).unwrap()).collect::<Vec<_>>()
+            })
+        }).collect();
+        for caller in callers {
+            for res in caller.join().unwrap() {
+                assert_eq!(res, Ok("hello".to_string()));
+            }
+        }
+        drop(plugins);
+        drop(dummy_loader);
+        let (_res1, _res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+    }
+
+    #[test]
+    fn execute_map() {
+        let mut dummy_runtime = build_dummy_runtime();
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let handle2 = runtime.spawn(async move {
+            dummy_event_loop(handle)
+        });
+        let mut dummy_loader = PluginLoader::new(DummySource{}, dummy_runtime);
+        let plugins = dummy_loader.load_plugins(vec![]);
+        let plugin = plugins.first().unwrap();
+        let len = plugin.execute_map((), |res| res.map(|greeting| greeting.len()));
+        assert_eq!(len.unwrap(), Ok(5));
+        drop(plugins);
+        drop(dummy_loader);
+        let (_res1, _res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+    }
+
+    #[test]
+    fn execute_shared() {
+        let mut dummy_runtime = build_dummy_runtime();
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let handle2 = runtime.spawn(async move {
+            dummy_event_loop(handle)
+        });
+        let plugin = dummy_runtime.load_plugin(DummyPlugin {}).unwrap();
+        let shared = plugin.execute_shared(()).unwrap().unwrap();
+        assert_eq!(shared.as_str(), "hello");
+        drop(plugin);
+        drop(dummy_runtime);
+        let (_res1, _res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+    }
+
+    #[test]
+    fn execute_pooled() {
+        let mut pooled_runtime = PluginRuntime::new();
+        let (fut1, handle) = pooled_runtime.run();
+        let mut progress_runtime = build_dummy_runtime();
+        let (fut2, progress_handle) = progress_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let handle2 = runtime.spawn(async move {
+            late_first_event_loop(handle)
+        });
+        let handle3 = runtime.spawn(fut2);
+        let handle4 = runtime.spawn(async move {
+            progress_event_loop(progress_handle)
+        });
+        let plugin = pooled_runtime.load_plugin(DummyPlugin {}).unwrap();
+        // The first call times out, which drops its subscriber without a result; the slot it
+        // leaves behind serves the next calls and never sees the late "late".
+        let impatient = plugin.clone().with_default_timeout(Some(Duration::from_millis(10)));
+        assert_eq!(impatient.execute_pooled(()), Err(PluginError::Timeout(Duration::from_millis(10))));
+        for _ in 0..3 {
+            assert_eq!(plugin.execute_pooled(()), Ok(Ok("hello".to_string())));
+        }
+        // Progress callbacks run on the other runtime's result loop, which pools a slot of its own.
+        let nested = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let (recorded, caller) = (nested.clone(), plugin.clone());
+        let progress_plugin = progress_runtime.load_plugin(DummyPlugin {}).unwrap();
+        let outer = progress_plugin.execute_with_progress((), move |_| {
+            recorded.lock().unwrap().push(caller.execute_pooled(()));
+        });
+        assert_eq!(outer, Ok(Ok("done".to_string())));
+        let nested = nested.lock().unwrap().drain(..).collect::<Vec<_>>();
+        assert!(!nested.is_empty());
+        assert!(nested.iter().all(|res| res == &Ok(Ok("hello".to_string()))));
+        assert!(pooled_runtime.inflight_ids().is_empty());
+        drop(progress_plugin);
+        drop(progress_runtime);
+        drop(impatient);
+        drop(plugin);
+        drop(pooled_runtime);
+        let (_res1, _res2, _res3, _res4) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2, handle3, handle4)
+        });
+    }
+
+    #[test]
+    fn timeout_cancels_call() {
+        let mut dummy_runtime = build_dummy_runtime();
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let handle2 = runtime.spawn(async move {
+            cancellable_event_loop(handle)
+        });
+        let mut dummy_loader = PluginLoader::new(DummySource{}, dummy_runtime);
+        let plugins = dummy_loader.load_plugins(vec![]);
+        let plugin = plugins.first().unwrap();
+        let results = plugin.execute_all(vec![()], Duration::from_millis(20));
+        assert_eq!(results[0], Err(PluginError::Timeout(Duration::from_millis(20))));
+        let results = plugin.execute_all(vec![()], Duration::from_millis(20));
+        assert_eq!(results[0], Err(PluginError::Timeout(Duration::from_millis(20))));
+        drop(plugins);
+        drop(dummy_loader);
+        let (_res1, _res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+    }
+
+    #[test]
+    fn race_timeouts_against_results() {
+        let mut dummy_runtime = PluginRuntime::new();
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let handle2 = runtime.spawn(async move {
+            jittery_event_loop(handle)
+        });
+        let plugin = dummy_runtime.load_plugin(DummyPlugin {}).unwrap();
+        let timeout = Duration::from_millis(1);
+        let callers: Vec<_> = (0..8).map(|_| {
+            let caller = plugin.clone();
+            std::thread::spawn(move || (0..25).map(|_| caller.execute_timeout((), timeout)).collect::<Vec<_>>())
+        }).collect();
+        let results: Vec<_> = callers.into_iter().flat_map(|caller| caller.join().unwrap()).collect();
+        let resolved = results.iter().filter(|result| **result == Ok(Ok("hello".to_string()))).count();
+        let timed_out = results.iter().filter(|result| **result == Err(PluginError::Timeout(timeout))).count();
+        assert_eq!(resolved + timed_out, 200);
+        let metrics = dummy_runtime.metrics()["test"].clone();
+        assert_eq!((metrics.resolved as usize, metrics.timed_out as usize), (resolved, timed_out));
+        assert!(dummy_runtime.inflight_ids().is_empty());
+        drop(plugin);
+        drop(dummy_runtime);
+        let (_res1, _res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+    }
+
+    #[test]
+    fn execute_timeout_carries_deadline() {
+        let mut dummy_runtime = build_dummy_runtime();
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let handle2 = runtime.spawn(async move {
+            deadline_event_loop(handle)
+        });
+        let mut dummy_loader = PluginLoader::new(DummySource{}, dummy_runtime);
+        let plugins = dummy_loader.load_plugins(vec![]);
+        let plugin = plugins.first().unwrap();
+        assert_eq!(plugin.execute(()).unwrap(), Ok("no deadline".to_string()));
+        assert_eq!(plugin.execute_timeout((), Duration::from_secs(5)).unwrap(), Ok("in time".to_string()));
+        let res = plugin.execute_timeout((), Duration::from_millis(0));
+        assert!(matches!(res, Err(PluginError::Timeout(_))) || res == Ok(Err("expired".to_string())));
+        drop(plugins);
+        drop(dummy_loader);
+        let (_res1, _res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+    }
+
+    #[test]
+    fn execute_into_shared_channel() {
+        let mut dummy_runtime = build_dummy_runtime();
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let handle2 = runtime.spawn(async move {
+            dummy_event_loop(handle)
+        });
+        let mut dummy_loader = PluginLoader::new(DummySource{}, dummy_runtime);
+        let plugins = dummy_loader.load_plugins(vec![]);
+        let plugin = plugins.first().unwrap();
+        let (result_sender, result_receiver) = channel();
+        let mut ids = vec![
+            plugin.execute_into((), result_sender.clone()).unwrap(),
+            plugin.execute_into((), result_sender).unwrap(),
+        ];
+        let mut received: Vec<_> = result_receiver.iter().take(2).map(|(id, res)| {
+            assert_eq!(res.map(Into::into), Ok(Ok::<String, String>("hello".to_string())));
+            id
+        }).collect();
+        ids.sort();
+        received.sort();
+        assert_eq!(ids, received);
+        drop(plugins);
+        drop(dummy_loader);
+        let (_res1, _res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+    }
+
+    #[test]
+    fn execute_stream() {
+        let mut dummy_runtime = build_dummy_runtime();
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let handle2 = runtime.spawn(async move {
+            streaming_event_loop(handle, 3)
+        });
+        let mut dummy_loader = PluginLoader::new(DummySource{}, dummy_runtime);
+        let plugins = dummy_loader.load_plugins(vec![]);
+        let plugin = plugins.first().unwrap();
+        let items: Vec<_> = plugin.execute_stream(()).unwrap().collect();
+        assert_eq!(items, vec![Ok(Ok("0".to_string())), Ok(Ok("1".to_string())), Ok(Ok("2".to_string()))]);
+        let mut stream = plugin.execute_stream(()).unwrap();
+        assert_eq!(stream.next(), Some(Ok(Ok("0".to_string()))));
+        drop(stream);
+        drop(plugins);
+        drop(dummy_loader);
+        let (_res1, _res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+    }
+
+    #[test]
+    fn cap_buffered_items() {
+        let mut dummy_runtime = PluginRuntime::with_options(RuntimeOptions {
+            max_buffered: Some(2),
+            ..Default::default()
+        });
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let (refused_sender, refused_receiver) = channel();
+        let handle2 = runtime.spawn(async move {
+            lagging_stream_event_loop(handle, refused_sender)
+        });
+        let plugin = dummy_runtime.load_plugin(DummyPlugin {}).unwrap();
+        let stream = plugin.execute_stream(()).unwrap();
+        assert_eq!(refused_receiver.recv_timeout(Duration::from_secs(1)), Ok(true));
+        let items: Vec<_> = stream.collect();
+        assert_eq!(items, ["0", "1", "2", "3", "done"].iter().map(|item| Ok(Ok(item.to_string()))).collect::<Vec<_>>());
+        drop(plugin);
+        drop(dummy_runtime);
+        let (_res1, _res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+    }
+
+    #[test]
+    fn execute_with_progress() {
+        let mut dummy_runtime = build_dummy_runtime();
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let handle2 = runtime.spawn(async move {
+            progress_event_loop(handle)
+        });
+        let mut dummy_loader = PluginLoader::new(DummySource{}, dummy_runtime);
+        let plugins = dummy_loader.load_plugins(vec![]);
+        let plugin = plugins.first().unwrap();
+        let reported = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let progress = reported.clone();
+        let res = plugin.execute_with_progress((), move |fraction| progress.lock().unwrap().push(fraction));
+        assert_eq!(res, Ok(Ok("done".to_string())));
+        assert_eq!(*reported.lock().unwrap(), vec![0.0, 0.5, 1.0]);
+        drop(plugins);
+        drop(dummy_loader);
+        let (_res1, _res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+    }
+
+    #[test]
+    fn execute_timed() {
+        let mut dummy_runtime = build_dummy_runtime();
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let handle2 = runtime.spawn(async move {
+            while let Ok(call) = handle.receive() {
+                std::thread::sleep(Duration::from_millis(20));
+                handle.resolve(call.id(), "hello");
+            }
+        });
+        let plugin = dummy_runtime.load_plugin(DummyPlugin {}).unwrap();
+        let ahead = plugin.clone();
+        let ahead = std::thread::spawn(move || ahead.execute(()));
+        std::thread::sleep(Duration::from_millis(5));
+        let (res, timing) = plugin.execute_timed(()).unwrap();
+        assert_eq!(res, Ok("hello".to_string()));
+        assert!(timing.queued >= Duration::from_millis(10), "{:?}", timing);
+        assert!(timing.processing >= Duration::from_millis(20), "{:?}", timing);
+        assert_eq!(timing.total, timing.queued + timing.processing);
+        assert_eq!(ahead.join().unwrap(), Ok(Ok("hello".to_string())));
+        drop(plugin);
+        drop(dummy_runtime);
+        let (_res1, _res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+    }
+
+    #[test]
+    fn plugin_capabilities() {
+        let greeter = Plugin::from_parts(KindPlugin::Greeter, channel().0, Arc::new(SubscriberMap::with_capacity(0)));
+        assert_eq!(greeter.capabilities(), vec!["greet".to_string()]);
+        assert!(Plugin::from_parts(DummyPlugin {}, channel().0, Arc::new(SubscriberMap::with_capacity(0))).capabilities().is_empty());
+    }
+
+    #[test]
+    fn build_plugin_from_parts() {
+        let (call_sender, call_receiver) = channel::<PluginOpCall<DummyPlugin>>();
+        let subscribers: Subscribers<DummyPlugin> = Arc::new(SubscriberMap::with_capacity(16));
+        let plugin = Plugin::from_parts(DummyPlugin {}, call_sender, subscribers.clone());
+        let backend = std::thread::spawn(move || {
+            for call in call_receiver {
+                let subscriber = subscribers.remove(&call.id()).unwrap();
+                assert!(subscriber.sender.send(call.id(), Ok(Ok::<String, String>("bridged".to_string()).into())));
+            }
+        });
+        assert_eq!(plugin.execute(()).unwrap(), Ok("bridged".to_string()));
+        drop(plugin);
+        backend.join().unwrap();
+    }
+}
\ No newline at end of file
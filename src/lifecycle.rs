@@ -0,0 +1,10 @@
+use crate::PluginError;
+
+#[derive(Debug, Clone)]
+pub enum LifecycleEvent {
+    LoadStarted(String),
+    Loaded(String),
+    LoadFailed(String, PluginError),
+    Failed(String, PluginError),
+    Unloaded(String),
+}
\ No newline at end of file
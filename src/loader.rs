@@ -1,10 +1,120 @@
-use crate::source::PluginSource;
-use crate::{PluginData, Plugin};
-use crate::runtime::PluginRuntime;
+use crate::source::{PluginDescriptor, PluginSource};
+use crate::{PluginCallResult, PluginData, Plugin, PluginResult, PluginCallOutcome, PluginError};
+use crate::runtime::{panic_reason, CallPayload, Handle, Shared, PluginRuntime, RuntimeOptions, ShutdownReason};
+#[cfg(feature = "tokio")]
+use crate::runtime::RuntimeTasks;
+use crate::subscribers::ResultSender;
+use std::future::Future;
+use crate::lifecycle::LifecycleEvent;
+use crate::registry::{FromPluginName, PluginRegistry};
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::time::{Duration, Instant};
+use std::ops::Deref;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+/// Group of plugins whose descriptor doesn't name one.
+pub const DEFAULT_GROUP: &str = "default";
+
+struct LoadedPlugin<P: PluginData> {
+    plugin: Plugin<P>,
+    /// Name the source lists the plugin under, which digests and lifecycle events go by. The
+    /// map is keyed by the plugin's own name, which may differ.
+    source: String,
+    paused: bool,
+    group: String,
+}
+
+type LoadedPlugins<P> = Arc<Mutex<HashMap<String, LoadedPlugin<P>>>>;
+
+type Listeners = Arc<Mutex<Vec<Box<dyn Send + Fn(LifecycleEvent)>>>>;
+
+type IdleListeners = Arc<Mutex<Vec<Box<dyn Send + Fn()>>>>;
+
+/// Order `PluginLoader::load_plugins` and its siblings load discovered plugins in.
+#[derive(Default)]
+pub enum LoadOrder {
+    /// By name, so startup doesn't depend on e.g. the order a directory is listed in.
+    #[default]
+    Sorted,
+    /// Lowest priority first, plugins with the same priority by name.
+    Priority(Box<dyn Send + Fn(&str) -> i32>),
+    /// As `PluginSource::plugins_iter` yields them.
+    Source,
+}
+
+/// What `PluginLoader::load_one` and its siblings do with a plugin named like one already
+/// loaded: a name the source lists twice, or a plugin whose `PluginData::name` matches another
+/// one's. Loaded plugins are keyed by that name, so loading both would orphan the first.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Duplicates {
+    /// Keep the plugin already loaded and log a warning, `load_one` returns it.
+    #[default]
+    Skip,
+    /// Fail the load with `PluginError::InvalidPlugin`.
+    Reject,
+}
+
+/// How `PluginLoader::dispatch` picks one plugin of a group.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Selection {
+    /// The plugin with the lowest average latency, see `PluginMetrics::avg_latency`. Plugins
+    /// without any completed call are tried first, and every `EXPLORE_EVERY`th dispatch goes
+    /// to the plugin with the fewest calls, so a plugin that was slow once gets another chance.
+    LeastLatency,
+}
+
+/// See `Selection::LeastLatency`.
+pub const EXPLORE_EVERY: usize = 8;
+
+/// Decides when `PluginLoader::execute_quorum` has its answer.
+pub trait QuorumPolicy<P: PluginData> {
+    /// Index in `answers`, in the order they came in, of the one that settles a call sent to
+    /// `asked` plugins, or `None` to wait for more. Plugins that couldn't be called, timed out
+    /// or failed answer with their error.
+    fn settle(&self, answers: &[PluginResult<PluginCallOutcome<P>>], asked: usize) -> Option<usize>;
+}
+
+/// Settles on the first plugin to resolve, or on the last answer once every plugin failed.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FirstSuccess;
+
+impl<P: PluginData> QuorumPolicy<P> for FirstSuccess {
+    fn settle(&self, answers: &[PluginResult<PluginCallOutcome<P>>], asked: usize) -> Option<usize> {
+        answers.iter().position(|answer| matches!(answer, Ok(Ok(_))))
+            .or_else(|| answers.len().checked_sub(1).filter(|_| answers.len() >= asked))
+    }
+}
+
+/// Settles once more than half the plugins asked resolved to equal values. Rejections and
+/// errors never count towards a majority, so a split vote with no majority fails the call.
+/// Only available when the resolved type is `Eq`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Majority;
+
+impl<P: PluginData> QuorumPolicy<P> for Majority where <P::PluginCallResult as PluginCallResult>::Ok: Eq {
+    fn settle(&self, answers: &[PluginResult<PluginCallOutcome<P>>], asked: usize) -> Option<usize> {
+        answers.iter().position(|answer| match answer {
+            Ok(Ok(value)) => answers.iter().filter(|other| matches!(other, Ok(Ok(other)) if other == value)).count() * 2 > asked,
+            _ => false,
+        })
+    }
+}
 
 pub struct PluginLoader<Source: PluginSource> {
     source: Source,
     runtime: Option<PluginRuntime<Source::PluginType>>,
+    digests: HashMap<String, String>,
+    plugins: LoadedPlugins<Source::PluginType>,
+    listeners: Listeners,
+    idle_listeners: IdleListeners,
+    catch_open_panics: bool,
+    load_order: LoadOrder,
+    duplicates: Duplicates,
+    dispatched: AtomicUsize,
 }
 
 impl<Source: PluginSource> Drop for PluginLoader<Source> {
@@ -16,38 +126,702 @@ impl<Source: PluginSource> Drop for PluginLoader<Source> {
 
 impl<Source: 'static + PluginSource> PluginLoader<Source> {
     pub fn new(plugin_source: Source, plugin_runtime: PluginRuntime<Source::PluginType>) -> Self {
+        let plugins: LoadedPlugins<Source::PluginType> = Arc::new(Mutex::new(HashMap::new()));
+        let listeners: Listeners = Arc::new(Mutex::new(Vec::new()));
+        let idle_listeners: IdleListeners = Arc::new(Mutex::new(Vec::new()));
+        let (weak_plugins, weak_listeners) = (Arc::downgrade(&plugins), Arc::downgrade(&listeners));
+        let weak_idle_listeners = Arc::downgrade(&idle_listeners);
+        plugin_runtime.on_failure(move |name, error| {
+            let mut emptied = false;
+            let mut source = name.to_string();
+            if let Some(plugins) = weak_plugins.upgrade() {
+                let mut plugins = plugins.lock().unwrap();
+                let removed = plugins.remove(name);
+                emptied = removed.is_some() && plugins.is_empty();
+                drop(plugins);
+                if let Some(loaded) = removed {
+                    loaded.plugin.plugin_data.on_unload();
+                    source = loaded.source;
+                }
+            }
+            if let Some(listeners) = weak_listeners.upgrade() {
+                emit(&listeners, LifecycleEvent::Failed(source.clone(), error.clone()));
+                emit(&listeners, LifecycleEvent::Unloaded(source));
+            }
+            if let Some(idle_listeners) = weak_idle_listeners.upgrade().filter(|_| emptied) {
+                notify(&idle_listeners);
+            }
+        });
         PluginLoader {
             source: plugin_source,
             runtime: Some(plugin_runtime),
+            digests: HashMap::new(),
+            plugins,
+            listeners,
+            idle_listeners,
+            catch_open_panics: false,
+            load_order: LoadOrder::default(),
+            duplicates: Duplicates::default(),
+            dispatched: AtomicUsize::new(0),
         }
     }
 
-    pub fn load_plugins(&mut self, excludes: Vec<String>) -> Vec<Plugin<Source::PluginType>> {
+    /// Builds the runtime in place from the default options, e.g.
+    /// `PluginLoader::with_runtime_builder(source, |options| RuntimeOptions { plugin_loader: Some(..), ..options }.build())`,
+    /// then `start` hands out what `PluginRuntime::run` would.
+    pub fn with_runtime_builder<F>(plugin_source: Source, build: F) -> Self
+        where F: FnOnce(RuntimeOptions<Source::PluginType>) -> PluginRuntime<Source::PluginType> {
+        Self::new(plugin_source, build(RuntimeOptions::default()))
+    }
+
+    /// Starts the owned runtime, see `PluginRuntime::run`.
+    pub fn start(&mut self) -> (impl Future<Output=()> + Send, Handle<Source::PluginType>) {
+        self.runtime.as_mut().unwrap().run()
+    }
+
+    /// Like `start`, with the event loop run by the second future, which completes with the
+    /// loop's outcome so the host learns whether it ended in error. If it did, calls still
+    /// waiting for a result fail with that error rather than hanging. Spawn both futures like
+    /// those of `start`; see `spawn` for a runtime given `with_existing_tokio_handle`.
+    pub fn start_with<F, E>(&mut self, event_loop: F) -> (impl Future<Output=()> + Send, impl Future<Output=PluginResult<()>> + Send)
+        where F: 'static + Send + FnOnce(Handle<Source::PluginType>) -> Result<(), E>, E: ToString {
+        let (result_loop, handle) = self.start();
+        (result_loop, async move {
+            run_to_outcome(handle, event_loop)
+        })
+    }
+
+    /// Spawns both loops on the runtime's tokio handle, see `PluginRuntime::spawn`, with the
+    /// event loop's task yielding its outcome as in `start_with`.
+    #[cfg(feature = "tokio")]
+    pub fn spawn<F, E>(&mut self, event_loop: F) -> PluginResult<RuntimeTasks<PluginResult<()>>>
+        where F: 'static + Send + FnOnce(Handle<Source::PluginType>) -> Result<(), E>, E: ToString {
+        self.runtime.as_mut().unwrap().spawn(move |handle| run_to_outcome(handle, event_loop))
+    }
+
+    /// When set, a source whose `open` panics fails that one load with
+    /// `PluginError::InvalidPlugin` instead of unwinding through the loader. Off by default.
+    pub fn catch_open_panics(&mut self, catch: bool) {
+        self.catch_open_panics = catch;
+    }
+
+    /// Sets the order plugins are discovered and loaded in, `LoadOrder::Sorted` by default.
+    pub fn load_order(&mut self, order: LoadOrder) {
+        self.load_order = order;
+    }
+
+    /// Sets what loading a plugin named like a loaded one does, `Duplicates::Skip` by default.
+    pub fn duplicates(&mut self, duplicates: Duplicates) {
+        self.duplicates = duplicates;
+    }
+
+    /// What loading another plugin called `name` comes down to, if one is already loaded.
+    fn duplicate(&self, name: &str) -> Option<PluginResult<Plugin<Source::PluginType>>> {
+        let loaded = self.plugins.lock().unwrap().get(name).map(|loaded| loaded.plugin.clone())?;
+        Some(match self.duplicates {
+            Duplicates::Skip => {
+                eprintln!("{} is already loaded, skipping the duplicate", name);
+                Ok(loaded)
+            }
+            Duplicates::Reject => Err(PluginError::InvalidPlugin(format!("{} is already loaded", name))),
+        })
+    }
+
+    /// Names of the source's plugins that pass `include`, in load order.
+    fn discover<F: FnMut(&str) -> bool>(&self, mut include: F) -> Vec<String> {
+        let mut names: Vec<_> = self.source.plugins_iter().filter(|name| include(name)).collect();
+        match self.load_order {
+            LoadOrder::Sorted => names.sort(),
+            LoadOrder::Priority(ref priority) => {
+                names.sort();
+                names.sort_by_key(|name| priority(name));
+            }
+            LoadOrder::Source => {}
+        }
+        names
+    }
+
+    fn open(&mut self, name: &str) -> PluginResult<Source::PluginType> {
+        if !self.catch_open_panics {
+            return self.source.open(name);
+        }
         let source = &mut self.source;
-        let runtime = self.runtime.as_mut().unwrap();
-        source.plugins().iter().filter_map(|item|{
-            if excludes.contains(item) {return None;}
-            let plugin = source.open(item);
-            if let Err(ref e) = plugin{
-                eprintln!("could not load {}: {}", item, e.to_string());
-                return None;
-            }
-            plugin.ok()
-        }).filter_map(|plugin|{
-            let plugin_name = plugin.name();
-            runtime.load_plugin(plugin).or_else(|e|{
-                eprintln!("failed to load {}: {}", plugin_name, e.to_string());
-                Err(e)
-            }).ok()
+        catch_unwind(AssertUnwindSafe(|| source.open(name))).unwrap_or_else(|panic| {
+            Err(PluginError::InvalidPlugin(format!("open panicked: {}", panic_reason(panic))))
+        })
+    }
+
+    pub fn execute(&self, name: &str, call: <Source::PluginType as PluginData>::PluginCall) -> PluginResult<PluginCallOutcome<Source::PluginType>> {
+        self.callable(name)?.execute(call)
+    }
+
+    /// Like `execute`, loading the plugin first if it isn't yet, so plugins that are never called
+    /// are never opened. `name` is the one the source lists the plugin under, as for
+    /// `load_one`. Taking `&mut self` makes the first calls to a plugin take turns: behind a
+    /// shared lock, the first one loads it and the others find it loaded.
+    pub fn execute_lazy(&mut self, name: &str, call: <Source::PluginType as PluginData>::PluginCall) -> PluginResult<PluginCallOutcome<Source::PluginType>> {
+        let loaded = self.plugins.lock().unwrap().iter()
+            .find(|(_, loaded)| loaded.source == name)
+            .map(|(plugin, _)| plugin.clone());
+        let plugin = match loaded {
+            Some(plugin) => plugin,
+            None => self.load_one(name)?.name(),
+        };
+        self.execute(&plugin, call)
+    }
+
+    fn callable(&self, name: &str) -> PluginResult<Plugin<Source::PluginType>> {
+        match self.plugins.lock().unwrap().get(name) {
+            Some(loaded) if loaded.paused => Err(PluginError::RuntimeError("paused".to_string())),
+            Some(loaded) => Ok(loaded.plugin.clone()),
+            None => Err(PluginError::NotLoaded(name.to_string())),
+        }
+    }
+
+    pub fn pause(&mut self, name: &str) -> PluginResult<()> {
+        self.set_paused(name, true)
+    }
+
+    pub fn resume(&mut self, name: &str) -> PluginResult<()> {
+        self.set_paused(name, false)
+    }
+
+    pub fn is_paused(&self, name: &str) -> bool {
+        self.plugins.lock().unwrap().get(name).is_some_and(|loaded| loaded.paused)
+    }
+
+    fn set_paused(&mut self, name: &str, paused: bool) -> PluginResult<()> {
+        match self.plugins.lock().unwrap().get_mut(name) {
+            Some(loaded) => {
+                loaded.paused = paused;
+                Ok(())
+            }
+            None => Err(PluginError::NotLoaded(name.to_string())),
+        }
+    }
+
+    pub fn group_of(&self, name: &str) -> Option<String> {
+        self.plugins.lock().unwrap().get(name).map(|loaded| loaded.group.clone())
+    }
+
+    /// Names of the loaded plugins in `group`.
+    pub fn group(&self, group: &str) -> Vec<String> {
+        self.plugins.lock().unwrap().iter()
+            .filter(|(_, loaded)| loaded.group == group)
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    /// Sends `call` to every plugin of `group`, paused ones answer with an error. The plugins
+    /// share the one payload, see `Plugin::execute_arc`.
+    pub fn broadcast_group(&self, group: &str, call: <Source::PluginType as PluginData>::PluginCall) -> Vec<(String, PluginResult<PluginCallOutcome<Source::PluginType>>)> where <Source::PluginType as PluginData>::PluginCall: Sync {
+        let call = Arc::new(call);
+        self.group(group).into_iter().map(|name| {
+            let res = self.callable(&name).and_then(|plugin| plugin.execute_arc(call.clone()));
+            (name, res)
         }).collect()
     }
+
+    /// Sends `call` to one unpaused plugin of `group` picked by `selection`, and returns its name
+    /// along with the outcome. Fails with `PluginError::NotLoaded` if there is none.
+    pub fn dispatch(&self, group: &str, call: <Source::PluginType as PluginData>::PluginCall, selection: Selection) -> PluginResult<(String, PluginCallOutcome<Source::PluginType>)> {
+        let name = self.select(group, selection).ok_or_else(|| PluginError::NotLoaded(group.to_string()))?;
+        let outcome = self.callable(&name)?.execute(call)?;
+        Ok((name, outcome))
+    }
+
+    fn select(&self, group: &str, selection: Selection) -> Option<String> {
+        let metrics = self.runtime.as_ref().unwrap().metrics();
+        let mut candidates: Vec<_> = self.plugins.lock().unwrap().iter()
+            .filter(|(_, loaded)| loaded.group == group && !loaded.paused)
+            .map(|(name, _)| (name.clone(), metrics.get(name).cloned().unwrap_or_default()))
+            .collect();
+        candidates.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+        match selection {
+            Selection::LeastLatency => {
+                let explore = (self.dispatched.fetch_add(1, Ordering::Relaxed) + 1) % EXPLORE_EVERY == 0;
+                candidates.into_iter()
+                    .min_by_key(|(_, metrics)| match metrics.avg_latency {
+                        None => (false, 0, Duration::ZERO),
+                        Some(_) if explore => (true, metrics.calls(), Duration::ZERO),
+                        Some(avg) => (true, 0, avg),
+                    })
+                    .map(|(name, _)| name)
+            }
+        }
+    }
+
+    /// Sends `call` to every plugin in `names` and returns the answer `policy` settles on, e.g.
+    /// `FirstSuccess` or `Majority`. Plugins still working on it are then cancelled. The plugins
+    /// share the one payload and each is waited on up to its default timeout, if it has one.
+    /// Fails with a `RuntimeError` if every plugin answered and `policy` didn't settle.
+    pub fn execute_quorum<Q: QuorumPolicy<Source::PluginType>>(&self, names: &[&str], call: <Source::PluginType as PluginData>::PluginCall, policy: Q) -> PluginResult<PluginCallOutcome<Source::PluginType>> where <Source::PluginType as PluginData>::PluginCall: Sync {
+        let call = Arc::new(call);
+        let (result_sender, result_receiver) = channel();
+        let mut answers = Vec::with_capacity(names.len());
+        let mut pending = Vec::with_capacity(names.len());
+        for name in names {
+            let sent = self.callable(name).and_then(|plugin| {
+                let deadline = plugin.default_timeout().map(|timeout| Instant::now() + timeout);
+                let id = plugin.dispatch(CallPayload::Shared(Shared::new(call.clone())), HashMap::new(), deadline, ResultSender::Tagged(result_sender.clone()), None)?;
+                Ok((plugin, id, deadline))
+            });
+            match sent {
+                Ok(sent) => pending.push(sent),
+                Err(e) => answers.push(Err(e)),
+            }
+        }
+        drop(result_sender);
+        loop {
+            if let Some(index) = policy.settle(&answers, names.len()) {
+                for (plugin, id, _) in pending {
+                    plugin.cancel(id);
+                }
+                return answers.swap_remove(index);
+            }
+            if pending.is_empty() {
+                return Err(PluginError::RuntimeError(format!("no quorum among {} answers", answers.len())));
+            }
+            let received = match pending.iter().filter_map(|(_, _, deadline)| *deadline).min() {
+                Some(deadline) => result_receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())),
+                None => result_receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            match received {
+                Ok((id, res)) => {
+                    if let Some(index) = pending.iter().position(|(_, pending_id, _)| *pending_id == id) {
+                        pending.swap_remove(index);
+                        answers.push(res.map(Into::into));
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    let now = Instant::now();
+                    let (expired, left) = pending.into_iter().partition(|(_, _, deadline)| deadline.is_some_and(|deadline| deadline <= now));
+                    pending = left;
+                    for (plugin, id, _) in expired {
+                        let timeout = plugin.default_timeout().unwrap_or_default();
+                        match plugin.timed_out(id, timeout) {
+                            Some(e) => answers.push(Err(e)),
+                            // Answered just in time, its result is on the way.
+                            None => pending.push((plugin, id, None)),
+                        }
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    for (plugin, _, _) in pending.drain(..) {
+                        answers.push(Err(plugin.failure().unwrap_or(PluginError::Disconnected)));
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn pause_group(&mut self, group: &str) {
+        self.set_group_paused(group, true)
+    }
+
+    pub fn resume_group(&mut self, group: &str) {
+        self.set_group_paused(group, false)
+    }
+
+    fn set_group_paused(&mut self, group: &str, paused: bool) {
+        for loaded in self.plugins.lock().unwrap().values_mut().filter(|loaded| loaded.group == group) {
+            loaded.paused = paused;
+        }
+    }
+
+    /// Unloads every plugin of `group`, returning the first error once all were tried.
+    pub fn unload_group(&mut self, group: &str) -> PluginResult<()> {
+        let mut res = Ok(());
+        for name in self.group(group) {
+            if let Err(e) = self.unload(&name) {
+                res = res.and(Err(e));
+            }
+        }
+        res
+    }
+
+    /// Unloads every plugin, then stops the runtime like `PluginRuntime::shutdown_with`, so
+    /// stateful plugins get to flush before their event loop sees the runtime go away.
+    ///
+    /// A plugin acknowledges by answering its unload call, built by the runtime's
+    /// `plugin_unloader`, within `per_plugin_timeout`; the answer reaches the loader by that
+    /// call's id like any other result, so no id is reserved for it. Plugins are asked one
+    /// after another, by name. One that doesn't answer in time, or fails the call, is dropped
+    /// anyway with a warning and its name is returned. Without a `plugin_unloader` there is
+    /// nothing to acknowledge and plugins are dropped right away. The loader can't load
+    /// plugins afterwards.
+    pub fn shutdown_graceful(&mut self, per_plugin_timeout: Duration) -> Vec<String> {
+        let mut loaded: Vec<_> = self.plugins.lock().unwrap().drain().collect();
+        loaded.sort_by(|(name, _), (other, _)| name.cmp(other));
+        let emptied = !loaded.is_empty();
+        let mut unacknowledged = Vec::new();
+        for (name, loaded) in loaded {
+            self.digests.remove(&loaded.source);
+            loaded.plugin.plugin_data.on_unload();
+            if let Err(e) = self.runtime.as_ref().unwrap().unload_plugin_within(&loaded.plugin, per_plugin_timeout) {
+                eprintln!("{} did not acknowledge the shutdown, dropping it: {}", name, e);
+                unacknowledged.push(name.clone());
+            }
+            self.emit(LifecycleEvent::Unloaded(loaded.source));
+        }
+        if emptied {
+            notify(&self.idle_listeners);
+        }
+        self.runtime.as_mut().unwrap().shutdown_with(ShutdownReason::Planned);
+        unacknowledged
+    }
+
+    pub fn available(&self) -> Vec<PluginDescriptor> {
+        self.source.describe()
+    }
+
+    /// Whether the source's digest for `name` differs from the one it was loaded at, or either
+    /// is unknown. `reload_all` leaves the plugins this is false for alone.
+    pub fn needs_reload(&self, name: &str) -> bool {
+        match (self.source.digest(name), self.digests.get(name)) {
+            (Some(current), Some(cached)) => &current != cached,
+            _ => true,
+        }
+    }
+
+    pub fn on_event<F: 'static + Send + Fn(LifecycleEvent)>(&mut self, callback: F) {
+        self.listeners.lock().unwrap().push(Box::new(callback));
+    }
+
+    /// Runs `callback` whenever an unload or a failure leaves the loader without plugins, e.g.
+    /// to stop the runtime loops once the last one is gone. It doesn't run for a loader that
+    /// never had a plugin, nor while `reload_all` swaps instances.
+    pub fn on_all_unloaded<F: 'static + Send + Fn()>(&mut self, callback: F) {
+        self.idle_listeners.lock().unwrap().push(Box::new(callback));
+    }
+
+    fn emit(&self, event: LifecycleEvent) {
+        emit(&self.listeners, event);
+    }
+
+    /// Opens and loads the plugin `name`, unless a plugin by that name is already loaded, see
+    /// `Duplicates`. A plugin that turns out to be named like a loaded one once opened is never
+    /// sent its load call.
+    pub fn load_one(&mut self, name: &str) -> PluginResult<Plugin<Source::PluginType>> {
+        self.load_new(name).map(|(plugin, _)| plugin)
+    }
+
+    /// `load_one`, also telling whether this call loaded the plugin rather than finding it
+    /// already loaded, so that rollbacks leave alone what they didn't load.
+    fn load_new(&mut self, name: &str) -> PluginResult<(Plugin<Source::PluginType>, bool)> {
+        if let Some(loaded) = self.duplicate(name) {
+            return loaded.map(|plugin| (plugin, false));
+        }
+        self.emit(LifecycleEvent::LoadStarted(name.to_string()));
+        let opened = self.open_logged(name);
+        let loaded = opened.as_ref().ok().and_then(|plugin| self.duplicate(&plugin.name_ref()));
+        let plugin = match loaded {
+            Some(Ok(loaded)) => return Ok((loaded, false)),
+            Some(Err(e)) => Err(e),
+            None => opened.and_then(|plugin| self.load_opened(plugin)),
+        };
+        let plugin = match plugin {
+            Ok(plugin) => plugin,
+            Err(e) => {
+                self.emit(LifecycleEvent::LoadFailed(name.to_string(), e.clone()));
+                return Err(e);
+            }
+        };
+        let plugin = self.register(name, plugin, false);
+        self.emit(LifecycleEvent::Loaded(name.to_string()));
+        Ok((plugin, true))
+    }
+
+    /// Unloads the plugins a failed batch load loaded itself, newest first.
+    fn roll_back(&mut self, fresh: Vec<String>) {
+        for name in fresh.iter().rev() {
+            if let Err(unload_error) = self.unload(name) {
+                eprintln!("failed to unload {}: {}", name, unload_error);
+            }
+        }
+    }
+
+    /// Reloads every loaded plugin whose source changed since it was loaded, see
+    /// `needs_reload`, all at once: fresh instances are opened and loaded next to the current
+    /// ones, and only once all of them loaded do they replace the current ones, keeping their
+    /// paused state. Until then calls keep going to the old instances; afterwards calls already
+    /// sent to them still complete, and each gets its unload call. If any instance fails to
+    /// load, the fresh ones loaded so far are unloaded and nothing changes.
+    pub fn reload_all(&mut self) -> PluginResult<()> {
+        let current: Vec<(String, String, bool)> = self.plugins.lock().unwrap().iter()
+            .map(|(name, loaded)| (name.clone(), loaded.source.clone(), loaded.paused))
+            .collect();
+        let mut fresh = Vec::with_capacity(current.len());
+        for (name, source, paused) in current {
+            if !self.needs_reload(&source) {
+                continue;
+            }
+            match self.instantiate(&source) {
+                Ok(plugin) => fresh.push((name, source, paused, plugin)),
+                Err(e) => {
+                    for (_, source, _, plugin) in fresh {
+                        if let Err(unload_error) = self.runtime.as_ref().unwrap().unload_plugin(&plugin) {
+                            eprintln!("failed to unload fresh {}: {}", source, unload_error);
+                        }
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        for (name, source, paused, plugin) in fresh {
+            let removed = self.plugins.lock().unwrap().remove(&name);
+            if let Some(old) = removed {
+                old.plugin.plugin_data.on_unload();
+                if let Err(e) = self.runtime.as_ref().unwrap().unload_plugin(&old.plugin) {
+                    eprintln!("failed to unload {}: {}", source, e);
+                }
+                self.emit(LifecycleEvent::Unloaded(source.clone()));
+            }
+            self.register(&source, plugin, paused);
+            self.emit(LifecycleEvent::Loaded(source));
+        }
+        Ok(())
+    }
+
+    fn instantiate(&mut self, name: &str) -> PluginResult<Plugin<Source::PluginType>> {
+        self.open_logged(name).and_then(|plugin| self.load_opened(plugin))
+    }
+
+    fn open_logged(&mut self, name: &str) -> PluginResult<Source::PluginType> {
+        self.open(name).map_err(|e| {
+            eprintln!("could not load {}: {}", name, e);
+            e
+        })
+    }
+
+    fn load_opened(&self, plugin: Source::PluginType) -> PluginResult<Plugin<Source::PluginType>> {
+        let plugin_name = plugin.name_ref().into_owned();
+        self.runtime.as_ref().unwrap().load_plugin(plugin).map_err(|e| {
+            eprintln!("failed to load {}: {}", plugin_name, e);
+            e
+        })
+    }
+
+    fn register(&mut self, name: &str, plugin: Plugin<Source::PluginType>, paused: bool) -> Plugin<Source::PluginType> {
+        match self.source.digest(name) {
+            Some(digest) => self.digests.insert(name.to_string(), digest),
+            None => self.digests.remove(name),
+        };
+        let descriptor = self.source.descriptor(name).unwrap_or_default();
+        let plugin = plugin.with_default_timeout(descriptor.timeout);
+        let group = descriptor.group.unwrap_or_else(|| DEFAULT_GROUP.to_string());
+        plugin.plugin_data.on_load();
+        self.plugins.lock().unwrap().insert(plugin.name(), LoadedPlugin {
+            plugin: plugin.clone(),
+            source: name.to_string(),
+            paused,
+            group,
+        });
+        plugin
+    }
+
+    pub fn load_scoped(&mut self, name: &str) -> PluginResult<ScopedPlugin<'_, Source>> {
+        let plugin = self.load_one(name)?;
+        Ok(ScopedPlugin {
+            loader: self,
+            plugin,
+        })
+    }
+
+    pub fn load_plugins(&mut self, excludes: Vec<String>) -> Vec<Plugin<Source::PluginType>> {
+        self.load_matching(|name| !excludes.iter().any(|exclude| exclude == name))
+    }
+
+    /// Loads the plugins whose name passes `include` in `LoadOrder`, skipping those that fail to
+    /// load. Names are filtered as `PluginSource::plugins_iter` yields them, so only the
+    /// matching ones are kept around while loading.
+    /// Each plugin is returned once, even if it was listed several times.
+    pub fn load_matching<F: FnMut(&str) -> bool>(&mut self, include: F) -> Vec<Plugin<Source::PluginType>> {
+        let names = self.discover(include);
+        let mut loaded: Vec<Plugin<Source::PluginType>> = Vec::new();
+        for plugin in names.iter().filter_map(|name| self.load_one(name).ok()) {
+            if !loaded.iter().any(|other| other.name_ref() == plugin.name_ref()) {
+                loaded.push(plugin);
+            }
+        }
+        loaded
+    }
+
+    /// Dry run of `load_plugins`: opens each plugin and runs its `PluginData::validate`, but
+    /// sends no load call and keeps nothing loaded, so a host can check its plugins before
+    /// deploying them. Returns every plugin's outcome in `LoadOrder`.
+    pub fn validate(&mut self, excludes: Vec<String>) -> Vec<(String, PluginResult<()>)> {
+        let names = self.discover(|name| !excludes.iter().any(|exclude| exclude == name));
+        names.into_iter()
+            .map(|name| {
+                let outcome = self.open(&name).and_then(|plugin| plugin.validate());
+                (name, outcome)
+            })
+            .collect()
+    }
+
+    /// Loads exactly the plugins in `names`, in that order, into an array. If one fails to
+    /// load, the plugins loaded so far are unloaded again, like in `load_plugins_strict`. Only
+    /// the returned set is fixed-size: loading still allocates and the runtime tracks calls in
+    /// its usual maps, which the runtime's `subscriber_capacity` presizes.
+    pub fn load_fixed<const N: usize>(&mut self, names: [&str; N]) -> PluginResult<[Plugin<Source::PluginType>; N]> {
+        let mut loaded: [Option<Plugin<Source::PluginType>>; N] = std::array::from_fn(|_| None);
+        let mut fresh = [false; N];
+        for (i, name) in names.iter().enumerate() {
+            match self.load_new(name) {
+                Ok((plugin, is_fresh)) => {
+                    fresh[i] = is_fresh;
+                    loaded[i] = Some(plugin);
+                }
+                Err(e) => {
+                    let fresh = loaded.iter().zip(fresh.iter())
+                        .filter_map(|(plugin, is_fresh)| plugin.as_ref().filter(|_| *is_fresh).map(Plugin::name))
+                        .collect();
+                    self.roll_back(fresh);
+                    return Err(e);
+                }
+            }
+        }
+        Ok(loaded.map(Option::unwrap))
+    }
+
+    /// Loads the plugin named for each key and keys them by it, rolling back like
+    /// `load_plugins_strict` if any fails to load.
+    pub fn load_registry<K, I, N>(&mut self, names: I) -> PluginResult<PluginRegistry<K, Source::PluginType>>
+        where K: Eq + Hash, I: IntoIterator<Item=(K, N)>, N: AsRef<str> {
+        let mut loaded = HashMap::new();
+        let mut fresh = Vec::new();
+        for (key, name) in names {
+            match self.load_new(name.as_ref()) {
+                Ok((plugin, is_fresh)) => {
+                    if is_fresh {
+                        fresh.push(plugin.name());
+                    }
+                    loaded.insert(key, plugin);
+                }
+                Err(e) => {
+                    self.roll_back(fresh);
+                    return Err(e);
+                }
+            }
+        }
+        Ok(PluginRegistry::new(loaded))
+    }
+
+    /// Like `load_registry`, with each key parsed from the plugin's name. Every name is checked
+    /// before anything is loaded, so an unknown one fails the whole call with
+    /// `PluginError::InvalidPlugin` rather than leaving that plugin out.
+    pub fn load_registry_from<K, I, N>(&mut self, names: I) -> PluginResult<PluginRegistry<K, Source::PluginType>>
+        where K: Eq + Hash + FromPluginName, I: IntoIterator<Item=N>, N: AsRef<str> {
+        let keyed = names.into_iter()
+            .map(|name| match K::from_name(name.as_ref()) {
+                Some(key) => Ok((key, name)),
+                None => Err(PluginError::InvalidPlugin(format!("unknown plugin name {}", name.as_ref()))),
+            })
+            .collect::<PluginResult<Vec<_>>>()?;
+        self.load_registry(keyed)
+    }
+
+    pub fn load_plugins_strict(&mut self, excludes: Vec<String>) -> PluginResult<Vec<Plugin<Source::PluginType>>> {
+        let mut loaded: Vec<Plugin<Source::PluginType>> = Vec::new();
+        let mut fresh = Vec::new();
+        let names = self.discover(|name| !excludes.iter().any(|exclude| exclude == name));
+        for item in names.iter() {
+            match self.load_new(item) {
+                Ok((plugin, _)) if loaded.iter().any(|other| other.name_ref() == plugin.name_ref()) => {}
+                Ok((plugin, is_fresh)) => {
+                    if is_fresh {
+                        fresh.push(plugin.name());
+                    }
+                    loaded.push(plugin);
+                }
+                Err(e) => {
+                    self.roll_back(fresh);
+                    return Err(e);
+                }
+            }
+        }
+        Ok(loaded)
+    }
+
+    pub fn unload(&mut self, name: &str) -> PluginResult<()> {
+        let removed = self.plugins.lock().unwrap().remove(name);
+        match removed {
+            Some(loaded) => {
+                self.digests.remove(&loaded.source);
+                loaded.plugin.plugin_data.on_unload();
+                let res = self.runtime.as_ref().unwrap().unload_plugin(&loaded.plugin);
+                self.emit(LifecycleEvent::Unloaded(loaded.source));
+                if self.plugins.lock().unwrap().is_empty() {
+                    notify(&self.idle_listeners);
+                }
+                res
+            }
+            None => Err(PluginError::NotLoaded(name.to_string())),
+        }
+    }
+}
+
+/// A plugin loaded for the lifetime of this guard, unloaded again when it is dropped.
+///
+/// If the runtime is already gone by then, the failed unload call is logged and the plugin is
+/// still removed from the loader.
+pub struct ScopedPlugin<'a, Source: 'static + PluginSource> {
+    loader: &'a mut PluginLoader<Source>,
+    plugin: Plugin<Source::PluginType>,
+}
+
+impl<'a, Source: 'static + PluginSource> Deref for ScopedPlugin<'a, Source> {
+    type Target = Plugin<Source::PluginType>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.plugin
+    }
+}
+
+impl<'a, Source: 'static + PluginSource> Drop for ScopedPlugin<'a, Source> {
+    fn drop(&mut self) {
+        let name = self.plugin.name();
+        if let Err(e) = self.loader.unload(&name) {
+            eprintln!("failed to unload {}: {}", name, e);
+        }
+    }
+}
+
+fn run_to_outcome<P, F, E>(handle: Handle<P>, event_loop: F) -> PluginResult<()>
+    where P: PluginData, F: FnOnce(Handle<P>) -> Result<(), E>, E: ToString {
+    let aborter = handle.clone();
+    let outcome = event_loop(handle).map_err(|e| PluginError::RuntimeError(e.to_string()));
+    if let Err(ref e) = outcome {
+        aborter.abort_pending(e.clone());
+    }
+    outcome
+}
+
+fn emit(listeners: &Listeners, event: LifecycleEvent) {
+    for listener in listeners.lock().unwrap().iter() {
+        listener(event.clone());
+    }
+}
+
+fn notify(idle_listeners: &IdleListeners) {
+    for listener in idle_listeners.lock().unwrap().iter() {
+        listener();
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use crate::loader::PluginLoader;
-    use crate::test_utils::{DummySource, build_dummy_runtime, dummy_event_loop};
+    use crate::test_utils::{DummySource, DuplicateSource, ExhaustibleSource, FlakySource, HookSource, KindPlugin, KindSource, PanickySource, ReplicaSource, VersionedSource, hook_event_loop, lagging_kind_event_loop, build_dummy_runtime, dummy_event_loop, failing_event_loop, kind_event_loop};
+    use crate::loader::{Duplicates, FirstSuccess, LoadOrder, Majority, Selection, DEFAULT_GROUP, EXPLORE_EVERY};
+    use crate::runtime::{PluginRuntime, RuntimeOptions};
+    use std::sync::atomic::{AtomicUsize, Ordering};
     use crate::tokio_utils::create_tokio_runtime;
+    use crate::{PluginError, PluginErrorKind};
+    use crate::lifecycle::LifecycleEvent;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
 
     #[test]
     fn load_plugin() {
@@ -85,4 +859,740 @@ mod tests {
             tokio::join!(handle1, handle2)
         });
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn load_matching() {
+        let mut kind_runtime = PluginRuntime::new();
+        let (fut1, handle) = kind_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let handle2 = runtime.spawn(async move {
+            kind_event_loop(handle)
+        });
+        let mut kind_loader = PluginLoader::new(KindSource{}, kind_runtime);
+        let names: Vec<_> = kind_loader.load_matching(|name| name.starts_with('g')).iter().map(|plugin| plugin.name()).collect();
+        assert_eq!(names, vec!["greeter".to_string()]);
+        assert_eq!(kind_loader.group_of("counter"), None);
+        drop(kind_loader);
+        let (_res1, _res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+    }
+
+    #[test]
+    fn needs_reload() {
+        let mut dummy_runtime = build_dummy_runtime();
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let handle2 = runtime.spawn(async move {
+            dummy_event_loop(handle)
+        });
+        let mut dummy_loader = PluginLoader::new(DummySource{}, dummy_runtime);
+        let available = dummy_loader.available();
+        assert_eq!(available.len(), 1);
+        assert_eq!(available[0].name, "test");
+        assert_eq!(available[0].version.as_deref(), Some("v1"));
+        assert!(dummy_loader.needs_reload("test"));
+        let plugins = dummy_loader.load_plugins(vec![]);
+        assert!(!dummy_loader.needs_reload("test"));
+        drop(plugins);
+        drop(dummy_loader);
+        let (_res1, _res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+    }
+
+    #[test]
+    fn execute_by_name() {
+        let mut dummy_runtime = build_dummy_runtime();
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let handle2 = runtime.spawn(async move {
+            dummy_event_loop(handle)
+        });
+        let mut dummy_loader = PluginLoader::new(DummySource{}, dummy_runtime);
+        let plugins = dummy_loader.load_plugins(vec![]);
+        assert_eq!(dummy_loader.execute("test", ()).unwrap(), Ok("hello".to_string()));
+        assert_eq!(dummy_loader.execute("missing", ()), Err(PluginError::NotLoaded("missing".to_string())));
+        drop(plugins);
+        drop(dummy_loader);
+        let (_res1, _res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+    }
+
+    #[test]
+    fn lifecycle_events() {
+        let mut dummy_runtime = build_dummy_runtime();
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let handle2 = runtime.spawn(async move {
+            dummy_event_loop(handle)
+        });
+        let mut dummy_loader = PluginLoader::new(DummySource{}, dummy_runtime);
+        let events = Arc::new(Mutex::new(vec![]));
+        let recorded = events.clone();
+        dummy_loader.on_event(move |event| recorded.lock().unwrap().push(event));
+        let plugins = dummy_loader.load_plugins(vec![]);
+        dummy_loader.unload("test").unwrap();
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 3);
+        assert!(matches!(&events[0], LifecycleEvent::LoadStarted(name) if name == "test"));
+        assert!(matches!(&events[1], LifecycleEvent::Loaded(name) if name == "test"));
+        assert!(matches!(&events[2], LifecycleEvent::Unloaded(name) if name == "test"));
+        drop(events);
+        drop(plugins);
+        drop(dummy_loader);
+        let (_res1, _res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+    }
+
+    #[test]
+    fn event_loop_outcome() {
+        let runtime = create_tokio_runtime();
+        let mut dummy_loader = PluginLoader::new(DummySource{}, build_dummy_runtime());
+        let (fut1, fut2) = dummy_loader.start_with(|handle| {
+            let load = handle.receive().map_err(|e| e.to_string())?;
+            handle.resolve(load.id(), "loaded".to_string());
+            handle.receive().map_err(|e| e.to_string())?;
+            Err("crashed".to_string())
+        });
+        let handle1 = runtime.spawn(fut1);
+        let handle2 = runtime.spawn(fut2);
+        dummy_loader.load_one("test").unwrap();
+        assert_eq!(dummy_loader.execute("test", ()), Err(PluginError::RuntimeError("crashed".to_string())));
+        drop(dummy_loader);
+        let (_res1, res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+        assert_eq!(res2.unwrap(), Err(PluginError::RuntimeError("crashed".to_string())));
+    }
+
+    #[test]
+    fn failed_plugin_is_unloaded() {
+        let mut dummy_runtime = build_dummy_runtime();
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let handle2 = runtime.spawn(async move {
+            failing_event_loop(handle)
+        });
+        let mut dummy_loader = PluginLoader::new(DummySource{}, dummy_runtime);
+        let (event_sender, event_receiver) = std::sync::mpsc::channel();
+        dummy_loader.on_event(move |event| {
+            if let LifecycleEvent::Unloaded(name) = event {
+                event_sender.send(name).unwrap();
+            }
+        });
+        let plugins = dummy_loader.load_plugins(vec![]);
+        let plugin = plugins.first().unwrap();
+        assert!(matches!(plugin.execute_pooled(()), Err(PluginError::RuntimeError(_))));
+        assert_eq!(event_receiver.recv().unwrap(), "test");
+        assert!(matches!(plugin.execute(()), Err(PluginError::RuntimeError(_))));
+        assert_eq!(dummy_loader.execute("test", ()), Err(PluginError::NotLoaded("test".to_string())));
+        drop(plugins);
+        drop(dummy_loader);
+        let (_res1, _res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+    }
+
+    #[test]
+    fn load_fixed() {
+        let mut dummy_runtime = build_dummy_runtime();
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let handle2 = runtime.spawn(async move {
+            dummy_event_loop(handle)
+        });
+        let mut dummy_loader = PluginLoader::new(DummySource{}, dummy_runtime);
+        let [plugin] = dummy_loader.load_fixed(["test"]).unwrap();
+        assert_eq!(plugin.execute(()).unwrap(), Ok("hello".to_string()));
+        drop(plugin);
+        drop(dummy_loader);
+        let (_res1, _res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+    }
+
+    #[test]
+    fn load_fixed_rolls_back() {
+        let unloaded = Arc::new(AtomicUsize::new(0));
+        let counter = unloaded.clone();
+        let mut dummy_runtime = PluginRuntime::with_options(RuntimeOptions {
+            plugin_loader: Some(Box::new(|_plugin| ())),
+            plugin_unloader: Some(Box::new(move |_plugin| {
+                counter.fetch_add(1, Ordering::SeqCst);
+            })),
+            ..Default::default()
+        });
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let handle2 = runtime.spawn(async move {
+            dummy_event_loop(handle)
+        });
+        let mut dummy_loader = PluginLoader::new(FlakySource{}, dummy_runtime);
+        assert_eq!(dummy_loader.load_fixed(["test", "broken"]).err(), Some(PluginError::InvalidPlugin("broken".to_string())));
+        assert_eq!(unloaded.load(Ordering::SeqCst), 1);
+        assert_eq!(dummy_loader.execute("test", ()), Err(PluginError::NotLoaded("test".to_string())));
+        drop(dummy_loader);
+        let (_res1, _res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+    }
+
+    #[test]
+    fn pause_and_resume() {
+        let mut dummy_runtime = build_dummy_runtime();
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let handle2 = runtime.spawn(async move {
+            dummy_event_loop(handle)
+        });
+        let mut dummy_loader = PluginLoader::new(DummySource{}, dummy_runtime);
+        let plugins = dummy_loader.load_plugins(vec![]);
+        dummy_loader.pause("test").unwrap();
+        assert!(dummy_loader.is_paused("test"));
+        assert_eq!(dummy_loader.execute("test", ()), Err(PluginError::RuntimeError("paused".to_string())));
+        dummy_loader.resume("test").unwrap();
+        assert_eq!(dummy_loader.execute("test", ()).unwrap(), Ok("hello".to_string()));
+        drop(plugins);
+        drop(dummy_loader);
+        let (_res1, _res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+    }
+
+    #[test]
+    fn strict_load_rolls_back() {
+        let unloaded = Arc::new(AtomicUsize::new(0));
+        let counter = unloaded.clone();
+        let mut dummy_runtime = PluginRuntime::with_options(RuntimeOptions {
+            plugin_loader: Some(Box::new(|_plugin| ())),
+            plugin_unloader: Some(Box::new(move |_plugin| {
+                counter.fetch_add(1, Ordering::SeqCst);
+            })),
+            ..Default::default()
+        });
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let handle2 = runtime.spawn(async move {
+            dummy_event_loop(handle)
+        });
+        let mut dummy_loader = PluginLoader::new(FlakySource{}, dummy_runtime);
+        dummy_loader.load_order(LoadOrder::Source);
+        assert!(matches!(dummy_loader.load_plugins_strict(vec![]), Err(PluginError::InvalidPlugin(_))));
+        assert_eq!(unloaded.load(Ordering::SeqCst), 1);
+        assert_eq!(dummy_loader.execute("test", ()), Err(PluginError::NotLoaded("test".to_string())));
+        assert_eq!(dummy_loader.load_plugins_strict(vec!["broken".to_string()]).unwrap().len(), 1);
+        drop(dummy_loader);
+        let (_res1, _res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+    }
+
+    #[test]
+    fn rollbacks_keep_preloaded_plugins() {
+        let unloaded = Arc::new(AtomicUsize::new(0));
+        let counter = unloaded.clone();
+        let mut dummy_runtime = PluginRuntime::with_options(RuntimeOptions {
+            plugin_loader: Some(Box::new(|_plugin| ())),
+            plugin_unloader: Some(Box::new(move |_plugin| {
+                counter.fetch_add(1, Ordering::SeqCst);
+            })),
+            ..Default::default()
+        });
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let handle2 = runtime.spawn(async move {
+            dummy_event_loop(handle)
+        });
+        let mut dummy_loader = PluginLoader::new(FlakySource{}, dummy_runtime);
+        dummy_loader.load_order(LoadOrder::Source);
+        let plugin = dummy_loader.load_one("test").unwrap();
+        assert!(matches!(dummy_loader.load_plugins_strict(vec![]), Err(PluginError::InvalidPlugin(_))));
+        assert!(dummy_loader.load_fixed(["test", "broken"]).is_err());
+        assert!(dummy_loader.load_registry(vec![(1, "test"), (2, "broken")]).is_err());
+        assert_eq!(unloaded.load(Ordering::SeqCst), 0);
+        assert_eq!(dummy_loader.execute("test", ()), Ok(Ok("hello".to_string())));
+        drop(plugin);
+        drop(dummy_loader);
+        let (_res1, _res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+    }
+
+    #[test]
+    fn skip_or_reject_duplicates() {
+        let mut dummy_runtime = build_dummy_runtime();
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let handle2 = runtime.spawn(async move {
+            dummy_event_loop(handle)
+        });
+        let mut dummy_loader = PluginLoader::new(DuplicateSource {}, dummy_runtime);
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let recorded = events.clone();
+        dummy_loader.on_event(move |event| recorded.lock().unwrap().push(event));
+        let plugins = dummy_loader.load_plugins(vec![]);
+        assert_eq!(plugins.len(), 1);
+        let loaded = events.lock().unwrap();
+        assert_eq!(loaded.len(), 2);
+        assert!(matches!(&loaded[0], LifecycleEvent::LoadStarted(name) if name == "alias"));
+        assert!(matches!(&loaded[1], LifecycleEvent::Loaded(name) if name == "alias"));
+        drop(loaded);
+        dummy_loader.duplicates(Duplicates::Reject);
+        assert_eq!(dummy_loader.load_one("test").err(), Some(PluginError::InvalidPlugin("test is already loaded".to_string())));
+        assert!(dummy_loader.load_plugins_strict(vec![]).is_err());
+        assert_eq!(dummy_loader.execute("test", ()), Ok(Ok("hello".to_string())));
+        drop(plugins);
+        drop(dummy_loader);
+        let (_res1, _res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+    }
+
+    #[test]
+    fn shutdown_gracefully() {
+        let mut kind_runtime = PluginRuntime::with_options(RuntimeOptions {
+            plugin_unloader: Some(Box::new(|_plugin| ())),
+            ..Default::default()
+        });
+        let (fut1, handle) = kind_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let handle2 = runtime.spawn(async move {
+            let mut acknowledged = Vec::new();
+            while let Ok(call) = handle.receive() {
+                if let KindPlugin::Greeter = call.plugin() {
+                    acknowledged.push(call.plugin_name().into_owned());
+                    handle.resolve(call.id(), "bye");
+                }
+            }
+            acknowledged
+        });
+        let mut kind_loader = PluginLoader::new(KindSource {}, kind_runtime);
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let recorded = events.clone();
+        kind_loader.on_event(move |event| recorded.lock().unwrap().push(event));
+        assert_eq!(kind_loader.load_plugins(vec![]).len(), 2);
+        assert_eq!(kind_loader.shutdown_graceful(Duration::from_millis(50)), vec!["counter".to_string()]);
+        assert!(kind_loader.group(DEFAULT_GROUP).is_empty());
+        assert_eq!(events.lock().unwrap().iter().filter(|event| matches!(event, LifecycleEvent::Unloaded(_))).count(), 2);
+        assert!(kind_loader.load_one("greeter").is_err());
+        drop(kind_loader);
+        let (_res1, res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+        assert_eq!(res2.unwrap(), vec!["greeter".to_string()]);
+    }
+
+    #[test]
+    fn with_runtime_builder() {
+        let mut dummy_loader = PluginLoader::with_runtime_builder(DummySource{}, |options| RuntimeOptions {
+            plugin_loader: Some(Box::new(|_plugin| ())),
+            ..options
+        }.build());
+        let (fut1, handle) = dummy_loader.start();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let handle2 = runtime.spawn(async move {
+            dummy_event_loop(handle)
+        });
+        dummy_loader.load_one("test").unwrap();
+        assert_eq!(dummy_loader.execute("test", ()).unwrap(), Ok("hello".to_string()));
+        drop(dummy_loader);
+        let (_res1, _res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+    }
+
+    #[test]
+    fn host_side_hooks() {
+        let mut hook_runtime = PluginRuntime::with_options(RuntimeOptions {
+            plugin_loader: Some(Box::new(|_plugin| "load")),
+            plugin_unloader: Some(Box::new(|_plugin| "unload")),
+            ..Default::default()
+        });
+        let (fut1, handle) = hook_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let handle2 = runtime.spawn(async move {
+            hook_event_loop(handle)
+        });
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut hook_loader = PluginLoader::new(HookSource { log: log.clone() }, hook_runtime);
+        hook_loader.load_one("hooked").unwrap();
+        hook_loader.unload("hooked").unwrap();
+        assert_eq!(*log.lock().unwrap(), vec!["load", "on_load", "on_unload", "unload"]);
+        drop(hook_loader);
+        let (_res1, _res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+    }
+
+    #[test]
+    fn validate_without_loading() {
+        let mut flaky_loader = PluginLoader::new(FlakySource{}, build_dummy_runtime());
+        assert_eq!(flaky_loader.validate(vec![]), vec![
+            ("broken".to_string(), Err(PluginError::InvalidPlugin("broken".to_string()))),
+            ("test".to_string(), Ok(())),
+        ]);
+        assert_eq!(flaky_loader.validate(vec!["broken".to_string()]), vec![("test".to_string(), Ok(()))]);
+        assert_eq!(flaky_loader.group_of("test"), None);
+
+        let hook_runtime = PluginRuntime::with_options(RuntimeOptions {
+            plugin_loader: Some(Box::new(|_plugin| "load")),
+            ..Default::default()
+        });
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut hook_loader = PluginLoader::new(HookSource { log: log.clone() }, hook_runtime);
+        assert_eq!(hook_loader.validate(vec![]), vec![("hooked".to_string(), Ok(()))]);
+        assert_eq!(*log.lock().unwrap(), vec!["validate"]);
+    }
+
+    #[test]
+    fn load_order() {
+        let mut kind_loader = PluginLoader::new(KindSource{}, PluginRuntime::new());
+        let order = |loader: &mut PluginLoader<KindSource>| -> Vec<String> {
+            loader.validate(vec![]).into_iter().map(|(name, _)| name).collect()
+        };
+        assert_eq!(order(&mut kind_loader), vec!["counter", "greeter"]);
+        kind_loader.load_order(LoadOrder::Source);
+        assert_eq!(order(&mut kind_loader), vec!["greeter", "counter"]);
+        kind_loader.load_order(LoadOrder::Priority(Box::new(|name| if name == "greeter" { -1 } else { 0 })));
+        assert_eq!(order(&mut kind_loader), vec!["greeter", "counter"]);
+        kind_loader.load_order(LoadOrder::Priority(Box::new(|_name| 0)));
+        assert_eq!(order(&mut kind_loader), vec!["counter", "greeter"]);
+    }
+
+    #[test]
+    fn all_unloaded() {
+        let mut kind_runtime = PluginRuntime::new();
+        let (fut1, handle) = kind_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let handle2 = runtime.spawn(async move {
+            kind_event_loop(handle)
+        });
+        let mut kind_loader = PluginLoader::new(KindSource{}, kind_runtime);
+        let (idle_sender, idle_receiver) = std::sync::mpsc::channel();
+        kind_loader.on_all_unloaded(move || idle_sender.send(()).unwrap());
+        assert_eq!(kind_loader.load_plugins(vec![]).len(), 2);
+        kind_loader.unload("greeter").unwrap();
+        assert!(idle_receiver.try_recv().is_err());
+        kind_loader.unload("counter").unwrap();
+        assert!(idle_receiver.try_recv().is_ok());
+        drop(kind_loader);
+        let (_res1, _res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+    }
+
+    #[test]
+    fn execute_lazy() {
+        let mut dummy_runtime = build_dummy_runtime();
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let handle2 = runtime.spawn(async move {
+            dummy_event_loop(handle)
+        });
+        let dummy_loader = Arc::new(Mutex::new(PluginLoader::new(DummySource{}, dummy_runtime)));
+        let loads = Arc::new(AtomicUsize::new(0));
+        let counted = loads.clone();
+        dummy_loader.lock().unwrap().on_event(move |event| if let LifecycleEvent::Loaded(_) = event {
+            counted.fetch_add(1, Ordering::SeqCst);
+        });
+        assert_eq!(dummy_loader.lock().unwrap().execute("test", ()), Err(PluginError::NotLoaded("test".to_string())));
+        let callers: Vec<_> = (0..4).map(|_| {
+            let dummy_loader = dummy_loader.clone();
+            std::thread::spawn(move || dummy_loader.lock().unwrap().execute_lazy("test", ()))
+        }).collect();
+        for caller in callers {
+            assert_eq!(caller.join().unwrap(), Ok(Ok("hello".to_string())));
+        }
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+        drop(dummy_loader);
+        let (_res1, _res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+    }
+
+    #[test]
+    fn reload_all() {
+        let mut dummy_runtime = build_dummy_runtime();
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let handle2 = runtime.spawn(async move {
+            dummy_event_loop(handle)
+        });
+        let opens = Arc::new(AtomicUsize::new(0));
+        let mut dummy_loader = PluginLoader::new(ExhaustibleSource { opens: opens.clone(), max_opens: 2 }, dummy_runtime);
+        dummy_loader.load_one("test").unwrap();
+        dummy_loader.pause("test").unwrap();
+        assert!(dummy_loader.reload_all().is_ok());
+        assert!(dummy_loader.is_paused("test"));
+        dummy_loader.resume("test").unwrap();
+        assert!(matches!(dummy_loader.reload_all(), Err(PluginError::InvalidPlugin(_))));
+        assert_eq!(opens.load(Ordering::SeqCst), 3);
+        assert_eq!(dummy_loader.execute("test", ()), Ok(Ok("hello".to_string())));
+        drop(dummy_loader);
+        let (_res1, _res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+    }
+
+    #[test]
+    fn reload_changed_sources() {
+        let mut dummy_runtime = build_dummy_runtime();
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let handle2 = runtime.spawn(async move {
+            dummy_event_loop(handle)
+        });
+        let opens = Arc::new(AtomicUsize::new(0));
+        let version = Arc::new(Mutex::new("v1".to_string()));
+        let mut dummy_loader = PluginLoader::new(VersionedSource { opens: opens.clone(), version: version.clone() }, dummy_runtime);
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let recorded = events.clone();
+        dummy_loader.on_event(move |event| recorded.lock().unwrap().push(event));
+        dummy_loader.load_one("alias").unwrap();
+        assert!(dummy_loader.reload_all().is_ok());
+        assert_eq!(opens.load(Ordering::SeqCst), 1);
+        *version.lock().unwrap() = "v2".to_string();
+        assert!(dummy_loader.needs_reload("alias"));
+        assert!(dummy_loader.reload_all().is_ok());
+        assert_eq!(opens.load(Ordering::SeqCst), 2);
+        assert!(!dummy_loader.needs_reload("alias"));
+        let reloaded = events.lock().unwrap();
+        assert_eq!(reloaded.len(), 4);
+        assert!(matches!(&reloaded[2], LifecycleEvent::Unloaded(name) if name == "alias"));
+        assert!(matches!(&reloaded[3], LifecycleEvent::Loaded(name) if name == "alias"));
+        drop(reloaded);
+        assert_eq!(dummy_loader.execute("test", ()), Ok(Ok("hello".to_string())));
+        drop(dummy_loader);
+        let (_res1, _res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+    }
+
+    #[test]
+    fn unloaded_events_carry_source_names() {
+        let mut dummy_runtime = build_dummy_runtime();
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let handle2 = runtime.spawn(async move {
+            dummy_event_loop(handle)
+        });
+        let opens = Arc::new(AtomicUsize::new(0));
+        let version = Arc::new(Mutex::new("v1".to_string()));
+        let mut dummy_loader = PluginLoader::new(VersionedSource { opens, version }, dummy_runtime);
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let recorded = events.clone();
+        dummy_loader.on_event(move |event| if let LifecycleEvent::Unloaded(name) = event {
+            recorded.lock().unwrap().push(name);
+        });
+        dummy_loader.load_one("alias").unwrap();
+        dummy_loader.unload("test").unwrap();
+        dummy_loader.load_one("alias").unwrap();
+        assert!(dummy_loader.shutdown_graceful(Duration::from_secs(1)).is_empty());
+        assert_eq!(*events.lock().unwrap(), vec!["alias".to_string(), "alias".to_string()]);
+        drop(dummy_loader);
+        let (_res1, _res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+    }
+
+    #[test]
+    fn failed_events_carry_source_names() {
+        let mut dummy_runtime = build_dummy_runtime();
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let handle2 = runtime.spawn(async move {
+            failing_event_loop(handle)
+        });
+        let opens = Arc::new(AtomicUsize::new(0));
+        let version = Arc::new(Mutex::new("v1".to_string()));
+        let mut dummy_loader = PluginLoader::new(VersionedSource { opens, version }, dummy_runtime);
+        let (event_sender, event_receiver) = std::sync::mpsc::channel();
+        dummy_loader.on_event(move |event| match event {
+            LifecycleEvent::Failed(name, _) | LifecycleEvent::Unloaded(name) => event_sender.send(name).unwrap(),
+            _ => {}
+        });
+        let plugin = dummy_loader.load_one("alias").unwrap();
+        assert!(matches!(plugin.execute(()), Err(PluginError::RuntimeError(_))));
+        assert_eq!(event_receiver.recv().unwrap(), "alias");
+        assert_eq!(event_receiver.recv().unwrap(), "alias");
+        drop(plugin);
+        drop(dummy_loader);
+        let (_res1, _res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+    }
+
+    #[test]
+    fn execute_lazy_by_source_name() {
+        let mut dummy_runtime = build_dummy_runtime();
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let handle2 = runtime.spawn(async move {
+            dummy_event_loop(handle)
+        });
+        let opens = Arc::new(AtomicUsize::new(0));
+        let version = Arc::new(Mutex::new("v1".to_string()));
+        let mut dummy_loader = PluginLoader::new(VersionedSource { opens: opens.clone(), version }, dummy_runtime);
+        for _ in 0..2 {
+            assert_eq!(dummy_loader.execute_lazy("alias", ()), Ok(Ok("hello".to_string())));
+        }
+        assert_eq!(opens.load(Ordering::SeqCst), 1);
+        assert_eq!(dummy_loader.execute("test", ()), Ok(Ok("hello".to_string())));
+        drop(dummy_loader);
+        let (_res1, _res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+    }
+
+    #[test]
+    fn available_without_loading() {
+        let opens = Arc::new(AtomicUsize::new(0));
+        let version = Arc::new(Mutex::new("v1".to_string()));
+        let dummy_loader = PluginLoader::new(VersionedSource { opens: opens.clone(), version }, build_dummy_runtime());
+        let available = dummy_loader.available();
+        assert_eq!(available.iter().map(|descriptor| descriptor.name.as_str()).collect::<Vec<_>>(), vec!["alias"]);
+        assert_eq!(opens.load(Ordering::SeqCst), 0);
+        assert_eq!(dummy_loader.execute("test", ()), Err(PluginError::NotLoaded("test".to_string())));
+    }
+
+    #[test]
+    fn survive_panicking_open() {
+        let mut dummy_runtime = build_dummy_runtime();
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let handle2 = runtime.spawn(async move {
+            dummy_event_loop(handle)
+        });
+        let mut dummy_loader = PluginLoader::new(PanickySource{}, dummy_runtime);
+        dummy_loader.catch_open_panics(true);
+        let plugins = dummy_loader.load_plugins(vec![]);
+        assert_eq!(plugins.iter().map(|plugin| plugin.name()).collect::<Vec<_>>(), vec!["test".to_string()]);
+        assert!(matches!(dummy_loader.load_one("panicky"), Err(PluginError::InvalidPlugin(reason)) if reason.contains("bad manifest")));
+        drop(plugins);
+        drop(dummy_loader);
+        let (_res1, _res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+    }
+
+    #[test]
+    fn scoped_plugin_unloads_on_drop() {
+        let mut dummy_runtime = build_dummy_runtime();
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let handle2 = runtime.spawn(async move {
+            dummy_event_loop(handle)
+        });
+        let mut dummy_loader = PluginLoader::new(DummySource{}, dummy_runtime);
+        {
+            let scoped = dummy_loader.load_scoped("test").unwrap();
+            assert_eq!(scoped.execute(()).unwrap(), Ok("hello".to_string()));
+        }
+        assert_eq!(dummy_loader.execute("test", ()), Err(PluginError::NotLoaded("test".to_string())));
+        drop(dummy_loader);
+        let (_res1, _res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+    }
+
+    #[test]
+    fn plugin_groups() {
+        let mut kind_runtime = PluginRuntime::new();
+        let (fut1, handle) = kind_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let handle2 = runtime.spawn(async move {
+            kind_event_loop(handle)
+        });
+        let mut kind_loader = PluginLoader::new(KindSource{}, kind_runtime);
+        let timeouts: Vec<_> = kind_loader.load_plugins(vec![]).iter().map(|plugin| plugin.default_timeout()).collect();
+        assert_eq!(timeouts, vec![None, Some(Duration::from_secs(5))]);
+        assert_eq!(kind_loader.group_of("greeter"), Some("greetings".to_string()));
+        assert_eq!(kind_loader.group(DEFAULT_GROUP), vec!["counter".to_string()]);
+        assert_eq!(kind_loader.broadcast_group("greetings", ()), vec![("greeter".to_string(), Ok(Ok("hello".to_string())))]);
+        kind_loader.pause_group("greetings");
+        assert!(kind_loader.is_paused("greeter"));
+        assert!(!kind_loader.is_paused("counter"));
+        kind_loader.resume_group("greetings");
+        assert!(kind_loader.unload_group(DEFAULT_GROUP).is_ok());
+        assert_eq!(kind_loader.group_of("counter"), None);
+        assert_eq!(kind_loader.execute("greeter", ()), Ok(Ok("hello".to_string())));
+        drop(kind_loader);
+        let (_res1, _res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+    }
+
+    #[test]
+    fn least_latency() {
+        let mut kind_runtime = PluginRuntime::new();
+        let (fut1, handle) = kind_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let handle2 = runtime.spawn(async move {
+            lagging_kind_event_loop(handle, Duration::from_millis(20))
+        });
+        let mut kind_loader = PluginLoader::new(ReplicaSource{}, kind_runtime);
+        kind_loader.load_plugins(vec![]);
+        let mut picked: Vec<_> = (0..EXPLORE_EVERY)
+            .map(|_| kind_loader.dispatch("replicas", (), Selection::LeastLatency).unwrap().0)
+            .collect();
+        assert_eq!(picked.drain(..2).collect::<Vec<_>>(), vec!["counter".to_string(), "greeter".to_string()]);
+        assert_eq!(picked.pop(), Some("counter".to_string()));
+        assert!(picked.iter().all(|name| name == "greeter"));
+        kind_loader.pause("greeter").unwrap();
+        assert_eq!(kind_loader.dispatch("replicas", (), Selection::LeastLatency).unwrap().0, "counter".to_string());
+        assert_eq!(kind_loader.dispatch("nobody", (), Selection::LeastLatency).err(), Some(PluginError::NotLoaded("nobody".to_string())));
+        drop(kind_loader);
+        let (_res1, _res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+    }
+
+    #[test]
+    fn execute_quorum() {
+        let mut kind_runtime = PluginRuntime::new();
+        let (fut1, handle) = kind_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let handle2 = runtime.spawn(async move {
+            lagging_kind_event_loop(handle, Duration::from_millis(20))
+        });
+        let mut kind_loader = PluginLoader::new(ReplicaSource{}, kind_runtime);
+        kind_loader.load_plugins(vec![]);
+        assert_eq!(kind_loader.execute_quorum(&["missing", "greeter", "counter"], (), FirstSuccess), Ok(Ok("hello".to_string())));
+        assert_eq!(kind_loader.execute_quorum(&["missing"], (), FirstSuccess), Err(PluginError::NotLoaded("missing".to_string())));
+        assert_eq!(kind_loader.execute_quorum(&["greeter", "counter", "greeter"], (), Majority), Ok(Ok("hello".to_string())));
+        assert_eq!(kind_loader.execute_quorum(&["greeter", "counter"], (), Majority).err().map(|e| e.kind()), Some(PluginErrorKind::RuntimeError));
+        drop(kind_loader);
+        let (_res1, _res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+    }
+}
@@ -0,0 +1,44 @@
+use crate::{PluginData, PluginResult};
+use crate::runtime::{PluginOpCall, PluginOpCallId, RuntimeResult};
+use crate::subscribers::ResultCallback;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Watches the calls of a `Plugin`, see `Plugin::set_observer`.
+///
+/// Both methods run synchronously: `before_send` on the caller's thread, `after_result` on the
+/// result loop's, or on the caller's when it gives up on a timed out call. They only see the
+/// call and its result and can't change either, so keep them short.
+pub trait CallObserver<P: PluginData>: Send + Sync {
+    /// Runs right before `call` is queued for the event loop.
+    fn before_send(&self, _call: &PluginOpCall<P>) {}
+
+    /// Runs once the call with `id` completed, failed or timed out, `latency` after it was sent.
+    /// Streamed items don't count, only the call's final result.
+    fn after_result(&self, _id: &PluginOpCallId, _result: &PluginResult<RuntimeResult<P::PluginCallResult>>, _latency: Duration) {}
+}
+
+/// An observer along with its `after_result` as the callback subscribers carry.
+pub(crate) struct Observer<P: PluginData> {
+    pub(crate) calls: Arc<dyn CallObserver<P>>,
+    pub(crate) results: ResultCallback<P::PluginCallResult>,
+}
+
+impl<P: 'static + PluginData> Observer<P> {
+    pub(crate) fn new(observer: Arc<dyn CallObserver<P>>) -> Self {
+        let calls = observer.clone();
+        Observer {
+            calls,
+            results: Arc::new(move |id, result, latency| observer.after_result(&id, result, latency)),
+        }
+    }
+}
+
+impl<P: PluginData> Clone for Observer<P> {
+    fn clone(&self) -> Self {
+        Observer {
+            calls: self.calls.clone(),
+            results: self.results.clone(),
+        }
+    }
+}
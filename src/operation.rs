@@ -0,0 +1,70 @@
+use crate::{PluginCallResult, PluginData};
+
+type ResultOk<P> = <<P as PluginData>::PluginCallResult as PluginCallResult>::Ok;
+
+/// One kind of call a plugin understands, with the result it answers it with, for hosts that
+/// want the compiler to pair each call with its result rather than matching on the plugin's
+/// single `Ok` type, see `Plugin::execute_op` and `Handle::resolve_op`.
+///
+/// Operations are layered over the plugin's own types: the input is wrapped into a
+/// `PluginCall`, typically as one variant of the call enum, and the output travels as the
+/// result's `Ok`, typically one variant of the result enum. Errors stay the plugin's `Err`.
+pub trait Operation<P: PluginData> {
+    type Input;
+    type Output;
+
+    fn into_call(input: Self::Input) -> P::PluginCall;
+
+    fn into_result(output: Self::Output) -> ResultOk<P>;
+
+    /// `None` when the event loop answered with another operation's output.
+    fn from_result(result: ResultOk<P>) -> Option<Self::Output>;
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use crate::tokio_utils::create_tokio_runtime;
+    use crate::test_utils::DummyPlugin;
+    use crate::operation::Operation;
+    use crate::runtime::PluginRuntime;
+    use crate::PluginErrorKind;
+
+    struct Count;
+
+    impl Operation<DummyPlugin> for Count {
+        type Input = ();
+        type Output = usize;
+
+        fn into_call(_input: ()) {}
+
+        fn into_result(output: usize) -> String {
+            output.to_string()
+        }
+
+        fn from_result(result: String) -> Option<usize> {
+            result.parse().ok()
+        }
+    }
+
+    #[test]
+    fn execute_typed_operation() {
+        let mut dummy_runtime = PluginRuntime::new();
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let plugin = dummy_runtime.load_plugin(DummyPlugin {}).unwrap();
+        let caller = plugin.clone();
+        let calls = std::thread::spawn(move || (caller.execute_op::<Count>(()), caller.execute_op::<Count>(()), caller.execute_op::<Count>(())));
+        handle.resolve_op::<Count>(handle.receive().unwrap().id(), 3);
+        handle.resolve(handle.receive().unwrap().id(), "three");
+        handle.reject(handle.receive().unwrap().id(), "no count");
+        let (counted, mismatched, rejected) = calls.join().unwrap();
+        assert_eq!(counted, Ok(Ok(3)));
+        assert_eq!(mismatched.err().map(|e| e.kind()), Some(PluginErrorKind::InvalidPlugin));
+        assert_eq!(rejected, Ok(Err("no count".to_string())));
+        drop(plugin);
+        drop(dummy_runtime);
+        drop(handle);
+        let _res1 = runtime.block_on(handle1);
+    }
+}
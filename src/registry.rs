@@ -0,0 +1,121 @@
+use crate::{Plugin, PluginData};
+use std::collections::HashMap;
+use std::hash::Hash;
+
+/// Parses the plugin names a host is given, e.g. from its configuration, into its own key
+/// type, see `PluginLoader::load_registry_from`. Types with a `TryFrom<&str>` impl can
+/// forward to it.
+pub trait FromPluginName: Sized {
+    /// `None` for a name the host doesn't know.
+    fn from_name(name: &str) -> Option<Self>;
+}
+
+/// Loaded plugins keyed by the host's own type, typically an enum of the plugin kinds it knows
+/// about, so a typo in a plugin name fails to compile instead of failing a lookup.
+/// See `PluginLoader::load_registry`.
+pub struct PluginRegistry<K: Eq + Hash, P: PluginData> {
+    plugins: HashMap<K, Plugin<P>>,
+}
+
+impl<K: Eq + Hash, P: PluginData> PluginRegistry<K, P> {
+    pub(crate) fn new(plugins: HashMap<K, Plugin<P>>) -> Self {
+        PluginRegistry { plugins }
+    }
+
+    pub fn get(&self, key: &K) -> Option<&Plugin<P>> {
+        self.plugins.get(key)
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item=&K> {
+        self.plugins.keys()
+    }
+
+    pub fn len(&self) -> usize {
+        self.plugins.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.plugins.is_empty()
+    }
+}
+
+impl<K: Eq + Hash, P: PluginData> std::ops::Index<K> for PluginRegistry<K, P> {
+    type Output = Plugin<P>;
+
+    /// Panics if `key` wasn't in the mapping the registry was loaded from.
+    fn index(&self, key: K) -> &Plugin<P> {
+        &self.plugins[&key]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tokio_utils::create_tokio_runtime;
+    use crate::test_utils::{kind_event_loop, KindSource};
+    use crate::loader::PluginLoader;
+    use crate::runtime::PluginRuntime;
+    use crate::registry::FromPluginName;
+    use crate::PluginError;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    enum Kind {
+        Greeter,
+        Counter,
+    }
+
+    impl FromPluginName for Kind {
+        fn from_name(name: &str) -> Option<Self> {
+            match name {
+                "greeter" => Some(Kind::Greeter),
+                "counter" => Some(Kind::Counter),
+                _ => None,
+            }
+        }
+    }
+
+    #[test]
+    fn registry_by_kind() {
+        let mut kind_runtime = PluginRuntime::new();
+        let (fut1, handle) = kind_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let handle2 = runtime.spawn(async move {
+            kind_event_loop(handle)
+        });
+        let mut kind_loader = PluginLoader::new(KindSource{}, kind_runtime);
+        let registry = kind_loader.load_registry(vec![(Kind::Greeter, "greeter"), (Kind::Counter, "counter")]).unwrap();
+        assert_eq!(registry.len(), 2);
+        assert_eq!(registry[Kind::Greeter].execute(()), Ok(Ok("hello".to_string())));
+        assert_eq!(registry.get(&Kind::Counter).unwrap().execute(()), Ok(Ok("0".to_string())));
+        drop(registry);
+        kind_loader.unload("greeter").unwrap();
+        kind_loader.unload("counter").unwrap();
+        assert_eq!(kind_loader.load_registry(vec![(Kind::Greeter, "greeter"), (Kind::Counter, "missing")]).err(), Some(PluginError::InvalidPlugin("missing".to_string())));
+        assert!(kind_loader.group("greetings").is_empty());
+        drop(kind_loader);
+        let (_res1, _res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+    }
+
+    #[test]
+    fn registry_from_names() {
+        let mut kind_runtime = PluginRuntime::new();
+        let (fut1, handle) = kind_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let handle2 = runtime.spawn(async move {
+            kind_event_loop(handle)
+        });
+        let mut kind_loader = PluginLoader::new(KindSource{}, kind_runtime);
+        assert_eq!(kind_loader.load_registry_from::<Kind, _, _>(vec!["greeter", "conuter"]).err(), Some(PluginError::InvalidPlugin("unknown plugin name conuter".to_string())));
+        assert!(kind_loader.group("greetings").is_empty());
+        let registry = kind_loader.load_registry_from::<Kind, _, _>(vec!["counter"]).unwrap();
+        assert_eq!(registry.keys().collect::<Vec<_>>(), vec![&Kind::Counter]);
+        drop(registry);
+        drop(kind_loader);
+        let (_res1, _res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+    }
+}
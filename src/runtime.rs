@@ -1,190 +1,2682 @@
-use crate::{Plugin, PluginCallResult, PluginData, PluginResult, PluginError};
-use std::collections::HashMap;
-use std::sync::mpsc::{channel, Receiver, Sender};
-use std::sync::{Arc, Mutex};
+use crate::{LoadCause, Plugin, PluginCallOutcome, PluginCallResult, PluginData, PluginInit, PluginResult, PluginError, SharedCallOutcome};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::time::{Duration, Instant};
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread::JoinHandle;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+#[cfg(feature = "builder")]
 use typed_builder::TypedBuilder;
 use uuid::Uuid;
 use std::result::Result::Err;
+use std::borrow::Cow;
+use std::ops::Deref;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll, Waker};
+use crate::subscribers::{Backlog, ResultSender, SubscriberMap, TopicMap, DEFAULT_SHARDS};
+use crate::config::ConfigWatch;
+use crate::operation::Operation;
+use crate::executor::{Executor, LoopTasks, Task};
+use std::any::Any;
+use std::fmt::{Debug, Formatter};
 
+/// Result as handed to subscribers. Fanning it out to several subscribers clones the `Ok`
+/// payload, unless the event loop shared it, see `Handle::broadcast_shared`.
 pub enum RuntimeResult<P: PluginCallResult> {
-    Ok(P::Ok),
+    Ok(Payload<P::Ok>),
     Err(P::Err)
 }
 
-impl<P: PluginCallResult> Into<Result<P::Ok, P::Err>> for RuntimeResult<P> {
-    fn into(self) -> Result<<P as PluginCallResult>::Ok, <P as PluginCallResult>::Err> {
+impl<P: PluginCallResult> Clone for RuntimeResult<P> {
+    fn clone(&self) -> Self {
         match self {
-            RuntimeResult::Ok(o) => {
-                Ok(o)
+            RuntimeResult::Ok(o) => RuntimeResult::Ok(o.clone()),
+            RuntimeResult::Err(e) => RuntimeResult::Err(e.clone()),
+        }
+    }
+}
+
+impl<P: PluginCallResult> RuntimeResult<P> {
+    /// Hands the payload over behind an `Arc`, without copying it if it was shared.
+    pub fn into_shared(self) -> Result<Arc<P::Ok>, P::Err> {
+        match self {
+            RuntimeResult::Ok(o) => Ok(o.into_arc()),
+            RuntimeResult::Err(e) => Err(e),
+        }
+    }
+}
+
+/// Only clones the payload when it is still shared with other subscribers.
+impl<P: PluginCallResult> From<RuntimeResult<P>> for Result<P::Ok, P::Err> {
+    fn from(result: RuntimeResult<P>) -> Self {
+        match result {
+            RuntimeResult::Ok(o) => Ok(o.into_owned()),
+            RuntimeResult::Err(e) => Err(e),
+        }
+    }
+}
+
+impl<P: PluginCallResult> From<Result<P::Ok, P::Err>> for RuntimeResult<P> {
+    fn from(result: Result<P::Ok, P::Err>) -> Self {
+        match result {
+            Ok(o) => RuntimeResult::Ok(Payload::Owned(o)),
+            Err(e) => RuntimeResult::Err(e),
+        }
+    }
+}
+
+fn shared_result<P: PluginCallResult>(result: Result<Arc<P::Ok>, P::Err>) -> RuntimeResult<P> where P::Ok: Sync {
+    match result {
+        Ok(o) => RuntimeResult::Ok(Payload::Shared(Shared::new(o))),
+        Err(e) => RuntimeResult::Err(e),
+    }
+}
+
+pub type PluginOpCallId = Uuid;
+
+pub type Subscribers<P> = Arc<SubscriberMap<<P as PluginData>::PluginCallResult>>;
+
+pub type Topics<P> = Arc<TopicMap<<P as PluginData>::PluginCallResult>>;
+
+pub type ResultReceiver<P> = Receiver<PluginResult<RuntimeResult<<P as PluginData>::PluginCallResult>>>;
+
+pub type TaggedResult<P> = (PluginOpCallId, PluginResult<RuntimeResult<P>>);
+
+/// Where a call's time went, see `Plugin::execute_timed`. Measured with `Instant`, the
+/// monotonic clock, so it is as precise as the platform makes it (nanoseconds on Linux) and
+/// unaffected by wall clock changes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CallTiming {
+    /// From sending the call to an event loop first taking it. Deferring the call puts it back
+    /// in the queue without counting as queued again.
+    pub queued: Duration,
+    /// From an event loop first taking the call to the result loop delivering its result. The
+    /// whole call when it never reached an event loop, e.g. when the runtime aborted it.
+    pub processing: Duration,
+    pub total: Duration,
+}
+
+pub type TimedResult<P> = (PluginResult<RuntimeResult<P>>, CallTiming);
+
+pub type FailedPlugins = Arc<Mutex<HashMap<String, PluginError>>>;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PluginMetrics {
+    pub resolved: u64,
+    pub rejected: u64,
+    pub timed_out: u64,
+    /// Moving average of the time calls took, timed out calls counting for their timeout.
+    /// `None` until the first call completes.
+    pub avg_latency: Option<Duration>,
+}
+
+impl PluginMetrics {
+    /// The newest sample makes up `1 / LATENCY_WEIGHT` of `avg_latency`.
+    const LATENCY_WEIGHT: u32 = 8;
+
+    pub(crate) fn record_latency(&mut self, latency: Duration) {
+        self.avg_latency = Some(match self.avg_latency {
+            Some(avg) => avg * (Self::LATENCY_WEIGHT - 1) / Self::LATENCY_WEIGHT + latency / Self::LATENCY_WEIGHT,
+            None => latency,
+        });
+    }
+
+    /// Calls that completed one way or another.
+    pub fn calls(&self) -> u64 {
+        self.resolved + self.rejected + self.timed_out
+    }
+}
+
+pub type Metrics = Arc<Mutex<HashMap<String, PluginMetrics>>>;
+
+pub type Cancellations = Arc<Mutex<HashSet<PluginOpCallId>>>;
+
+/// How many times `Handle::defer` puts a call back unless the runtime is built otherwise.
+pub const DEFAULT_MAX_DEFERS: usize = 16;
+
+/// Where `Handle::defer` puts calls back, along with the wake-up token sender with dedicated
+/// channels. Emptied once the runtime stops handing out calls, so it never keeps the call
+/// channel open on its own.
+type Requeue<P> = Arc<Mutex<Option<(Sender<PluginOpCall<P>>, Option<Sender<()>>)>>>;
+
+/// Calls sent but not yet received by the event loop, see `PluginRuntime::queue_depth`.
+pub type QueueDepth = Arc<AtomicUsize>;
+
+/// How many of the latest calls `PluginRuntime::queue_wait_stats` summarizes.
+pub const QUEUE_WAIT_SAMPLES: usize = 1024;
+
+/// Queue waits of the latest calls, oldest first.
+type QueueWaits = Arc<Mutex<VecDeque<Duration>>>;
+
+/// How long the latest calls waited between being sent and an event loop taking them, see
+/// `PluginRuntime::queue_wait_stats`. Percentiles are nearest-rank over the samples.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueWaitStats {
+    /// How many calls the summary covers, at most `QUEUE_WAIT_SAMPLES`.
+    pub samples: usize,
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+/// Set once the runtime drains, plugins refuse new calls from then on.
+pub type Draining = Arc<AtomicBool>;
+
+pub type FailureHooks = Arc<Mutex<Vec<Box<dyn Send + Fn(&str, &PluginError)>>>>;
+
+/// Why the runtime stopped, see `Handle::shutdown_reason`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ShutdownReason {
+    /// The runtime was dropped or drained, or the host gave no reason.
+    Planned,
+    /// The host stops the runtime to start a fresh one, e.g. with new plugins or settings.
+    Reload,
+    /// The host gave up on the runtime after `error`.
+    Fatal(PluginError),
+}
+
+/// What `Handle::serve` does after a handler panicked, on top of failing the call.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OnPanic {
+    /// Nothing, the plugin keeps whatever state the panic left behind.
+    #[default]
+    RejectOnly,
+    /// Hand the handler the plugin's loading call, see `plugin_loader`, to bring it back to a
+    /// clean state. A plugin that panics more than `max_reloads` times within `window`, or
+    /// while reloading, is failed instead, so a plugin that can't recover doesn't crash loop.
+    Reload {
+        max_reloads: usize,
+        window: Duration,
+    },
+}
+
+type LoaderFn<P> = dyn Send + Sync + Fn(P) -> <P as PluginData>::PluginCall;
+
+/// When each plugin was last reloaded after a panic, oldest first.
+type Reloads = Arc<Mutex<HashMap<String, VecDeque<Instant>>>>;
+
+/// Text of a caught panic's payload.
+pub(crate) fn panic_reason(panic: Box<dyn Any + Send>) -> String {
+    panic.downcast_ref::<&str>().map(|reason| reason.to_string())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_string())
+}
+
+/// First reason given to stop the runtime, shared with its handles.
+type StopReason = Arc<Mutex<Option<ShutdownReason>>>;
+
+/// What a call does when `max_pending` calls are already waiting for a result.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FullQueue {
+    /// Block the caller until a pending call completes, or until the call's deadline. The
+    /// future of `Plugin::execute_pending` awaits the free slot instead of blocking.
+    #[default]
+    Wait,
+    /// Fail the call right away with a `RuntimeError`.
+    Fail,
+}
+
+/// Calls counted against the limit, and the futures waiting for one of them to complete.
+#[derive(Default)]
+pub(crate) struct Pending {
+    count: usize,
+    waiting: Vec<Waker>,
+}
+
+type PendingCount = Arc<(Mutex<Pending>, Condvar)>;
+
+/// Shared cap on pending calls, see `PluginRuntimeBuilder::max_pending`.
+#[derive(Clone)]
+pub struct PendingLimit {
+    max: usize,
+    policy: FullQueue,
+    count: PendingCount,
+}
+
+impl PendingLimit {
+    pub(crate) fn acquire(&self, deadline: Option<Instant>) -> PluginResult<PendingPermit> {
+        let (count, freed) = &*self.count;
+        let started = Instant::now();
+        let mut pending = count.lock().unwrap();
+        while pending.count >= self.max {
+            if self.policy == FullQueue::Fail {
+                return Err(PluginError::RuntimeError(format!("{} calls are already pending", self.max)));
             }
-            RuntimeResult::Err(e) => {
-                Err(e)
+            pending = match deadline {
+                None => freed.wait(pending).unwrap(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(PluginError::Timeout(started.elapsed()));
+                    }
+                    freed.wait_timeout(pending, deadline - now).unwrap().0
+                }
+            };
+        }
+        pending.count += 1;
+        Ok(PendingPermit(self.count.clone()))
+    }
+
+    /// Like `acquire` without waiting, `None` when the caller would have to wait.
+    pub(crate) fn try_acquire(&self) -> PluginResult<Option<PendingPermit>> {
+        self.acquire_or_register(None)
+    }
+
+    /// Like `acquire` without a deadline, registering the task to wake up rather than blocking.
+    pub(crate) fn poll_acquire(&self, cx: &mut Context<'_>) -> Poll<PluginResult<PendingPermit>> {
+        match self.acquire_or_register(Some(cx.waker())) {
+            Ok(Some(permit)) => Poll::Ready(Ok(permit)),
+            Ok(None) => Poll::Pending,
+            Err(e) => Poll::Ready(Err(e)),
+        }
+    }
+
+    fn acquire_or_register(&self, waker: Option<&Waker>) -> PluginResult<Option<PendingPermit>> {
+        let mut pending = self.count.0.lock().unwrap();
+        if pending.count < self.max {
+            pending.count += 1;
+            return Ok(Some(PendingPermit(self.count.clone())));
+        }
+        if self.policy == FullQueue::Fail {
+            return Err(PluginError::RuntimeError(format!("{} calls are already pending", self.max)));
+        }
+        if let Some(waker) = waker {
+            pending.waiting.push(waker.clone());
+        }
+        Ok(None)
+    }
+}
+
+/// Counts a call against its `PendingLimit` until the call's subscriber is dropped.
+pub struct PendingPermit(PendingCount);
+
+impl Drop for PendingPermit {
+    fn drop(&mut self) {
+        let (count, freed) = &*self.0;
+        let waiting = match count.lock() {
+            Ok(mut pending) => {
+                pending.count -= 1;
+                std::mem::take(&mut pending.waiting)
             }
+            Err(_) => Vec::new(),
+        };
+        freed.notify_one();
+        // Every waiting future polls again, those that lose the slot register anew.
+        for waker in waiting {
+            waker.wake();
         }
     }
 }
 
-pub type PluginOpCallId = Uuid;
+/// An `Arc` that can only be made from a `Send + Sync` value. Sending or sharing it is then
+/// as safe as for the `Arc` itself, which lets calls and results be shared between threads
+/// without `PluginData::PluginCall` or `PluginCallResult::Ok` having to be `Sync`: only the
+/// methods that share a payload ask for it.
+pub struct Shared<T>(Arc<T>);
+
+// SAFETY: a `Shared<T>` only exists for a `T: Send + Sync`, see `Shared::new`.
+unsafe impl<T: Send> Send for Shared<T> {}
+// SAFETY: as above.
+unsafe impl<T: Send> Sync for Shared<T> {}
+
+impl<T: Send + Sync> Shared<T> {
+    pub fn new(value: Arc<T>) -> Self {
+        Shared(value)
+    }
+}
+
+impl<T> Clone for Shared<T> {
+    fn clone(&self) -> Self {
+        Shared(self.0.clone())
+    }
+}
+
+impl<T> Deref for Shared<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: Debug> Debug for Shared<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// A call or result payload, owned or shared with other calls or subscribers. Derefs to the
+/// payload either way.
+#[derive(Debug, Clone)]
+pub enum Payload<T> {
+    Owned(T),
+    Shared(Shared<T>),
+}
+
+/// A call's payload, moved in by `Plugin::execute` or shared by `Plugin::execute_arc`.
+pub type CallPayload<C> = Payload<C>;
+
+impl<T> Payload<T> {
+    /// The payload by value, cloned only if it is still shared.
+    pub fn into_owned(self) -> T where T: Clone {
+        match self {
+            Payload::Owned(value) => value,
+            Payload::Shared(Shared(value)) => Arc::try_unwrap(value).unwrap_or_else(|value| (*value).clone()),
+        }
+    }
+
+    /// The payload behind an `Arc`, which is only allocated if it was owned.
+    pub fn into_arc(self) -> Arc<T> {
+        match self {
+            Payload::Owned(value) => Arc::new(value),
+            Payload::Shared(Shared(value)) => value,
+        }
+    }
+}
+
+impl<T> From<T> for Payload<T> {
+    fn from(value: T) -> Self {
+        Payload::Owned(value)
+    }
+}
+
+impl<T> Deref for Payload<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        match self {
+            Payload::Owned(value) => value,
+            Payload::Shared(value) => value,
+        }
+    }
+}
 
+/// A call as seen by the event loop.
+///
+/// `plugin_data` is the full value the plugin was loaded from, not just its name, so a single
+/// loop serving several plugins can match on it (or on its config) to route the call.
 pub struct PluginOpCall<P: PluginData> {
     pub plugin_data: P,
     pub call_id: PluginOpCallId,
-    pub call: P::PluginCall,
+    pub call: CallPayload<P::PluginCall>,
+    pub headers: HashMap<String, String>,
+    /// When the caller stops waiting, if it set a timeout. Purely advisory: a loop may reject
+    /// calls it can't finish in time, or ignore it and let the caller time out.
+    pub deadline: Option<Instant>,
+    /// How many times the event loop put the call back, see `Handle::defer`.
+    pub deferred: usize,
+}
+
+impl<P: PluginData> PluginOpCall<P> {
+    pub fn plugin(&self) -> &P {
+        &self.plugin_data
+    }
+
+    pub fn plugin_name(&self) -> Cow<'_, str> {
+        self.plugin_data.name_ref()
+    }
+
+    pub fn id(&self) -> PluginOpCallId {
+        self.call_id
+    }
+
+    pub fn call(&self) -> &P::PluginCall {
+        &self.call
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).map(String::as_str)
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.deadline.is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    pub fn into_parts(self) -> (P, PluginOpCallId, CallPayload<P::PluginCall>) {
+        (self.plugin_data, self.call_id, self.call)
+    }
+}
+
+/// Who a result is for. `Shutdown` also stops the result loop once every waiter got it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResultTarget {
+    One(PluginOpCallId),
+    Broadcast,
+    Shutdown,
+}
+
+/// Nil ids keep their historical meaning of broadcasting and shutting down.
+impl From<PluginOpCallId> for ResultTarget {
+    fn from(id: PluginOpCallId) -> Self {
+        if id.is_nil() {
+            ResultTarget::Shutdown
+        } else {
+            ResultTarget::One(id)
+        }
+    }
 }
 
 pub struct PluginOpCallResult<P: PluginCallResult> {
-    call_id: PluginOpCallId,
-    result: Result<P::Ok, P::Err>,
+    target: ResultTarget,
+    result: RuntimeResult<P>,
+}
+
+pub(crate) enum RuntimeMessage<P: PluginCallResult> {
+    Result(PluginOpCallResult<P>),
+    Failed(String, PluginError),
+    Aborted(PluginError),
+    Item(PluginOpCallId, Result<P::Ok, P::Err>),
+    End(PluginOpCallId),
+    /// Fails one call with a runtime error rather than the plugin's own.
+    Errored(PluginOpCallId, PluginError),
+    Progress(PluginOpCallId, f32),
+    Publish(String, RuntimeResult<P>),
 }
 
-#[derive(TypedBuilder)]
+#[cfg_attr(feature = "builder", derive(TypedBuilder))]
 pub struct PluginRuntime<P: PluginData> where P::PluginCall: Send, P::PluginCallResult: PluginCallResult,  {
-    plugin_loader: Box<dyn Send + Fn(P) -> P::PluginCall>,
+    #[cfg_attr(feature = "builder", builder(default, setter(strip_option)))]
+    plugin_loader: Option<Box<dyn Send + Sync + Fn(P) -> P::PluginCall>>,
+    #[cfg_attr(feature = "builder", builder(default, setter(strip_option)))]
+    plugin_unloader: Option<Box<dyn Send + Sync + Fn(P) -> P::PluginCall>>,
+    #[cfg_attr(feature = "builder", builder(default))]
+    subscriber_capacity: usize,
+    /// How many shards the pending calls are split into, see `SubscriberMap::with_shards`.
+    #[cfg_attr(feature = "builder", builder(default = DEFAULT_SHARDS))]
+    subscriber_shards: usize,
+    #[cfg_attr(feature = "builder", builder(default))]
+    batch_drain: bool,
+    /// Give every plugin its own call channel, drained round-robin by the event loop, so a
+    /// backlog on one plugin doesn't hold up calls to the others. Costs a channel per plugin
+    /// and a wake-up token per call, so the single shared channel stays the default.
+    #[cfg_attr(feature = "builder", builder(default))]
+    dedicated_channels: bool,
+    /// Serve the plugin that got the least service lately first instead of plain FIFO, so a
+    /// busy plugin can't starve the others sharing the call channel. Dedicated channels are
+    /// already served round-robin and ignore this.
+    #[cfg_attr(feature = "builder", builder(default))]
+    fair_scheduling: bool,
+    /// Caps how many calls may wait for a result at once across all plugins; `full_queue`
+    /// decides what a call beyond the cap does. Unbounded by default.
+    #[cfg_attr(feature = "builder", builder(default, setter(strip_option)))]
+    max_pending: Option<usize>,
+    #[cfg_attr(feature = "builder", builder(default))]
+    full_queue: FullQueue,
+    /// Caps how many items of a streamed call may wait for its consumer to read them. Past the
+    /// cap `Handle::push` blocks and `Handle::try_push` hands the item back. Unbounded by default.
+    #[cfg_attr(feature = "builder", builder(default, setter(strip_option)))]
+    max_buffered: Option<usize>,
+    #[cfg_attr(feature = "builder", builder(default))]
+    on_panic: OnPanic,
+    /// Count the calls waiting in the call channel, see `queue_depth`. Costs two atomic
+    /// operations per call, so it is off by default.
+    #[cfg_attr(feature = "builder", builder(default))]
+    track_queue_depth: bool,
+    /// Record how long calls wait before an event loop takes them, see `queue_wait_stats`.
+    /// Costs a lock per received call, so it is off by default.
+    #[cfg_attr(feature = "builder", builder(default))]
+    track_queue_wait: bool,
+    /// How many times `Handle::defer` may put the same call back before failing it.
+    #[cfg_attr(feature = "builder", builder(default = DEFAULT_MAX_DEFERS))]
+    max_defers: usize,
+    #[cfg(feature = "tokio")]
+    #[cfg_attr(feature = "builder", builder(default, setter(skip)))]
+    tokio_handle: Option<tokio::runtime::Handle>,
 
-    #[builder(default=None, setter(skip))]
-    result_sender: Option<Sender<PluginOpCallResult<P::PluginCallResult>>>,
-    #[builder(default=None, setter(skip))]
+    #[cfg_attr(feature = "builder", builder(default=None, setter(skip)))]
+    result_sender: Option<Sender<RuntimeMessage<P::PluginCallResult>>>,
+    #[cfg_attr(feature = "builder", builder(default=None, setter(skip)))]
     call_sender: Option<Sender<PluginOpCall<P>>>,
-    #[builder(default=None, setter(skip))]
-    subscribers: Option<Arc<Mutex<HashMap<PluginOpCallId, Sender<RuntimeResult<P::PluginCallResult>>>>>>,
+    #[cfg_attr(feature = "builder", builder(default=None, setter(skip)))]
+    lanes: Option<(Sender<()>, Lanes<P>)>,
+    #[cfg_attr(feature = "builder", builder(default=None, setter(skip)))]
+    subscribers: Option<Subscribers<P>>,
+    #[cfg_attr(feature = "builder", builder(default, setter(skip)))]
+    topics: Topics<P>,
+    #[cfg_attr(feature = "builder", builder(default, setter(skip)))]
+    failed: FailedPlugins,
+    #[cfg_attr(feature = "builder", builder(default, setter(skip)))]
+    failure_hooks: FailureHooks,
+    #[cfg_attr(feature = "builder", builder(default, setter(skip)))]
+    metrics: Metrics,
+    #[cfg_attr(feature = "builder", builder(default, setter(skip)))]
+    cancellations: Cancellations,
+    #[cfg_attr(feature = "builder", builder(default, setter(skip)))]
+    draining: Draining,
+    #[cfg_attr(feature = "builder", builder(default, setter(skip)))]
+    config: Option<Box<dyn Any + Send + Sync>>,
+    #[cfg_attr(feature = "builder", builder(default, setter(skip)))]
+    pending: PendingCount,
+    #[cfg_attr(feature = "builder", builder(default, setter(skip)))]
+    stop_reason: StopReason,
+    #[cfg_attr(feature = "builder", builder(default=None, setter(skip)))]
+    queue_depth: Option<QueueDepth>,
+    #[cfg_attr(feature = "builder", builder(default=None, setter(skip)))]
+    queue_waits: Option<QueueWaits>,
+    #[cfg_attr(feature = "builder", builder(default, setter(skip)))]
+    requeue: Requeue<P>,
+}
+
+/// The settings `PluginRuntime::builder` takes, as a plain struct for `PluginRuntime::with_options`
+/// when the `builder` feature is off.
+pub struct RuntimeOptions<P: PluginData> {
+    pub plugin_loader: Option<Box<dyn Send + Sync + Fn(P) -> P::PluginCall>>,
+    pub plugin_unloader: Option<Box<dyn Send + Sync + Fn(P) -> P::PluginCall>>,
+    pub subscriber_capacity: usize,
+    pub subscriber_shards: usize,
+    pub batch_drain: bool,
+    pub dedicated_channels: bool,
+    pub fair_scheduling: bool,
+    pub max_pending: Option<usize>,
+    pub full_queue: FullQueue,
+    pub max_buffered: Option<usize>,
+    pub on_panic: OnPanic,
+    pub track_queue_depth: bool,
+    pub track_queue_wait: bool,
+    pub max_defers: usize,
+}
+
+impl<P: PluginData> Default for RuntimeOptions<P> {
+    fn default() -> Self {
+        RuntimeOptions {
+            plugin_loader: None,
+            plugin_unloader: None,
+            subscriber_capacity: 0,
+            subscriber_shards: DEFAULT_SHARDS,
+            batch_drain: false,
+            dedicated_channels: false,
+            fair_scheduling: false,
+            max_pending: None,
+            full_queue: FullQueue::default(),
+            max_buffered: None,
+            on_panic: OnPanic::default(),
+            track_queue_depth: false,
+            track_queue_wait: false,
+            max_defers: DEFAULT_MAX_DEFERS,
+        }
+    }
+}
+
+impl<P: PluginData> RuntimeOptions<P> where P::PluginCallResult: 'static, P::PluginCall: 'static {
+    /// Same as `PluginRuntime::with_options`, to finish a struct update in one expression.
+    pub fn build(self) -> PluginRuntime<P> {
+        PluginRuntime::with_options(self)
+    }
+}
+
+impl<P: PluginData> Default for PluginRuntime<P> where P::PluginCallResult: 'static, P::PluginCall: 'static {
+    fn default() -> Self {
+        Self::with_options(RuntimeOptions::default())
+    }
 }
 
 impl<P: PluginData> Drop for PluginRuntime<P> {
     fn drop(&mut self) {
+        if let Ok(mut stop_reason) = self.stop_reason.lock() {
+            stop_reason.get_or_insert(ShutdownReason::Planned);
+        }
         self.call_sender.take();
+        self.lanes.take();
+        if let Ok(mut requeue) = self.requeue.lock() {
+            requeue.take();
+        }
         self.result_sender.take();
         self.subscribers.take();
+        self.topics.clear();
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReceiveError {
+    /// Every call sender is gone, the runtime shut down and the loop should exit cleanly.
+    Disconnected,
+    /// Another event loop sharing this handle panicked while receiving.
+    Poisoned,
+}
+
+impl core::fmt::Display for ReceiveError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ReceiveError::Disconnected => write!(f, "runtime disconnected"),
+            ReceiveError::Poisoned => write!(f, "call receiver poisoned"),
+        }
     }
 }
 
+impl std::error::Error for ReceiveError {}
+
 #[derive(Clone)]
 pub struct Handle<P: PluginData> {
-    result_sender: Sender<PluginOpCallResult<P::PluginCallResult>>,
+    result_sender: Sender<RuntimeMessage<P::PluginCallResult>>,
     call_receiver: Arc<Mutex<Receiver<PluginOpCall<P>>>>,
+    lanes: Option<(WakeReceiver, Lanes<P>)>,
+    fair: Option<FairQueues<P>>,
+    cancellations: Cancellations,
+    subscribers: Subscribers<P>,
+    stop_reason: StopReason,
+    on_panic: OnPanic,
+    reloader: Option<Arc<LoaderFn<P>>>,
+    reloads: Reloads,
+    queue_depth: Option<QueueDepth>,
+    queue_waits: Option<QueueWaits>,
+    requeue: Requeue<P>,
+    max_defers: usize,
 }
 
-impl<P: PluginData> Handle<P> {
-    pub fn resolve<T: Into<<P::PluginCallResult as PluginCallResult>::Ok>>(&self, id: PluginOpCallId, result: T) {
-        if let Err(e) = self.result_sender.send(PluginOpCallResult {
-            call_id: id,
-            result: Ok(result.into()),
-        }) {
-            eprintln!("{}", e.to_string())
+/// Per-plugin call channels and the position of the next one to serve.
+pub(crate) struct RoundRobin<P: PluginData> {
+    receivers: Vec<Receiver<PluginOpCall<P>>>,
+    cursor: usize,
+}
+
+pub(crate) type Lanes<P> = Arc<Mutex<RoundRobin<P>>>;
+
+type WakeReceiver = Arc<Mutex<Receiver<()>>>;
+
+impl<P: PluginData> RoundRobin<P> {
+    fn next(&mut self) -> Option<PluginOpCall<P>> {
+        let mut checked = 0;
+        while checked < self.receivers.len() {
+            let index = self.cursor % self.receivers.len();
+            match self.receivers[index].try_recv() {
+                Ok(call) => {
+                    self.cursor = index + 1;
+                    return Some(call);
+                }
+                Err(TryRecvError::Disconnected) => {
+                    self.receivers.remove(index);
+                }
+                Err(TryRecvError::Empty) => {
+                    self.cursor = index + 1;
+                    checked += 1;
+                }
+            }
         }
+        None
     }
+}
 
-    pub fn reject<T: Into<<P::PluginCallResult as PluginCallResult>::Err>>(&self, id: PluginOpCallId, result: T) {
-        if let Err(e) = self.result_sender.send(PluginOpCallResult {
-            call_id: id,
-            result: Err(result.into()),
-        }) {
-            eprintln!("{}", e.to_string());
+/// Calls buffered per plugin and handed out to the least recently served plugin first,
+/// oldest call first among equally served ones.
+pub(crate) struct FairQueue<P: PluginData> {
+    queues: HashMap<String, VecDeque<(u64, PluginOpCall<P>)>>,
+    served: HashMap<String, u64>,
+    sequence: u64,
+    picks: u64,
+}
+
+/// Service counts are halved this often so only recent service weighs in.
+const FAIR_DECAY_PICKS: u64 = 64;
+
+type FairQueues<P> = Arc<Mutex<FairQueue<P>>>;
+
+#[derive(Clone, Copy)]
+enum Wait {
+    Forever,
+    For(Duration),
+    Never,
+}
+
+impl<P: PluginData> FairQueue<P> {
+    fn new() -> Self {
+        FairQueue {
+            queues: HashMap::new(),
+            served: HashMap::new(),
+            sequence: 0,
+            picks: 0,
         }
     }
 
-    pub fn receive(&self) -> Result<PluginOpCall<P>, String> {
-        let res = {
-            self.call_receiver.lock().unwrap().recv()
-        };
-        if let Err(ref e) = res {
-            return Err(e.to_string());
+    fn push(&mut self, call: PluginOpCall<P>) {
+        self.sequence += 1;
+        let name = call.plugin_name().into_owned();
+        self.queues.entry(name).or_default().push_back((self.sequence, call));
+    }
+
+    fn fill(&mut self, receiver: &Receiver<PluginOpCall<P>>) {
+        for call in receiver.try_iter() {
+            self.push(call);
         }
-        Ok(res.unwrap())
     }
-}
 
-impl<P: PluginData> PluginRuntime<P> where P::PluginCallResult: 'static + PluginCallResult,  P::PluginCall: 'static + Send {
-    pub fn run(&mut self) -> (impl core::future::Future<Output=()>, Handle<P>) {
-        let (call_sender, call_receiver) = channel();
-        let (result_sender, result_receiver) = channel();
-        self.call_sender = Some(call_sender);
-        let handle = Handle {
-            result_sender: result_sender.clone(),
-            call_receiver: Arc::new(Mutex::new(call_receiver)),
-        };
-        self.result_sender.replace(result_sender);
-        self.subscribers.replace(Arc::new(Mutex::new(HashMap::new())));
-        let subscribers_cloned = self.subscribers.clone().unwrap();
-        (async move {
-            loop {
-                let res = result_receiver.recv();
-                if let Err(e) = res {
-                    eprintln!("{}", e.to_string());
-                    break;
-                }
-                let res = res.unwrap();
-                let mut subscribers = subscribers_cloned.lock().unwrap();
-                if res.call_id.is_nil() {
-                    for (_id, sender) in subscribers.drain() {
-                        let res = match res.result.clone() {
-                            Ok(o) => RuntimeResult::Ok(o),
-                            Err(e) => RuntimeResult::Err(e),
-                        };
-                        if let Err(e) = sender.send(res) {
-                            eprintln!("{}", e.to_string());
-                            break;
-                        }
-                    }
-                    break;
-                }
-                let sender = subscribers.remove(&res.call_id).unwrap();
-                let res = match res.result {
-                    Ok(o) => RuntimeResult::Ok(o),
-                    Err(e) => RuntimeResult::Err(e),
-                };
-                if let Err(e) = sender.send(res) {
-                    eprintln!("{}", e.to_string());
-                    break;
-                }
-            }
-        }, handle)
-        /*async move {
-            if let Err(e) = (event_loop)(handle) {
-                eprintln!("{}", e);
-            }
-        })*/
+    fn is_empty(&self) -> bool {
+        self.queues.is_empty()
     }
 
-    pub fn load_plugin(
-        &mut self,
-        plugin: P,
-    ) -> PluginResult<Plugin<P>> {
-        if self.call_sender.is_none() {
-            return Err(PluginError::FailedToLoad("run runtime first".to_string()))
+    fn pop(&mut self) -> Option<PluginOpCall<P>> {
+        let (_, _, name) = self.queues.iter()
+            .filter_map(|(name, queue)| {
+                let (sequence, _) = queue.front()?;
+                Some((self.served.get(name).copied().unwrap_or_default(), *sequence, name))
+            })
+            .min()?;
+        let name = name.clone();
+        let queue = self.queues.get_mut(&name)?;
+        let (_, call) = queue.pop_front()?;
+        if queue.is_empty() {
+            self.queues.remove(&name);
         }
-        if self.subscribers.is_none() {
-            return Err(PluginError::FailedToLoad("run runtime first".to_string()))
+        *self.served.entry(name).or_default() += 1;
+        self.picks += 1;
+        if self.picks % FAIR_DECAY_PICKS == 0 {
+            self.served.retain(|_, served| {
+                *served /= 2;
+                *served > 0
+            });
         }
-        let loading_call = (self.plugin_loader)(plugin.clone());
-        let pl = Plugin {
-            plugin_data: plugin,
-            call_sender: self.call_sender.clone().unwrap(),
-            subscribers: self.subscribers.clone().unwrap()
-        };
-        pl.execute(loading_call).and_then(|result| match result {
-            Ok(_) => Ok(pl),
-            Err(e) => Err(PluginError::FailedToLoad(e.to_string()))
-        })
+        Some(call)
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use crate::test_utils::{build_dummy_runtime, dummy_event_loop};
-    use crate::tokio_utils::create_tokio_runtime;
+impl<P: PluginData> Handle<P> {
+    pub fn resolve<T: Into<<P::PluginCallResult as PluginCallResult>::Ok>>(&self, id: PluginOpCallId, result: T) {
+        trace_call!("call {} resolved", id);
+        self.cancellations.lock().unwrap().remove(&id);
+        self.send_result(id.into(), Ok(result.into()));
+    }
 
-    #[test]
-    fn build_runtime() {
-        let mut dummy_runtime = build_dummy_runtime();
-        let (fut1, handle) = dummy_runtime.run();
-        let runtime = create_tokio_runtime();
-        let handle1 = runtime.spawn(fut1);
-        let handle2 = runtime.spawn(async move {
-            dummy_event_loop(handle)
-        });
-        drop(dummy_runtime);
-        let (_res1, _res2) = runtime.block_on(async move {
-            tokio::join!(handle1, handle2)
-        });
+    pub fn reject<T: Into<<P::PluginCallResult as PluginCallResult>::Err>>(&self, id: PluginOpCallId, result: T) {
+        trace_call!("call {} rejected", id);
+        self.cancellations.lock().unwrap().remove(&id);
+        self.send_result(id.into(), Err(result.into()));
     }
-}
+
+    /// Resolves a call made with `Plugin::execute_op::<O>`.
+    pub fn resolve_op<O: Operation<P>>(&self, id: PluginOpCallId, output: O::Output) {
+        self.resolve(id, O::into_result(output));
+    }
+
+    /// Resolves or rejects the call depending on `result`.
+    pub fn complete(&self, id: PluginOpCallId, result: PluginCallOutcome<P>) {
+        match result {
+            Ok(ok) => self.resolve(id, ok),
+            Err(err) => self.reject(id, err),
+        }
+    }
+
+    /// Answers every pending call with `result`, the runtime keeps running.
+    pub fn broadcast(&self, result: PluginCallOutcome<P>) {
+        self.send_result(ResultTarget::Broadcast, result);
+    }
+
+    /// Sends `result` to the callers subscribed to `topic` through `Plugin::subscribe`, if any.
+    /// Pending calls aren't answered, and the subscriptions stay open for later publications.
+    pub fn publish<T: Into<String>>(&self, topic: T, result: PluginCallOutcome<P>) {
+        self.publish_result(topic.into(), result.into());
+    }
+
+    /// Like `broadcast`, every caller gets the one shared payload instead of a copy of it.
+    pub fn broadcast_shared(&self, result: SharedCallOutcome<P>) where <P::PluginCallResult as PluginCallResult>::Ok: Sync {
+        self.send_shared(ResultTarget::Broadcast, result);
+    }
+
+    /// Like `publish`, every subscriber gets the one shared payload instead of a copy of it.
+    pub fn publish_shared<T: Into<String>>(&self, topic: T, result: SharedCallOutcome<P>) where <P::PluginCallResult as PluginCallResult>::Ok: Sync {
+        self.publish_result(topic.into(), shared_result(result));
+    }
+
+    fn publish_result(&self, topic: String, result: RuntimeResult<P::PluginCallResult>) {
+        trace_call!("result published to topic {}", topic);
+        if let Err(e) = self.result_sender.send(RuntimeMessage::Publish(topic, result)) {
+            eprintln!("{}", e);
+        }
+    }
+
+    /// Answers every pending call with `result` and stops the result loop.
+    pub fn shutdown(&self, result: PluginCallOutcome<P>) {
+        self.send_result(ResultTarget::Shutdown, result);
+    }
+
+    fn send_result(&self, target: ResultTarget, result: PluginCallOutcome<P>) {
+        self.send_runtime_result(target, result.into());
+    }
+
+    fn send_shared(&self, target: ResultTarget, result: SharedCallOutcome<P>) where <P::PluginCallResult as PluginCallResult>::Ok: Sync {
+        self.send_runtime_result(target, shared_result(result));
+    }
+
+    fn send_runtime_result(&self, target: ResultTarget, result: RuntimeResult<P::PluginCallResult>) {
+        if let Err(e) = self.result_sender.send(RuntimeMessage::Result(PluginOpCallResult { target, result })) {
+            eprintln!("{}", e);
+        }
+    }
+
+    /// Whether the caller gave up on this call, long running handlers can poll it to stop early.
+    /// Resolving or rejecting a cancelled call is harmless, the result is simply dropped.
+    pub fn is_cancelled(&self, id: PluginOpCallId) -> bool {
+        self.cancellations.lock().unwrap().contains(&id)
+    }
+
+    pub fn fail<T: ToString>(&self, plugin: &P, reason: T) {
+        let name = plugin.name();
+        let error = PluginError::RuntimeError(format!("{} failed permanently: {}", name, reason.to_string()));
+        if let Err(e) = self.result_sender.send(RuntimeMessage::Failed(name, error)) {
+            eprintln!("{}", e);
+        }
+    }
+
+    /// Sends one item of a streamed result without completing the call, the caller sees it
+    /// through `Plugin::execute_stream`. Finish the stream with `end`, `resolve` or `reject`.
+    /// Blocks while the caller has `max_buffered` items left to read.
+    pub fn push<T: Into<<P::PluginCallResult as PluginCallResult>::Ok>>(&self, id: PluginOpCallId, item: T) {
+        if let Some(backlog) = self.backlog(id) {
+            backlog.admit(true);
+        }
+        self.send_item(id, item);
+    }
+
+    /// Like `push`, but hands `item` back instead of blocking when the caller has `max_buffered`
+    /// items left to read, so the event loop can slow down or drop items for a lagging caller.
+    pub fn try_push<T: Into<<P::PluginCallResult as PluginCallResult>::Ok>>(&self, id: PluginOpCallId, item: T) -> Result<(), T> {
+        if let Some(backlog) = self.backlog(id) {
+            if !backlog.admit(false) {
+                return Err(item);
+            }
+        }
+        self.send_item(id, item);
+        Ok(())
+    }
+
+    fn backlog(&self, id: PluginOpCallId) -> Option<Arc<Backlog>> {
+        self.subscribers.with(&id, |subscriber| match subscriber.sender {
+            ResultSender::Stream(_, ref backlog) => Some(backlog.clone()),
+            _ => None,
+        }).flatten()
+    }
+
+    fn send_item<T: Into<<P::PluginCallResult as PluginCallResult>::Ok>>(&self, id: PluginOpCallId, item: T) {
+        trace_call!("call {} streamed an item", id);
+        if let Err(e) = self.result_sender.send(RuntimeMessage::Item(id, Ok(item.into()))) {
+            eprintln!("{}", e);
+        }
+    }
+
+    /// Reports how far along a call is, `fraction` is clamped to `0.0..=1.0` and ignored when it
+    /// is NaN. Progress reported once the call completed or was cancelled is dropped.
+    pub fn progress(&self, id: PluginOpCallId, fraction: f32) {
+        if fraction.is_nan() {
+            return;
+        }
+        if let Err(e) = self.result_sender.send(RuntimeMessage::Progress(id, fraction.clamp(0.0, 1.0))) {
+            eprintln!("{}", e);
+        }
+    }
+
+    /// Completes a streamed call without a final item.
+    pub fn end(&self, id: PluginOpCallId) {
+        trace_call!("call {} ended", id);
+        self.cancellations.lock().unwrap().remove(&id);
+        if let Err(e) = self.result_sender.send(RuntimeMessage::End(id)) {
+            eprintln!("{}", e);
+        }
+    }
+
+    /// Runs `handler` on `call` and completes the call with what it returns. If the handler
+    /// panics, the call fails with a `RuntimeError` instead of the panic unwinding through the
+    /// event loop, and the runtime's `on_panic` policy decides what happens to the plugin.
+    pub fn serve<F: FnMut(&PluginOpCall<P>) -> PluginCallOutcome<P>>(&self, call: PluginOpCall<P>, mut handler: F) {
+        let panic = match catch_unwind(AssertUnwindSafe(|| handler(&call))) {
+            Ok(result) => return self.complete(call.id(), result),
+            Err(panic) => panic_reason(panic),
+        };
+        self.error(call.id(), PluginError::RuntimeError(format!("handler panicked: {}", panic)));
+        if let OnPanic::Reload { max_reloads, window } = self.on_panic {
+            self.reload(call.plugin(), handler, max_reloads, window, panic);
+        }
+    }
+
+    fn reload<F: FnMut(&PluginOpCall<P>) -> PluginCallOutcome<P>>(&self, plugin: &P, mut handler: F, max_reloads: usize, window: Duration, panic: String) {
+        let loading_call = match self.reloader {
+            Some(ref reloader) => reloader(plugin.clone()),
+            None => return,
+        };
+        let now = Instant::now();
+        let too_often = {
+            let mut reloads = self.reloads.lock().unwrap();
+            let reloads = reloads.entry(plugin.name()).or_default();
+            while reloads.front().is_some_and(|reloaded| now.duration_since(*reloaded) > window) {
+                reloads.pop_front();
+            }
+            reloads.push_back(now);
+            reloads.len() > max_reloads
+        };
+        if too_often {
+            return self.fail(plugin, format!("panicked more than {} times within {:?}, last: {}", max_reloads, window, panic));
+        }
+        let reload = PluginOpCall {
+            plugin_data: plugin.clone(),
+            call_id: Uuid::new_v4(),
+            call: loading_call.into(),
+            headers: HashMap::new(),
+            deadline: None,
+            deferred: 0,
+        };
+        match catch_unwind(AssertUnwindSafe(|| handler(&reload))) {
+            Ok(Ok(_)) => {}
+            Ok(Err(_)) => self.fail(plugin, "reload after a panic was rejected"),
+            Err(panic) => self.fail(plugin, format!("panicked while reloading: {}", panic_reason(panic))),
+        }
+    }
+
+    /// Puts `call` back at the end of the call queue, for a call the loop can't serve yet, e.g.
+    /// while a dependency comes up or a rate limit applies, rather than rejecting it. A call
+    /// already deferred `max_defers` times fails with a `RuntimeError` instead, and one deferred
+    /// after the runtime stopped fails with `Disconnected`; either way the error is returned too.
+    /// A call the caller cancelled is dropped.
+    pub fn defer(&self, mut call: PluginOpCall<P>) -> PluginResult<()> {
+        let id = call.id();
+        if self.cancellations.lock().unwrap().remove(&id) {
+            return Ok(());
+        }
+        if call.deferred >= self.max_defers {
+            let error = PluginError::RuntimeError(format!("call deferred more than {} times", self.max_defers));
+            self.error(id, error.clone());
+            return Err(error);
+        }
+        call.deferred += 1;
+        trace_call!("call {} deferred", id);
+        let requeued = match *self.requeue.lock().unwrap() {
+            Some((ref call_sender, ref wake_sender)) => {
+                if let Some(ref queue_depth) = self.queue_depth {
+                    queue_depth.fetch_add(1, Ordering::SeqCst);
+                }
+                let sent = call_sender.send(call).is_ok();
+                if let (Some(queue_depth), false) = (&self.queue_depth, sent) {
+                    queue_depth.fetch_sub(1, Ordering::SeqCst);
+                }
+                sent && wake_sender.as_ref().map_or(true, |wake_sender| wake_sender.send(()).is_ok())
+            }
+            None => false,
+        };
+        if !requeued {
+            self.error(id, PluginError::Disconnected);
+            return Err(PluginError::Disconnected);
+        }
+        Ok(())
+    }
+
+    /// Why the runtime stopped, for an event loop whose `receive` failed with `Disconnected`
+    /// to clean up accordingly. `None` while the runtime runs.
+    pub fn shutdown_reason(&self) -> Option<ShutdownReason> {
+        self.stop_reason.lock().ok()?.clone()
+    }
+
+    /// Fails the call with a runtime error rather than the plugin's own.
+    pub(crate) fn error(&self, id: PluginOpCallId, error: PluginError) {
+        trace_call!("call {} errored", id);
+        self.cancellations.lock().unwrap().remove(&id);
+        if let Err(e) = self.result_sender.send(RuntimeMessage::Errored(id, error)) {
+            eprintln!("{}", e);
+        }
+    }
+
+    /// Fails every call still waiting for a result with `error`.
+    pub(crate) fn abort_pending(&self, error: PluginError) {
+        if let Err(e) = self.result_sender.send(RuntimeMessage::Aborted(error)) {
+            eprintln!("{}", e);
+        }
+    }
+
+    pub fn receive(&self) -> Result<PluginOpCall<P>, ReceiveError> {
+        self.receive_within(Wait::Forever)?.ok_or(ReceiveError::Disconnected)
+    }
+
+    /// Takes the next call if one is already queued, without blocking.
+    pub fn try_receive(&self) -> Result<Option<PluginOpCall<P>>, ReceiveError> {
+        self.receive_within(Wait::Never)
+    }
+
+    /// Collects up to `max` calls in FIFO order. Waits at most `wait` for the first call, then
+    /// only takes the calls that are already queued. Returns an empty batch when `wait` elapses,
+    /// and `Disconnected` only when nothing was received and the runtime is gone.
+    pub fn receive_batch(&self, max: usize, wait: Duration) -> Result<Vec<PluginOpCall<P>>, ReceiveError> {
+        let mut batch = Vec::new();
+        if max == 0 {
+            return Ok(batch);
+        }
+        match self.receive_within(Wait::For(wait))? {
+            Some(call) => batch.push(call),
+            None => return Ok(batch),
+        }
+        while batch.len() < max {
+            match self.receive_within(Wait::Never) {
+                Ok(Some(call)) => batch.push(call),
+                _ => break,
+            }
+        }
+        Ok(batch)
+    }
+
+    fn receive_within(&self, wait: Wait) -> Result<Option<PluginOpCall<P>>, ReceiveError> {
+        fn take<T>(receiver: &Receiver<T>, wait: Wait) -> Result<Option<T>, ReceiveError> {
+            let received = match wait {
+                Wait::Forever => receiver.recv().map_err(|_| true),
+                Wait::For(wait) => receiver.recv_timeout(wait).map_err(|e| matches!(e, RecvTimeoutError::Disconnected)),
+                Wait::Never => receiver.try_recv().map_err(|e| matches!(e, TryRecvError::Disconnected)),
+            };
+            match received {
+                Ok(value) => Ok(Some(value)),
+                Err(true) => Err(ReceiveError::Disconnected),
+                Err(false) => Ok(None),
+            }
+        }
+        let call = match (&self.lanes, &self.fair) {
+            (Some((wake, lanes)), _) => {
+                let wake = wake.lock().map_err(|_| ReceiveError::Poisoned)?;
+                if take(&wake, wait)?.is_none() {
+                    return Ok(None);
+                }
+                let mut lanes = lanes.lock().map_err(|_| ReceiveError::Poisoned)?;
+                lanes.next().ok_or(ReceiveError::Disconnected)?
+            }
+            (None, Some(fair)) => {
+                let mut fair = fair.lock().map_err(|_| ReceiveError::Poisoned)?;
+                let receiver = self.call_receiver.lock().map_err(|_| ReceiveError::Poisoned)?;
+                fair.fill(&receiver);
+                if fair.is_empty() {
+                    match take(&receiver, wait)? {
+                        Some(call) => fair.push(call),
+                        None => return Ok(None),
+                    }
+                    fair.fill(&receiver);
+                }
+                fair.pop().ok_or(ReceiveError::Disconnected)?
+            }
+            (None, None) => {
+                let receiver = self.call_receiver.lock().map_err(|_| ReceiveError::Poisoned)?;
+                match take(&receiver, wait)? {
+                    Some(call) => call,
+                    None => return Ok(None),
+                }
+            }
+        };
+        trace_call!("call {} received by event loop for {}", call.call_id, call.plugin_data.name_ref());
+        let waited = self.subscribers.received(&call.call_id);
+        if let (Some(queue_waits), Some(waited)) = (&self.queue_waits, waited) {
+            let mut queue_waits = queue_waits.lock().unwrap();
+            if queue_waits.len() == QUEUE_WAIT_SAMPLES {
+                queue_waits.pop_front();
+            }
+            queue_waits.push_back(waited);
+        }
+        if let Some(ref queue_depth) = self.queue_depth {
+            queue_depth.fetch_sub(1, Ordering::SeqCst);
+        }
+        Ok(Some(call))
+    }
+}
+
+impl<P: PluginData> PluginRuntime<P> where P::PluginCallResult: 'static + PluginCallResult,  P::PluginCall: 'static + Send {
+    pub fn run(&mut self) -> (impl Future<Output=()> + Send, Handle<P>) where P: 'static {
+        let (result_receiver, result_loop, handle) = self.start();
+        let batch_drain = self.batch_drain;
+        (async move {
+            result_loop.run(result_receiver, batch_drain);
+        }, handle)
+    }
+
+    /// Like `run`, with the result loop on a thread of its own instead of a future, so result
+    /// delivery never waits for an executor to schedule it. The thread exits once a shutdown
+    /// is delivered, or once the runtime, its plugins and every `Handle` clone are dropped;
+    /// join it after that to be sure every result was delivered.
+    pub fn run_threaded(&mut self) -> PluginResult<(JoinHandle<()>, Handle<P>)> where P: 'static {
+        let (result_receiver, result_loop, handle) = self.start();
+        let batch_drain = self.batch_drain;
+        let thread = std::thread::Builder::new()
+            .name("plao-results".to_string())
+            .spawn(move || result_loop.run(result_receiver, batch_drain))
+            .map_err(|e| PluginError::RuntimeError(e.to_string()))?;
+        Ok((thread, handle))
+    }
+
+    /// Runs both loops on threads of their own, for hosts without an async executor: the
+    /// result loop as in `run_threaded` and `event_loop` on a second thread. The event loop
+    /// thread ends when `event_loop` returns, typically once `Handle::receive` reports the
+    /// runtime gone after the runtime and its plugins are dropped; see `RunningHandles::join`.
+    pub fn run_blocking<F, R>(&mut self, event_loop: F) -> PluginResult<RunningHandles<R>>
+        where F: 'static + Send + FnOnce(Handle<P>) -> R, R: 'static + Send, P: 'static {
+        let (result_loop, handle) = self.run_threaded()?;
+        let event_loop = std::thread::Builder::new()
+            .name("plao-events".to_string())
+            .spawn(move || event_loop(handle))
+            .map_err(|e| PluginError::RuntimeError(e.to_string()))?;
+        Ok(RunningHandles { result_loop, event_loop })
+    }
+
+    /// Like `run`, but results are only delivered when the returned `ResultStepper` is stepped,
+    /// so a single-threaded test decides exactly when each result reaches its caller.
+    #[cfg(feature = "testing")]
+    pub fn run_manual(&mut self) -> (crate::testing::ResultStepper<P>, Handle<P>) where P: 'static {
+        let (result_receiver, result_loop, handle) = self.start();
+        (crate::testing::ResultStepper::new(result_receiver, result_loop), handle)
+    }
+
+    fn start(&mut self) -> (Receiver<RuntimeMessage<P::PluginCallResult>>, ResultLoop<P>, Handle<P>) where P: 'static {
+        let (call_sender, call_receiver) = channel();
+        let (result_sender, result_receiver) = channel();
+        self.call_sender = Some(call_sender);
+        let lanes = if self.dedicated_channels {
+            let (wake_sender, wake_receiver) = channel();
+            let lanes = Arc::new(Mutex::new(RoundRobin {
+                receivers: Vec::new(),
+                cursor: 0,
+            }));
+            self.lanes = Some((wake_sender, lanes.clone()));
+            Some((Arc::new(Mutex::new(wake_receiver)), lanes))
+        } else {
+            None
+        };
+        self.subscribers.replace(Arc::new(SubscriberMap::with_shards(self.subscriber_shards, self.subscriber_capacity)));
+        self.queue_depth = if self.track_queue_depth { Some(Arc::new(AtomicUsize::new(0))) } else { None };
+        self.queue_waits = if self.track_queue_wait { Some(Default::default()) } else { None };
+        let requeue = match self.lanes {
+            Some((ref wake_sender, ref lanes)) => {
+                let (requeue_sender, requeue_receiver) = channel();
+                lanes.lock().unwrap().receivers.push(requeue_receiver);
+                (requeue_sender, Some(wake_sender.clone()))
+            }
+            None => (self.call_sender.clone().unwrap(), None),
+        };
+        *self.requeue.lock().unwrap() = Some(requeue);
+        let reloader = self.plugin_loader.take().map(|loader| -> Arc<LoaderFn<P>> { Arc::from(loader) });
+        if let Some(ref reloader) = reloader {
+            let reloader = reloader.clone();
+            self.plugin_loader = Some(Box::new(move |plugin| reloader(plugin)));
+        }
+        let handle = Handle {
+            result_sender: result_sender.clone(),
+            call_receiver: Arc::new(Mutex::new(call_receiver)),
+            lanes,
+            fair: if self.fair_scheduling { Some(Arc::new(Mutex::new(FairQueue::new()))) } else { None },
+            cancellations: self.cancellations.clone(),
+            subscribers: self.subscribers.clone().unwrap(),
+            stop_reason: self.stop_reason.clone(),
+            on_panic: self.on_panic,
+            reloader,
+            reloads: Default::default(),
+            queue_depth: self.queue_depth.clone(),
+            queue_waits: self.queue_waits.clone(),
+            requeue: self.requeue.clone(),
+            max_defers: self.max_defers,
+        };
+        self.result_sender.replace(result_sender);
+        let result_loop = ResultLoop::<P> {
+            subscribers: self.subscribers.clone().unwrap(),
+            topics: self.topics.clone(),
+            failed: self.failed.clone(),
+            failure_hooks: self.failure_hooks.clone(),
+            metrics: self.metrics.clone(),
+        };
+        (result_receiver, result_loop, handle)
+    }
+
+    /// Like `run`, also handing the event loop a watch on `config` that `reconfigure` updates.
+    pub fn run_configured<C: 'static + Send + Sync>(&mut self, config: C) -> (impl Future<Output=()> + Send, Handle<P>, ConfigWatch<C>) where P: 'static {
+        let watch = ConfigWatch::new(config);
+        self.config = Some(Box::new(watch.clone()));
+        let (result_loop, handle) = self.run();
+        (result_loop, handle, watch)
+    }
+
+    /// Pushes `config` to the event loop's `ConfigWatch`. Fails if the runtime wasn't run with
+    /// `run_configured`, or with a configuration of another type.
+    pub fn reconfigure<C: 'static + Send + Sync>(&self, config: C) -> PluginResult<()> {
+        let watch = self.config.as_ref()
+            .ok_or_else(|| PluginError::RuntimeError("runtime was not run with a configuration".to_string()))?;
+        let watch = watch.downcast_ref::<ConfigWatch<C>>()
+            .ok_or_else(|| PluginError::RuntimeError(format!("configuration is not a {}", std::any::type_name::<C>())))?;
+        watch.publish(config);
+        Ok(())
+    }
+
+    /// Runs the runtime and hands the result loop and the event loop to `spawn` as boxed futures,
+    /// so any executor can drive them, e.g. `|fut| { async_std::task::spawn(fut); }` or
+    /// `|fut| smol::spawn(fut).detach()`. Both loops block on std channels while idle, so prefer
+    /// spawning onto threads that may block.
+    pub fn run_with<S, F, R>(&mut self, mut spawn: S, event_loop: F)
+        where S: FnMut(Pin<Box<dyn Future<Output=()> + Send>>), F: 'static + Send + FnOnce(Handle<P>) -> R, P: 'static {
+        let (result_loop, handle) = self.run();
+        spawn(Box::pin(result_loop));
+        spawn(Box::pin(async move {
+            event_loop(handle);
+        }));
+    }
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_options(options: RuntimeOptions<P>) -> Self {
+        PluginRuntime {
+            plugin_loader: options.plugin_loader,
+            plugin_unloader: options.plugin_unloader,
+            subscriber_capacity: options.subscriber_capacity,
+            subscriber_shards: options.subscriber_shards,
+            batch_drain: options.batch_drain,
+            dedicated_channels: options.dedicated_channels,
+            fair_scheduling: options.fair_scheduling,
+            max_pending: options.max_pending,
+            full_queue: options.full_queue,
+            max_buffered: options.max_buffered,
+            on_panic: options.on_panic,
+            track_queue_depth: options.track_queue_depth,
+            track_queue_wait: options.track_queue_wait,
+            max_defers: options.max_defers,
+            #[cfg(feature = "tokio")]
+            tokio_handle: None,
+            result_sender: None,
+            call_sender: None,
+            lanes: None,
+            subscribers: None,
+            topics: Default::default(),
+            failed: Default::default(),
+            failure_hooks: Default::default(),
+            metrics: Default::default(),
+            cancellations: Default::default(),
+            draining: Default::default(),
+            config: None,
+            pending: Default::default(),
+            stop_reason: Default::default(),
+            queue_depth: None,
+            queue_waits: None,
+            requeue: Default::default(),
+        }
+    }
+
+    #[cfg(feature = "tokio")]
+    pub fn with_existing_tokio_handle(mut self, tokio_handle: tokio::runtime::Handle) -> Self {
+        self.tokio_handle = Some(tokio_handle);
+        self
+    }
+
+    /// Runs the runtime and spawns both loops on the tokio handle given to
+    /// `with_existing_tokio_handle`. The event loop and the result loop block on channels, so
+    /// both go to the blocking pool. They finish once the runtime and every plugin handle are
+    /// dropped; shutting the tokio runtime down waits for them, so drop those first.
+    #[cfg(feature = "tokio")]
+    pub fn spawn<F, R>(&mut self, event_loop: F) -> PluginResult<RuntimeTasks<R>>
+        where F: 'static + Send + FnOnce(Handle<P>) -> R, R: 'static + Send, P: 'static {
+        let tokio_handle = match self.tokio_handle.clone() {
+            Some(tokio_handle) => tokio_handle,
+            None => return Err(PluginError::RuntimeError("no tokio handle configured".to_string())),
+        };
+        let (result_loop, handle) = self.run();
+        let blocking_handle = tokio_handle.clone();
+        Ok(RuntimeTasks {
+            result_loop: tokio_handle.spawn_blocking(move || blocking_handle.block_on(result_loop)),
+            event_loop: tokio_handle.spawn_blocking(move || event_loop(handle)),
+        })
+    }
+
+    /// Runs the runtime and hands both loops to `executor`, for hosts that don't use tokio or
+    /// want the loops on a pool of their own. Like `spawn`, they finish once the runtime and
+    /// every plugin handle are dropped.
+    pub fn spawn_with<E, F, R>(&mut self, executor: &E, event_loop: F) -> LoopTasks<R>
+        where E: Executor + ?Sized, F: 'static + Send + FnOnce(Handle<P>) -> R, R: 'static + Send, P: 'static {
+        let (result_receiver, result_loop, handle) = self.start();
+        let batch_drain = self.batch_drain;
+        LoopTasks {
+            result_loop: Task::spawn(executor, move || result_loop.run(result_receiver, batch_drain)),
+            event_loop: Task::spawn(executor, move || event_loop(handle)),
+        }
+    }
+
+    pub fn on_failure<F: 'static + Send + Fn(&str, &PluginError)>(&self, hook: F) {
+        self.failure_hooks.lock().unwrap().push(Box::new(hook));
+    }
+
+    pub fn metrics(&self) -> HashMap<String, PluginMetrics> {
+        self.metrics.lock().unwrap().clone()
+    }
+
+    /// Stops accepting calls and waits up to `drain_timeout` for the pending ones to complete,
+    /// then fails whatever is left with `Timeout` and returns how many calls that was.
+    ///
+    /// New calls fail with `Disconnected` and the runtime can't load plugins anymore. The event
+    /// loop exits once the plugins handed out are dropped, the result loop right after it.
+    pub fn drain(&mut self, drain_timeout: Duration) -> usize {
+        self.stopping(ShutdownReason::Planned);
+        self.draining.store(true, Ordering::SeqCst);
+        let started = Instant::now();
+        let mut remaining = self.inflight_ids().len();
+        while remaining > 0 && started.elapsed() < drain_timeout {
+            std::thread::sleep(Duration::from_millis(1));
+            remaining = self.inflight_ids().len();
+        }
+        if remaining > 0 {
+            if let Some(ref result_sender) = self.result_sender {
+                if let Err(e) = result_sender.send(RuntimeMessage::Aborted(PluginError::Timeout(drain_timeout))) {
+                    eprintln!("{}", e);
+                }
+            }
+        }
+        self.call_sender.take();
+        self.lanes.take();
+        self.requeue.lock().unwrap().take();
+        remaining
+    }
+
+    /// Stops handing calls to the event loop and tells it why through `Handle::shutdown_reason`.
+    /// Its `receive` fails with `Disconnected` once the plugins handed out are dropped too.
+    /// Only the first reason given sticks, dropping the runtime gives `Planned`.
+    pub fn shutdown_with(&mut self, reason: ShutdownReason) {
+        self.stopping(reason);
+        self.call_sender.take();
+        self.lanes.take();
+        self.requeue.lock().unwrap().take();
+    }
+
+    fn stopping(&self, reason: ShutdownReason) {
+        if let Ok(mut stop_reason) = self.stop_reason.lock() {
+            stop_reason.get_or_insert(reason);
+        }
+    }
+
+    /// How many calls were sent but not yet received by the event loop, as opposed to the
+    /// calls in flight, which also count those being handled, see `inflight_ids`. `None` unless
+    /// the runtime was built with `track_queue_depth` and run.
+    pub fn queue_depth(&self) -> Option<usize> {
+        self.queue_depth.as_ref().map(|queue_depth| queue_depth.load(Ordering::SeqCst))
+    }
+
+    /// Percentiles of how long the latest `QUEUE_WAIT_SAMPLES` calls waited before an event loop
+    /// took them. Unlike `PluginMetrics::avg_latency` it leaves out the time spent handling
+    /// calls, so waits growing while handling stays flat point at too few event loops. Calls
+    /// taken again after `Handle::defer` only count their first wait. `None` unless the runtime
+    /// was built with `track_queue_wait` and an event loop took a call.
+    pub fn queue_wait_stats(&self) -> Option<QueueWaitStats> {
+        let mut waits: Vec<Duration> = self.queue_waits.as_ref()?.lock().unwrap().iter().copied().collect();
+        if waits.is_empty() {
+            return None;
+        }
+        waits.sort();
+        let percentile = |percent: usize| waits[(waits.len() - 1) * percent / 100];
+        Some(QueueWaitStats {
+            samples: waits.len(),
+            p50: percentile(50),
+            p90: percentile(90),
+            p99: percentile(99),
+            max: waits[waits.len() - 1],
+        })
+    }
+
+    /// Ids of the calls still waiting for a result, empty until the runtime is run.
+    pub fn inflight_ids(&self) -> Vec<PluginOpCallId> {
+        self.subscribers.as_ref().map(|subscribers| subscribers.ids()).unwrap_or_default()
+    }
+
+    /// How long each call still waiting for a result has been pending, to spot stuck calls.
+    /// Empty until the runtime is run.
+    pub fn subscriber_ages(&self) -> Vec<(PluginOpCallId, Duration)> {
+        self.subscribers.as_ref().map(|subscribers| subscribers.ages()).unwrap_or_default()
+    }
+
+    pub fn into_shared(self) -> SharedRuntime<P> {
+        SharedRuntime {
+            runtime: Arc::new(self),
+        }
+    }
+
+    pub fn load_plugin(
+        &self,
+        plugin: P,
+    ) -> PluginResult<Plugin<P>> {
+        let loading_call = self.plugin_loader.as_ref().map(|loader| loader(plugin.clone()));
+        self.load_with_call(plugin, loading_call)
+    }
+
+    pub fn load_plugin_with(&self, plugin: P, init: P::InitParams) -> PluginResult<Plugin<P>> where P: PluginInit {
+        let loading_call = plugin.init_call(init);
+        self.load_with_call(plugin, Some(loading_call))
+    }
+
+    /// Sends the unload call built by `plugin_unloader`, if one is configured.
+    pub fn unload_plugin(&self, plugin: &Plugin<P>) -> PluginResult<()> {
+        self.send_unload(plugin, None)
+    }
+
+    /// Like `unload_plugin`, giving up on the unload call with `Timeout` after `timeout`.
+    pub fn unload_plugin_within(&self, plugin: &Plugin<P>, timeout: Duration) -> PluginResult<()> {
+        self.send_unload(plugin, Some(timeout))
+    }
+
+    fn send_unload(&self, plugin: &Plugin<P>, timeout: Option<Duration>) -> PluginResult<()> {
+        let unloader = match self.plugin_unloader {
+            Some(ref unloader) => unloader,
+            None => return Ok(()),
+        };
+        let unload_call = unloader(plugin.plugin_data.clone());
+        let result = match timeout {
+            Some(timeout) => plugin.execute_timeout(unload_call, timeout),
+            None => plugin.execute(unload_call),
+        };
+        result.and_then(|result| match result {
+            Ok(_) => Ok(()),
+            Err(e) => Err(PluginError::RuntimeError(e.to_string())),
+        })
+    }
+
+    fn load_with_call(&self, plugin: P, loading_call: Option<P::PluginCall>) -> PluginResult<Plugin<P>> {
+        if self.call_sender.is_none() {
+            return Err(PluginError::FailedToLoad("run runtime first".to_string(), None))
+        }
+        if self.subscribers.is_none() {
+            return Err(PluginError::FailedToLoad("run runtime first".to_string(), None))
+        }
+        self.failed.lock().unwrap().remove(plugin.name_ref().as_ref());
+        let (call_sender, wake_sender) = match self.lanes {
+            Some((ref wake_sender, ref lanes)) => {
+                let (call_sender, call_receiver) = channel();
+                lanes.lock().unwrap().receivers.push(call_receiver);
+                (call_sender, Some(wake_sender.clone()))
+            }
+            None => (self.call_sender.clone().unwrap(), None),
+        };
+        let pl = Plugin {
+            plugin_data: plugin,
+            call_sender,
+            wake_sender,
+            subscribers: self.subscribers.clone().unwrap(),
+            topics: self.topics.clone(),
+            failed: self.failed.clone(),
+            metrics: self.metrics.clone(),
+            cancellations: self.cancellations.clone(),
+            draining: self.draining.clone(),
+            default_timeout: None,
+            pending_limit: self.max_pending.map(|max| PendingLimit {
+                max,
+                policy: self.full_queue,
+                count: self.pending.clone(),
+            }),
+            max_buffered: self.max_buffered,
+            observer: None,
+            queue_depth: self.queue_depth.clone(),
+        };
+        let loading_call = match loading_call {
+            Some(loading_call) => loading_call,
+            None => return Ok(pl),
+        };
+        pl.execute(loading_call).and_then(|result| match result {
+            Ok(_) => Ok(pl),
+            Err(e) => Err(PluginError::FailedToLoad(e.to_string(), Some(LoadCause::new(e))))
+        })
+    }
+}
+
+#[cfg(feature = "tokio")]
+pub struct RuntimeTasks<R> {
+    pub result_loop: tokio::task::JoinHandle<()>,
+    pub event_loop: tokio::task::JoinHandle<R>,
+}
+
+/// Threads started by `PluginRuntime::run_blocking`.
+pub struct RunningHandles<R> {
+    pub result_loop: JoinHandle<()>,
+    pub event_loop: JoinHandle<R>,
+}
+
+impl<R> RunningHandles<R> {
+    /// Waits for the event loop, then for the result loop, so every result the event loop sent
+    /// was delivered by the time it returns. Blocks until the runtime and its plugins are
+    /// dropped unless the event loop returns on its own. Errs with the event loop's panic if it
+    /// panicked, or else with the result loop's.
+    pub fn join(self) -> std::thread::Result<R> {
+        let outcome = self.event_loop.join();
+        let delivered = self.result_loop.join();
+        outcome.and_then(|outcome| delivered.map(|_| outcome))
+    }
+}
+
+pub(crate) struct ResultLoop<P: PluginData> {
+    subscribers: Subscribers<P>,
+    topics: Topics<P>,
+    failed: FailedPlugins,
+    failure_hooks: FailureHooks,
+    metrics: Metrics,
+}
+
+impl<P: PluginData> ResultLoop<P> {
+    /// Handles a batch of messages in order, returns `false` once the loop should stop.
+    pub(crate) fn process(&self, batch: Vec<RuntimeMessage<P::PluginCallResult>>) -> bool {
+        let mut pending = Vec::new();
+        for message in batch {
+            match message {
+                RuntimeMessage::Result(res) => match res.target {
+                    ResultTarget::One(id) => pending.push((id, res.result)),
+                    ResultTarget::Broadcast => {
+                        self.deliver(std::mem::take(&mut pending));
+                        self.broadcast(res.result);
+                    }
+                    ResultTarget::Shutdown => {
+                        self.deliver(std::mem::take(&mut pending));
+                        self.broadcast(res.result);
+                        self.topics.clear();
+                        return false;
+                    }
+                },
+                RuntimeMessage::Failed(name, error) => {
+                    self.deliver(std::mem::take(&mut pending));
+                    self.fail(name, error);
+                }
+                RuntimeMessage::Aborted(error) => {
+                    self.deliver(std::mem::take(&mut pending));
+                    self.abort(error);
+                }
+                RuntimeMessage::Item(id, result) => {
+                    self.deliver(std::mem::take(&mut pending));
+                    self.deliver_item(id, result);
+                }
+                RuntimeMessage::Errored(id, error) => {
+                    self.deliver(std::mem::take(&mut pending));
+                    self.error(id, error);
+                }
+                RuntimeMessage::End(id) => {
+                    self.deliver(std::mem::take(&mut pending));
+                    self.subscribers.remove(&id);
+                }
+                RuntimeMessage::Progress(id, fraction) => {
+                    self.deliver(std::mem::take(&mut pending));
+                    let progress = self.subscribers.with(&id, |subscriber| subscriber.progress.clone()).flatten();
+                    if let Some(progress) = progress {
+                        progress(fraction);
+                    }
+                }
+                RuntimeMessage::Publish(topic, result) => {
+                    self.deliver(std::mem::take(&mut pending));
+                    self.topics.publish(&topic, result);
+                }
+            }
+        }
+        self.deliver(pending);
+        true
+    }
+
+    fn run(&self, result_receiver: Receiver<RuntimeMessage<P::PluginCallResult>>, batch_drain: bool) {
+        loop {
+            let res = result_receiver.recv();
+            if let Err(e) = res {
+                eprintln!("{}", e);
+                break;
+            }
+            let mut batch = vec![res.unwrap()];
+            if batch_drain {
+                batch.extend(result_receiver.try_iter());
+            }
+            if !self.process(batch) {
+                break;
+            }
+        }
+        self.topics.clear();
+    }
+
+    fn error(&self, id: PluginOpCallId, error: PluginError) {
+        let subscriber = match self.subscribers.remove(&id) {
+            Some(subscriber) => subscriber,
+            None => return eprintln!("dropping error for unknown call {}", id),
+        };
+        let mut metrics = self.metrics.lock().unwrap();
+        let plugin_metrics = metrics.entry(subscriber.plugin.clone()).or_default();
+        plugin_metrics.rejected += 1;
+        plugin_metrics.record_latency(subscriber.since.elapsed());
+        drop(metrics);
+        if !subscriber.complete(id, Err(error)) {
+            eprintln!("caller of {} is gone, dropping error", id);
+        }
+    }
+
+    fn fail(&self, name: String, error: PluginError) {
+        self.failed.lock().unwrap().insert(name.clone(), error.clone());
+        self.subscribers.retain(|_id, subscriber| subscriber.plugin != name);
+        self.topics.remove_plugin(&name);
+        for hook in self.failure_hooks.lock().unwrap().iter() {
+            hook(&name, &error);
+        }
+    }
+
+    fn abort(&self, error: PluginError) {
+        for (id, subscriber) in self.subscribers.drain() {
+            if !subscriber.complete(id, Err(error.clone())) {
+                eprintln!("caller of {} is gone, dropping abort", id);
+            }
+        }
+    }
+
+    fn broadcast(&self, result: RuntimeResult<P::PluginCallResult>) {
+        for (id, subscriber) in self.subscribers.drain() {
+            if !subscriber.complete(id, Ok(result.clone())) {
+                eprintln!("caller of {} is gone, dropping broadcast", id);
+                continue;
+            }
+        }
+    }
+
+    fn deliver_item(&self, res_id: PluginOpCallId, result: PluginCallOutcome<P>) {
+        let delivered = self.subscribers.with(&res_id, |subscriber| subscriber.sender.send(res_id, Ok(result.into())));
+        match delivered {
+            Some(true) => {}
+            Some(false) => eprintln!("caller of {} is gone, dropping item", res_id),
+            None => eprintln!("dropping item for unknown call {}", res_id),
+        }
+    }
+
+    fn deliver(&self, results: Vec<(PluginOpCallId, RuntimeResult<P::PluginCallResult>)>) {
+        if results.is_empty() {
+            return;
+        }
+        let ids: Vec<_> = results.iter().map(|(id, _)| *id).collect();
+        let subscribers = self.subscribers.remove_many(&ids);
+        let mut metrics = self.metrics.lock().unwrap();
+        for ((res_id, result), subscriber) in results.into_iter().zip(subscribers) {
+            let subscriber = match subscriber {
+                Some(subscriber) => subscriber,
+                None => {
+                    eprintln!("dropping result for unknown call {}", res_id);
+                    continue;
+                }
+            };
+            let plugin_metrics = metrics.entry(subscriber.plugin.clone()).or_default();
+            match result {
+                RuntimeResult::Ok(_) => plugin_metrics.resolved += 1,
+                RuntimeResult::Err(_) => plugin_metrics.rejected += 1,
+            }
+            plugin_metrics.record_latency(subscriber.since.elapsed());
+            if !subscriber.complete(res_id, Ok(result)) {
+                eprintln!("caller of {} is gone, dropping result", res_id);
+                continue;
+            }
+            trace_call!("call {} delivered to caller", res_id);
+        }
+    }
+}
+
+/// Cheaply cloneable view of a running `PluginRuntime`.
+///
+/// Loading only reads the runtime's channels and shares the subscribers map behind its own
+/// mutex, so clones can load plugins and dispatch calls from any thread without extra locking.
+/// The runtime is torn down once the last clone is dropped.
+pub struct SharedRuntime<P: PluginData> {
+    runtime: Arc<PluginRuntime<P>>,
+}
+
+impl<P: PluginData> Clone for SharedRuntime<P> {
+    fn clone(&self) -> Self {
+        SharedRuntime {
+            runtime: self.runtime.clone(),
+        }
+    }
+}
+
+impl<P: PluginData> SharedRuntime<P> where P::PluginCallResult: 'static + PluginCallResult,  P::PluginCall: 'static + Send {
+    pub fn load_plugin(&self, plugin: P) -> PluginResult<Plugin<P>> {
+        self.runtime.load_plugin(plugin)
+    }
+
+    pub fn load_plugin_with(&self, plugin: P, init: P::InitParams) -> PluginResult<Plugin<P>> where P: PluginInit {
+        self.runtime.load_plugin_with(plugin, init)
+    }
+
+    pub fn unload_plugin(&self, plugin: &Plugin<P>) -> PluginResult<()> {
+        self.runtime.unload_plugin(plugin)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::{build_dummy_runtime, dummy_event_loop, load_only_event_loop};
+    use crate::tokio_utils::create_tokio_runtime;
+    use crate::test_utils::{DummyPlugin, DummyResult, HookPlugin, KindPlugin, kind_event_loop, serving_event_loop};
+    use crate::{PluginCallOutcome, PluginError, PluginErrorKind, PluginResult};
+    use crate::runtime::{FullQueue, OnPanic, Payload, PluginRuntime, RuntimeOptions, RuntimeResult, Shared, SharedRuntime, PluginMetrics, ReceiveError, ShutdownReason};
+    use std::time::Duration;
+    use std::sync::mpsc::channel;
+    use crate::subscribers::ResultSender;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    #[test]
+    fn runtime_result_from_result() {
+        let ok = RuntimeResult::<DummyResult>::from(Ok("hello".to_string()));
+        assert!(matches!(&ok, RuntimeResult::Ok(value) if value.as_str() == "hello"));
+        let err = RuntimeResult::<DummyResult>::from(Err("nope".to_string()));
+        assert!(matches!(&err, RuntimeResult::Err(e) if e == "nope"));
+        assert_eq!(Result::from(ok), Ok("hello".to_string()));
+        assert_eq!(Result::from(err), Err("nope".to_string()));
+    }
+
+    #[test]
+    fn shared_results_skip_copies() {
+        let payload = Arc::new("big".to_string());
+        let shared = RuntimeResult::<DummyResult>::Ok(Payload::Shared(Shared::new(payload.clone())));
+        let fanned_out = shared.clone();
+        assert!(Arc::ptr_eq(&fanned_out.into_shared().unwrap(), &payload));
+        assert_eq!(Result::from(shared), Ok("big".to_string()));
+        let owned = RuntimeResult::<DummyResult>::from(Ok("small".to_string()));
+        assert_eq!(owned.into_shared().map(|value| value.as_str().to_string()), Ok("small".to_string()));
+    }
+
+    #[test]
+    fn build_runtime() {
+        let mut dummy_runtime = build_dummy_runtime();
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let handle2 = runtime.spawn(async move {
+            dummy_event_loop(handle)
+        });
+        drop(dummy_runtime);
+        let (_res1, _res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+    }
+
+    #[test]
+    fn shared_runtime_is_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<PluginRuntime<DummyPlugin>>();
+        assert_send_sync::<SharedRuntime<DummyPlugin>>();
+    }
+
+    #[test]
+    fn load_from_two_threads() {
+        let mut dummy_runtime = build_dummy_runtime();
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let handle2 = runtime.spawn(async move {
+            dummy_event_loop(handle)
+        });
+        let shared = dummy_runtime.into_shared();
+        let loaders: Vec<_> = (0..2).map(|_| {
+            let shared = shared.clone();
+            std::thread::spawn(move || {
+                let plugin = shared.load_plugin(DummyPlugin {}).unwrap();
+                plugin.execute(()).unwrap()
+            })
+        }).collect();
+        for loader in loaders {
+            assert_eq!(loader.join().unwrap(), Ok("hello".to_string()));
+        }
+        drop(shared);
+        let (_res1, _res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+    }
+
+    #[test]
+    fn metrics_by_outcome() {
+        let mut dummy_runtime = build_dummy_runtime();
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let handle2 = runtime.spawn(async move {
+            load_only_event_loop(handle)
+        });
+        let plugin = dummy_runtime.load_plugin(DummyPlugin {}).unwrap();
+        plugin.execute_all(vec![()], Duration::from_millis(10));
+        let metrics = dummy_runtime.metrics().remove("test").unwrap();
+        assert_eq!(metrics, PluginMetrics {
+            resolved: 1,
+            rejected: 0,
+            timed_out: 1,
+            avg_latency: metrics.avg_latency,
+        });
+        assert!(metrics.avg_latency.is_some());
+        drop(plugin);
+        drop(dummy_runtime);
+        let (_res1, _res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+    }
+
+    #[test]
+    fn load_with_init_params() {
+        let mut dummy_runtime = build_dummy_runtime();
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let handle2 = runtime.spawn(async move {
+            dummy_event_loop(handle)
+        });
+        let plugin = dummy_runtime.load_plugin_with(DummyPlugin {}, "config.toml".to_string()).unwrap();
+        assert_eq!(plugin.execute(()).unwrap(), Ok("hello".to_string()));
+        drop(plugin);
+        drop(dummy_runtime);
+        let (_res1, _res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+    }
+
+    #[test]
+    fn batch_drain() {
+        let mut dummy_runtime = PluginRuntime::with_options(RuntimeOptions {
+            plugin_loader: Some(Box::new(|_plugin| ())),
+            batch_drain: true,
+            ..Default::default()
+        });
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let handle2 = runtime.spawn(async move {
+            dummy_event_loop(handle)
+        });
+        let plugin = dummy_runtime.load_plugin(DummyPlugin {}).unwrap();
+        let callers: Vec<_> = (0..4).map(|_| {
+            let plugin = plugin.clone();
+            std::thread::spawn(move || {
+                (0..25).map(|_| plugin.execute(()).unwrap()).collect::<Vec<_>>()
+            })
+        }).collect();
+        for caller in callers {
+            assert!(caller.join().unwrap().iter().all(|res| res == &Ok("hello".to_string())));
+        }
+        assert_eq!(dummy_runtime.metrics().get("test").unwrap().resolved, 101);
+        drop(plugin);
+        drop(dummy_runtime);
+        let (_res1, _res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+    }
+
+    #[test]
+    fn receive_reports_disconnect() {
+        let mut dummy_runtime = build_dummy_runtime();
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        drop(dummy_runtime);
+        assert_eq!(handle.receive().err(), Some(ReceiveError::Disconnected));
+        drop(handle);
+        let _res1 = runtime.block_on(handle1);
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn spawn_on_existing_tokio_handle() {
+        let runtime = create_tokio_runtime();
+        let mut dummy_runtime = build_dummy_runtime().with_existing_tokio_handle(runtime.handle().clone());
+        let tasks = dummy_runtime.spawn(dummy_event_loop).unwrap();
+        let plugin = dummy_runtime.load_plugin(DummyPlugin {}).unwrap();
+        assert_eq!(plugin.execute(()).unwrap(), Ok("hello".to_string()));
+        drop(plugin);
+        drop(dummy_runtime);
+        let (res1, res2) = runtime.block_on(async move {
+            tokio::join!(tasks.result_loop, tasks.event_loop)
+        });
+        assert!(res1.is_ok());
+        assert_eq!(res2.unwrap(), Ok(()));
+    }
+
+    #[test]
+    fn route_on_plugin_data() {
+        let mut kind_runtime = PluginRuntime::with_options(RuntimeOptions {
+            plugin_loader: Some(Box::new(|_plugin: KindPlugin| ())),
+            ..Default::default()
+        });
+        let (fut1, handle) = kind_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let handle2 = runtime.spawn(async move {
+            kind_event_loop(handle)
+        });
+        let greeter = kind_runtime.load_plugin(KindPlugin::Greeter).unwrap();
+        let counter = kind_runtime.load_plugin(KindPlugin::Counter(41)).unwrap();
+        assert_eq!(greeter.execute(()).unwrap(), Ok("hello".to_string()));
+        assert_eq!(counter.execute(()).unwrap(), Ok("41".to_string()));
+        drop((greeter, counter));
+        drop(kind_runtime);
+        let (_res1, _res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+    }
+
+    #[test]
+    fn load_without_load_call() {
+        let mut dummy_runtime = PluginRuntime::new();
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let handle2 = runtime.spawn(async move {
+            dummy_event_loop(handle)
+        });
+        let plugin = dummy_runtime.load_plugin(DummyPlugin {}).unwrap();
+        assert!(dummy_runtime.metrics().is_empty());
+        assert_eq!(plugin.execute(()).unwrap(), Ok("hello".to_string()));
+        assert_eq!(dummy_runtime.metrics().get("test").unwrap().resolved, 1);
+        drop(plugin);
+        drop(dummy_runtime);
+        let (_res1, _res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+    }
+
+    #[test]
+    fn receive_batch() {
+        let mut dummy_runtime = PluginRuntime::new();
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let plugin = dummy_runtime.load_plugin(DummyPlugin {}).unwrap();
+        assert!(handle.try_receive().unwrap().is_none());
+        assert!(handle.receive_batch(2, Duration::from_millis(10)).unwrap().is_empty());
+        let caller = plugin.clone();
+        let calls = std::thread::spawn(move || caller.execute_all(vec![(), (), ()], Duration::from_secs(5)));
+        let mut received = 0;
+        while received < 3 {
+            let batch = handle.receive_batch(2, Duration::from_secs(5)).unwrap();
+            assert!(!batch.is_empty() && batch.len() <= 2);
+            received += batch.len();
+            for call in batch {
+                handle.resolve(call.id(), format!("batch of {}", received));
+            }
+        }
+        for res in calls.join().unwrap() {
+            assert!(res.unwrap().is_ok());
+        }
+        drop(plugin);
+        drop(dummy_runtime);
+        drop(handle);
+        let _res1 = runtime.block_on(handle1);
+    }
+
+    #[test]
+    fn load_failure_keeps_cause() {
+        let mut dummy_runtime = build_dummy_runtime();
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let handle2 = runtime.spawn(async move {
+            let call = handle.receive().map_err(|e| e.to_string())?;
+            handle.reject(call.id(), "bad config");
+            Ok::<(), String>(())
+        });
+        let error = dummy_runtime.load_plugin(DummyPlugin {}).err().unwrap();
+        assert_eq!(error.kind(), PluginErrorKind::FailedToLoad);
+        assert_eq!(error.clone(), error);
+        assert_ne!(error, PluginError::FailedToLoad("bad config".to_string(), None));
+        assert_eq!(error.load_cause::<String>().map(String::as_str), Some("bad config"));
+        assert!(error.load_cause::<u32>().is_none());
+        drop(dummy_runtime);
+        let (_res1, _res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+    }
+
+    #[test]
+    fn reconfigure_running_loop() {
+        let mut dummy_runtime = PluginRuntime::new();
+        let (fut1, handle, mut config) = dummy_runtime.run_configured("v1".to_string());
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let handle2 = runtime.spawn(async move {
+            let mut current = config.current();
+            while let Ok(r) = handle.receive() {
+                if let Some(updated) = config.changed() {
+                    current = updated;
+                }
+                handle.resolve(r.id(), current.to_string());
+            }
+        });
+        let plugin = dummy_runtime.load_plugin(DummyPlugin {}).unwrap();
+        assert_eq!(plugin.execute(()).unwrap(), Ok("v1".to_string()));
+        dummy_runtime.reconfigure("v2".to_string()).unwrap();
+        assert_eq!(plugin.execute(()).unwrap(), Ok("v2".to_string()));
+        assert_eq!(dummy_runtime.reconfigure(2).err().map(|e| e.kind()), Some(PluginErrorKind::RuntimeError));
+        drop(plugin);
+        drop(dummy_runtime);
+        let (_res1, _res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+    }
+
+    #[test]
+    fn runtime_with_options() {
+        let mut dummy_runtime = PluginRuntime::with_options(RuntimeOptions {
+            plugin_loader: Some(Box::new(|_plugin| ())),
+            batch_drain: true,
+            subscriber_shards: 1,
+            ..RuntimeOptions::default()
+        });
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let handle2 = runtime.spawn(async move {
+            dummy_event_loop(handle)
+        });
+        let plugin = dummy_runtime.load_plugin(DummyPlugin {}).unwrap();
+        assert_eq!(plugin.execute(()).unwrap(), Ok("hello".to_string()));
+        assert_eq!(plugin.execute_all(vec![(), ()], Duration::from_secs(5)), vec![Ok(Ok("hello".to_string())), Ok(Ok("hello".to_string()))]);
+        assert!(dummy_runtime.inflight_ids().is_empty());
+        drop(plugin);
+        drop(dummy_runtime);
+        let (_res1, _res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+    }
+
+    #[test]
+    fn run_threaded() {
+        let mut dummy_runtime = PluginRuntime::new();
+        let (result_thread, handle) = dummy_runtime.run_threaded().unwrap();
+        let event_thread = std::thread::spawn(move || dummy_event_loop(handle));
+        let plugin = dummy_runtime.load_plugin(DummyPlugin {}).unwrap();
+        assert_eq!(plugin.execute(()).unwrap(), Ok("hello".to_string()));
+        drop(plugin);
+        drop(dummy_runtime);
+        assert_eq!(event_thread.join().unwrap(), Ok(()));
+        result_thread.join().unwrap();
+    }
+
+    #[test]
+    fn run_blocking() {
+        let mut dummy_runtime = PluginRuntime::new();
+        let running = dummy_runtime.run_blocking(dummy_event_loop).unwrap();
+        let plugin = dummy_runtime.load_plugin(DummyPlugin {}).unwrap();
+        assert_eq!(plugin.execute(()).unwrap(), Ok("hello".to_string()));
+        drop(plugin);
+        drop(dummy_runtime);
+        assert_eq!(running.join().unwrap(), Ok(()));
+    }
+
+    #[test]
+    fn shutdown_reason() {
+        let mut dummy_runtime = PluginRuntime::new();
+        let (fut1, handle) = dummy_runtime.run();
+        assert_eq!(handle.shutdown_reason(), None);
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let handle2 = runtime.spawn(async move {
+            while handle.receive().is_ok() {}
+            handle.shutdown_reason()
+        });
+        let plugin = dummy_runtime.load_plugin(DummyPlugin {}).unwrap();
+        dummy_runtime.shutdown_with(ShutdownReason::Fatal(PluginError::Disconnected));
+        dummy_runtime.shutdown_with(ShutdownReason::Reload);
+        assert!(dummy_runtime.load_plugin(DummyPlugin {}).is_err());
+        drop(plugin);
+        drop(dummy_runtime);
+        let (_res1, res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+        assert_eq!(res2.unwrap(), Some(ShutdownReason::Fatal(PluginError::Disconnected)));
+    }
+
+    fn serve_with_policy(on_panic: OnPanic) -> Vec<PluginResult<PluginCallOutcome<HookPlugin>>> {
+        let mut hook_runtime = PluginRuntime::with_options(RuntimeOptions {
+            plugin_loader: Some(Box::new(|_plugin: HookPlugin| "load")),
+            on_panic,
+            ..Default::default()
+        });
+        let (fut1, handle) = hook_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let handle2 = runtime.spawn(async move {
+            serving_event_loop(handle)
+        });
+        let plugin = hook_runtime.load_plugin(HookPlugin { log: Default::default() }).unwrap();
+        let results = (0..6).map(|_| plugin.execute("bump")).collect();
+        drop(plugin);
+        drop(hook_runtime);
+        let (_res1, _res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+        results
+    }
+
+    #[test]
+    fn recover_from_panics() {
+        let kinds = |results: Vec<PluginResult<PluginCallOutcome<HookPlugin>>>| -> Vec<_> {
+            results.into_iter().map(|result| result.map_err(|e| e.kind())).collect()
+        };
+        assert_eq!(kinds(serve_with_policy(OnPanic::RejectOnly)), vec![
+            Ok(Ok("1".to_string())),
+            Err(PluginErrorKind::RuntimeError),
+            Ok(Ok("3".to_string())),
+            Ok(Ok("4".to_string())),
+            Ok(Ok("5".to_string())),
+            Ok(Ok("6".to_string())),
+        ]);
+        assert_eq!(kinds(serve_with_policy(OnPanic::Reload { max_reloads: 1, window: Duration::from_secs(60) })), vec![
+            Ok(Ok("1".to_string())),
+            Err(PluginErrorKind::RuntimeError),
+            Ok(Ok("1".to_string())),
+            Err(PluginErrorKind::RuntimeError),
+            Err(PluginErrorKind::RuntimeError),
+            Err(PluginErrorKind::RuntimeError),
+        ]);
+    }
+
+    #[test]
+    fn complete_with_result() {
+        let mut dummy_runtime = PluginRuntime::new();
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let handle2 = runtime.spawn(async move {
+            let mut fail = false;
+            while let Ok(r) = handle.receive() {
+                handle.complete(r.id(), if fail { Err("no".to_string()) } else { Ok("yes".to_string()) });
+                fail = !fail;
+            }
+        });
+        let plugin = dummy_runtime.load_plugin(DummyPlugin {}).unwrap();
+        assert_eq!(plugin.execute(()).unwrap(), Ok("yes".to_string()));
+        assert_eq!(plugin.execute(()).unwrap(), Err("no".to_string()));
+        drop(plugin);
+        drop(dummy_runtime);
+        let (_res1, _res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+    }
+
+    #[test]
+    fn subscriber_ages() {
+        let mut dummy_runtime = PluginRuntime::new();
+        assert!(dummy_runtime.subscriber_ages().is_empty());
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let plugin = dummy_runtime.load_plugin(DummyPlugin {}).unwrap();
+        let (result_sender, result_receiver) = channel();
+        let id = plugin.execute_into((), result_sender).unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        let ages = dummy_runtime.subscriber_ages();
+        assert_eq!(ages.len(), 1);
+        assert_eq!(ages[0].0, id);
+        assert!(ages[0].1 >= Duration::from_millis(20));
+        handle.resolve(handle.receive().unwrap().id(), "hello");
+        assert_eq!(result_receiver.recv().unwrap().0, id);
+        assert!(dummy_runtime.subscriber_ages().is_empty());
+        drop(plugin);
+        drop(dummy_runtime);
+        drop(handle);
+        let _res1 = runtime.block_on(handle1);
+    }
+
+    #[test]
+    fn max_pending() {
+        let mut dummy_runtime = PluginRuntime::with_options(RuntimeOptions {
+            max_pending: Some(1),
+            ..Default::default()
+        });
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let plugin = dummy_runtime.load_plugin(DummyPlugin {}).unwrap();
+        let callers: Vec<_> = (0..2).map(|_| {
+            let caller = plugin.clone();
+            std::thread::spawn(move || caller.execute(()))
+        }).collect();
+        let first = handle.receive().unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(handle.try_receive().unwrap().is_none());
+        handle.resolve(first.id(), "first");
+        let second = handle.receive().unwrap();
+        handle.resolve(second.id(), "second");
+        for caller in callers {
+            assert!(caller.join().unwrap().unwrap().is_ok());
+        }
+        drop(plugin);
+        drop(dummy_runtime);
+        drop(handle);
+        let _res1 = runtime.block_on(handle1);
+    }
+
+    #[test]
+    fn max_pending_awaits_slot() {
+        let mut dummy_runtime = PluginRuntime::with_options(RuntimeOptions {
+            max_pending: Some(1),
+            ..Default::default()
+        });
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let plugin = dummy_runtime.load_plugin(DummyPlugin {}).unwrap();
+        let (result_sender, result_receiver) = channel();
+        let first = plugin.execute_into((), result_sender).unwrap();
+        let waiter = plugin.execute_pending(()).unwrap();
+        let event_loop = std::thread::spawn(move || {
+            assert_eq!(handle.receive().unwrap().id(), first);
+            std::thread::sleep(Duration::from_millis(20));
+            let queued = handle.try_receive().unwrap().is_some();
+            handle.resolve(first, "first");
+            let second = handle.receive().unwrap();
+            handle.resolve(second.id(), "second");
+            queued
+        });
+        assert_eq!(runtime.block_on(waiter), Ok(Ok("second".to_string())));
+        assert_eq!(result_receiver.recv().unwrap().0, first);
+        assert!(!event_loop.join().unwrap());
+        drop(plugin);
+        drop(dummy_runtime);
+        let _res1 = runtime.block_on(handle1);
+    }
+
+    #[test]
+    fn max_pending_fail_fast() {
+        let mut dummy_runtime = PluginRuntime::with_options(RuntimeOptions {
+            max_pending: Some(1),
+            full_queue: FullQueue::Fail,
+            ..Default::default()
+        });
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let plugin = dummy_runtime.load_plugin(DummyPlugin {}).unwrap();
+        let (result_sender, result_receiver) = std::sync::mpsc::channel();
+        let id = plugin.execute_into((), result_sender.clone()).unwrap();
+        assert_eq!(plugin.execute_into((), result_sender).err().map(|e| e.kind()), Some(PluginErrorKind::RuntimeError));
+        handle.resolve(handle.receive().unwrap().id(), "hello");
+        assert_eq!(result_receiver.recv().unwrap().0, id);
+        drop(plugin);
+        drop(dummy_runtime);
+        drop(handle);
+        let _res1 = runtime.block_on(handle1);
+    }
+
+    #[test]
+    fn defer_calls() {
+        let mut dummy_runtime = PluginRuntime::with_options(RuntimeOptions {
+            max_defers: 1,
+            ..Default::default()
+        });
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let plugin = dummy_runtime.load_plugin(DummyPlugin {}).unwrap();
+        let (result_sender, result_receiver) = channel();
+        let first = plugin.execute_into((), result_sender.clone()).unwrap();
+        let second = plugin.execute_into((), result_sender).unwrap();
+        handle.defer(handle.receive().unwrap()).unwrap();
+        let received = handle.receive().unwrap();
+        assert_eq!(received.id(), second);
+        handle.resolve(received.id(), "second");
+        let received = handle.receive().unwrap();
+        assert_eq!((received.id(), received.deferred), (first, 1));
+        let error = PluginError::RuntimeError("call deferred more than 1 times".to_string());
+        assert_eq!(handle.defer(received), Err(error.clone()));
+        let results: Vec<_> = result_receiver.iter().take(2).map(|(id, result)| (id, result.map(Into::into))).collect();
+        assert_eq!(results, vec![(second, Ok(Ok("second".to_string()))), (first, Err(error))]);
+        drop(plugin);
+        dummy_runtime.shutdown_with(ShutdownReason::Planned);
+        assert_eq!(handle.receive().err(), Some(ReceiveError::Disconnected));
+        drop(dummy_runtime);
+        drop(handle);
+        let _res1 = runtime.block_on(handle1);
+    }
+
+    #[test]
+    fn queue_wait_stats() {
+        let mut untracked = PluginRuntime::<DummyPlugin>::new();
+        let (_fut, _handle) = untracked.run();
+        assert_eq!(untracked.queue_wait_stats(), None);
+
+        let mut dummy_runtime = PluginRuntime::with_options(RuntimeOptions {
+            track_queue_wait: true,
+            ..Default::default()
+        });
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let plugin = dummy_runtime.load_plugin(DummyPlugin {}).unwrap();
+        assert_eq!(dummy_runtime.queue_wait_stats(), None);
+        let (result_sender, result_receiver) = channel();
+        plugin.execute_into((), result_sender.clone()).unwrap();
+        plugin.execute_into((), result_sender).unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        let first = handle.receive().unwrap();
+        let second = handle.receive().unwrap();
+        handle.defer(first).unwrap();
+        let first = handle.receive().unwrap();
+        let stats = dummy_runtime.queue_wait_stats().unwrap();
+        assert_eq!(stats.samples, 2);
+        assert!(stats.p50 >= Duration::from_millis(20), "{:?}", stats);
+        assert!(stats.p50 <= stats.p99 && stats.p99 <= stats.max, "{:?}", stats);
+        handle.resolve(first.id(), "hello");
+        handle.resolve(second.id(), "hello");
+        let _ = result_receiver.recv().unwrap();
+        let _ = result_receiver.recv().unwrap();
+        drop(plugin);
+        drop(dummy_runtime);
+        drop(handle);
+        let _res1 = runtime.block_on(handle1);
+    }
+
+    #[test]
+    fn queue_depth() {
+        let mut untracked = PluginRuntime::<DummyPlugin>::new();
+        let (_fut, _handle) = untracked.run();
+        assert_eq!(untracked.queue_depth(), None);
+
+        let mut dummy_runtime = PluginRuntime::with_options(RuntimeOptions {
+            track_queue_depth: true,
+            ..Default::default()
+        });
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let plugin = dummy_runtime.load_plugin(DummyPlugin {}).unwrap();
+        assert_eq!(dummy_runtime.queue_depth(), Some(0));
+        let (result_sender, result_receiver) = channel();
+        plugin.execute_into((), result_sender.clone()).unwrap();
+        plugin.execute_into((), result_sender).unwrap();
+        assert_eq!(dummy_runtime.queue_depth(), Some(2));
+        let received = handle.receive().unwrap();
+        assert_eq!(dummy_runtime.queue_depth(), Some(1));
+        assert_eq!(dummy_runtime.inflight_ids().len(), 2);
+        handle.resolve(received.id(), "hello");
+        let _ = result_receiver.recv().unwrap();
+        let received = handle.try_receive().unwrap().unwrap();
+        assert_eq!(dummy_runtime.queue_depth(), Some(0));
+        handle.resolve(received.id(), "hello");
+        let _ = result_receiver.recv().unwrap();
+        drop(plugin);
+        drop(dummy_runtime);
+        drop(handle);
+        let _res1 = runtime.block_on(handle1);
+    }
+
+    #[test]
+    fn inflight_ids() {
+        let mut dummy_runtime = PluginRuntime::new();
+        assert!(dummy_runtime.inflight_ids().is_empty());
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let plugin = dummy_runtime.load_plugin(DummyPlugin {}).unwrap();
+        let caller = plugin.clone();
+        let call = std::thread::spawn(move || caller.execute(()));
+        let received = handle.receive().unwrap();
+        assert_eq!(dummy_runtime.inflight_ids(), vec![received.id()]);
+        std::thread::sleep(Duration::from_millis(5));
+        let ages = dummy_runtime.subscriber_ages();
+        assert_eq!(ages.len(), 1);
+        assert_eq!(ages[0].0, received.id());
+        assert!(ages[0].1 >= Duration::from_millis(5));
+        handle.resolve(received.id(), "hello");
+        assert_eq!(call.join().unwrap().unwrap(), Ok("hello".to_string()));
+        assert!(dummy_runtime.inflight_ids().is_empty());
+        assert!(dummy_runtime.subscriber_ages().is_empty());
+        drop(plugin);
+        drop(dummy_runtime);
+        drop(handle);
+        let _res1 = runtime.block_on(handle1);
+    }
+
+    #[cfg(feature = "log")]
+    struct RecordingLogger(std::sync::Mutex<Vec<String>>);
+
+    #[cfg(feature = "log")]
+    impl log::Log for RecordingLogger {
+        fn enabled(&self, _metadata: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            self.0.lock().unwrap().push(record.args().to_string());
+        }
+
+        fn flush(&self) {}
+    }
+
+    #[cfg(feature = "log")]
+    static CALL_LOG: RecordingLogger = RecordingLogger(std::sync::Mutex::new(Vec::new()));
+
+    #[test]
+    #[cfg(feature = "log")]
+    fn trace_call_lifecycle() {
+        log::set_logger(&CALL_LOG).unwrap();
+        log::set_max_level(log::LevelFilter::Trace);
+        let mut dummy_runtime = build_dummy_runtime();
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let handle2 = runtime.spawn(async move {
+            dummy_event_loop(handle)
+        });
+        let plugin = dummy_runtime.load_plugin(DummyPlugin {}).unwrap();
+        let (result_sender, result_receiver) = channel();
+        let id = plugin.execute_into((), result_sender).unwrap();
+        assert_eq!(result_receiver.recv().unwrap().0, id);
+        drop(plugin);
+        drop(dummy_runtime);
+        let (_res1, _res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+        let id = id.to_string();
+        let lines: Vec<_> = CALL_LOG.0.lock().unwrap().iter().filter(|line| line.contains(&id)).cloned().collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].starts_with(&format!("call {} enqueued for test", id)));
+        assert_eq!(lines[1], format!("call {} received by event loop for test", id));
+        assert_eq!(lines[2], format!("call {} resolved", id));
+        assert_eq!(lines[3], format!("call {} delivered to caller", id));
+    }
+
+    #[test]
+    fn broadcast_without_shutdown() {
+        let mut dummy_runtime = PluginRuntime::new();
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let plugin = dummy_runtime.load_plugin(DummyPlugin {}).unwrap();
+        let callers: Vec<_> = (0..2).map(|_| {
+            let caller = plugin.clone();
+            std::thread::spawn(move || caller.execute(()))
+        }).collect();
+        handle.receive().unwrap();
+        handle.receive().unwrap();
+        handle.broadcast(Ok("everyone".to_string()));
+        for caller in callers {
+            assert_eq!(caller.join().unwrap(), Ok(Ok("everyone".to_string())));
+        }
+        let caller = plugin.clone();
+        let call = std::thread::spawn(move || caller.execute(()));
+        let received = handle.receive().unwrap();
+        handle.resolve(received.id(), "still running");
+        assert_eq!(call.join().unwrap(), Ok(Ok("still running".to_string())));
+        handle.shutdown(Ok("bye".to_string()));
+        let _res1 = runtime.block_on(handle1);
+        drop(plugin);
+        drop(dummy_runtime);
+    }
+
+    #[test]
+    fn broadcast_past_gone_callers() {
+        let mut dummy_runtime = PluginRuntime::new();
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let plugin = dummy_runtime.load_plugin(DummyPlugin {}).unwrap();
+        for _ in 0..4 {
+            let (result_sender, result_receiver) = channel();
+            plugin.dispatch((), HashMap::new(), None, ResultSender::Call(result_sender), None).unwrap();
+            drop(result_receiver);
+            handle.receive().unwrap();
+        }
+        let callers: Vec<_> = (0..4).map(|_| {
+            let caller = plugin.clone();
+            std::thread::spawn(move || caller.execute(()))
+        }).collect();
+        for _ in 0..4 {
+            handle.receive().unwrap();
+        }
+        // Gone callers are drained along with the others, whatever the order the rest still
+        // get the result.
+        handle.broadcast(Ok("everyone".to_string()));
+        for caller in callers {
+            assert_eq!(caller.join().unwrap(), Ok(Ok("everyone".to_string())));
+        }
+        handle.shutdown(Ok("bye".to_string()));
+        let _res1 = runtime.block_on(handle1);
+        drop(plugin);
+        drop(dummy_runtime);
+    }
+
+    #[test]
+    fn broadcast_shared_payload() {
+        let mut dummy_runtime = PluginRuntime::new();
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let plugin = dummy_runtime.load_plugin(DummyPlugin {}).unwrap();
+        let callers: Vec<_> = (0..2).map(|_| {
+            let caller = plugin.clone();
+            std::thread::spawn(move || caller.execute_shared(()))
+        }).collect();
+        handle.receive().unwrap();
+        handle.receive().unwrap();
+        let payload = Arc::new("everyone".to_string());
+        handle.broadcast_shared(Ok(payload.clone()));
+        for caller in callers {
+            let shared = caller.join().unwrap().unwrap().unwrap();
+            assert!(Arc::ptr_eq(&shared, &payload));
+        }
+        handle.shutdown(Ok("bye".to_string()));
+        let _res1 = runtime.block_on(handle1);
+        drop(plugin);
+        drop(dummy_runtime);
+    }
+
+    #[test]
+    fn publish_to_topics() {
+        let mut dummy_runtime = PluginRuntime::new();
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let plugin = dummy_runtime.load_plugin(DummyPlugin {}).unwrap();
+        let mut news = plugin.subscribe("news").unwrap();
+        let mut sports = plugin.subscribe("sports").unwrap();
+        handle.publish("news", Ok("headline".to_string()));
+        handle.publish("sports", Err("rained off".to_string()));
+        handle.publish("weather", Ok("sunny".to_string()));
+        assert_eq!(news.next(), Some(Ok("headline".to_string())));
+        assert_eq!(sports.next(), Some(Err("rained off".to_string())));
+        assert_eq!(news.try_next(), None);
+        let mut late = plugin.subscribe("news").unwrap();
+        drop(news);
+        assert_eq!(dummy_runtime.topics.subscriptions("news"), 1);
+        handle.publish("news", Ok("update".to_string()));
+        assert_eq!(late.next(), Some(Ok("update".to_string())));
+        handle.fail(&DummyPlugin {}, "crashed");
+        assert_eq!(sports.next(), None);
+        assert_eq!(late.next(), None);
+        handle.shutdown(Ok("bye".to_string()));
+        let _res1 = runtime.block_on(handle1);
+        drop(plugin);
+        drop(dummy_runtime);
+    }
+
+    #[test]
+    fn drain() {
+        let mut dummy_runtime = PluginRuntime::new();
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let plugin = dummy_runtime.load_plugin(DummyPlugin {}).unwrap();
+        let (finished, stuck) = (plugin.clone(), plugin.clone());
+        let finished = std::thread::spawn(move || finished.execute(()));
+        let finished_call = handle.receive().unwrap();
+        let stuck = std::thread::spawn(move || stuck.execute(()));
+        handle.receive().unwrap();
+        let resolver = handle.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(10));
+            resolver.resolve(finished_call.id(), "finished");
+        });
+        assert_eq!(dummy_runtime.drain(Duration::from_millis(200)), 1);
+        assert_eq!(finished.join().unwrap(), Ok(Ok("finished".to_string())));
+        assert_eq!(stuck.join().unwrap(), Err(PluginError::Timeout(Duration::from_millis(200))));
+        assert_eq!(plugin.execute(()), Err(PluginError::Disconnected));
+        assert!(dummy_runtime.load_plugin(DummyPlugin {}).is_err());
+        drop(plugin);
+        assert_eq!(handle.receive().err(), Some(ReceiveError::Disconnected));
+        drop(handle);
+        drop(dummy_runtime);
+        let _res1 = runtime.block_on(handle1);
+    }
+
+    #[test]
+    fn fair_scheduling() {
+        let mut kind_runtime = PluginRuntime::with_options(RuntimeOptions {
+            fair_scheduling: true,
+            ..Default::default()
+        });
+        let (fut1, handle) = kind_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let greeter = kind_runtime.load_plugin(KindPlugin::Greeter).unwrap();
+        let counter = kind_runtime.load_plugin(KindPlugin::Counter(0)).unwrap();
+        let (result_sender, _result_receiver) = std::sync::mpsc::channel();
+        for _ in 0..3 {
+            greeter.execute_into((), result_sender.clone()).unwrap();
+        }
+        counter.execute_into((), result_sender).unwrap();
+        let order: Vec<_> = (0..4).map(|_| handle.receive().unwrap().plugin_name().into_owned()).collect();
+        assert_eq!(order, vec!["greeter", "counter", "greeter", "greeter"]);
+        drop((greeter, counter));
+        drop(kind_runtime);
+        drop(handle);
+        let _res1 = runtime.block_on(handle1);
+    }
+
+    #[test]
+    fn load_before_run() {
+        let dummy_runtime = build_dummy_runtime();
+        let error = dummy_runtime.load_plugin(DummyPlugin {}).err().unwrap();
+        assert_eq!(error, PluginError::FailedToLoad("run runtime first".to_string(), None));
+    }
+
+    #[test]
+    fn dedicated_channels() {
+        let mut kind_runtime = PluginRuntime::with_options(RuntimeOptions {
+            plugin_loader: Some(Box::new(|_plugin: KindPlugin| ())),
+            dedicated_channels: true,
+            ..Default::default()
+        });
+        let (fut1, handle) = kind_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let handle2 = runtime.spawn(async move {
+            kind_event_loop(handle)
+        });
+        let greeter = kind_runtime.load_plugin(KindPlugin::Greeter).unwrap();
+        let counter = kind_runtime.load_plugin(KindPlugin::Counter(7)).unwrap();
+        let results = greeter.execute_all(vec![(), ()], Duration::from_secs(5));
+        assert!(results.into_iter().all(|res| res.unwrap() == Ok("hello".to_string())));
+        assert_eq!(counter.execute(()).unwrap(), Ok("7".to_string()));
+        drop((greeter, counter));
+        drop(kind_runtime);
+        let (_res1, _res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+    }
+
+    #[test]
+    fn execute_on_dead_runtime() {
+        let mut dummy_runtime = PluginRuntime::new();
+        let (fut1, handle) = dummy_runtime.run();
+        drop((fut1, handle));
+        let plugin = dummy_runtime.load_plugin(DummyPlugin {}).unwrap();
+        assert_eq!(plugin.execute(()), Err(PluginError::Disconnected));
+    }
+
+    #[test]
+    fn run_with_custom_spawner() {
+        let runtime = create_tokio_runtime();
+        let tokio_handle = runtime.handle().clone();
+        let mut dummy_runtime = build_dummy_runtime();
+        dummy_runtime.run_with(|fut| {
+            tokio_handle.spawn(fut);
+        }, dummy_event_loop);
+        let plugin = dummy_runtime.load_plugin(DummyPlugin {}).unwrap();
+        assert_eq!(plugin.execute(()).unwrap(), Ok("hello".to_string()));
+        drop(plugin);
+        drop(dummy_runtime);
+    }
+}
\ No newline at end of file
@@ -1,7 +1,142 @@
-use crate::{PluginData, PluginResult};
+use crate::{PluginData, PluginResult, PluginError};
+use std::time::Duration;
+
+/// What a source can tell about a plugin before it is loaded.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PluginDescriptor {
+    pub name: String,
+    pub version: Option<String>,
+    pub description: Option<String>,
+    pub tags: Vec<String>,
+    /// Group the loader files the plugin under, `None` puts it in the default group.
+    pub group: Option<String>,
+    /// Default timeout of the plugin's calls, see `Plugin::with_default_timeout`.
+    pub timeout: Option<Duration>,
+}
+
+impl PluginDescriptor {
+    pub fn new<N: Into<String>>(name: N) -> Self {
+        PluginDescriptor {
+            name: name.into(),
+            ..Default::default()
+        }
+    }
+}
 
 pub trait PluginSource {
     type PluginType: PluginData;
     fn plugins(&self) -> Vec<String>;
     fn open<P: Into<String>>(&mut self, plugin: P) -> PluginResult<Self::PluginType>;
-}
\ No newline at end of file
+
+    /// Lazy counterpart of `plugins`, which the loader discovers plugins through so it only
+    /// keeps the names it wants. Sources backed by a large or paginated catalog should
+    /// override it to fetch names as they go; small ones can keep the default over `plugins`.
+    fn plugins_iter(&self) -> Box<dyn Iterator<Item=String> + '_> {
+        Box::new(self.plugins().into_iter())
+    }
+
+    /// Richer listing than `plugins`, defaults to the names without metadata.
+    fn describe(&self) -> Vec<PluginDescriptor> {
+        self.plugins_iter().map(PluginDescriptor::new).collect()
+    }
+
+    /// Descriptor of the plugin `plugin`, defaults to looking it up in `describe`. Sources that
+    /// can describe a single plugin without listing them all should override it.
+    fn descriptor(&self, plugin: &str) -> Option<PluginDescriptor> {
+        self.describe().into_iter().find(|descriptor| descriptor.name == plugin)
+    }
+
+    /// Version or content hash of the plugin artifact, used to skip redundant reloads.
+    /// `None` means the source can't tell, so the plugin is always reloaded.
+    fn digest(&self, _plugin: &str) -> Option<String> {
+        None
+    }
+}
+
+/// Source over plugins registered programmatically, listed in registration order.
+pub struct RegistrySource<P: PluginData> {
+    plugins: Vec<(String, P)>,
+}
+
+impl<P: PluginData> Default for RegistrySource<P> {
+    fn default() -> Self {
+        RegistrySource {
+            plugins: Vec::new(),
+        }
+    }
+}
+
+impl<P: PluginData> RegistrySource<P> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register<N: Into<String>>(&mut self, name: N, plugin: P) -> &mut Self {
+        let name = name.into();
+        self.unregister(&name);
+        self.plugins.push((name, plugin));
+        self
+    }
+
+    pub fn unregister(&mut self, name: &str) -> Option<P> {
+        let position = self.plugins.iter().position(|(registered, _)| registered == name)?;
+        Some(self.plugins.remove(position).1)
+    }
+}
+
+impl<P: PluginData> PluginSource for RegistrySource<P> {
+    type PluginType = P;
+
+    fn plugins(&self) -> Vec<String> {
+        self.plugins_iter().collect()
+    }
+
+    fn plugins_iter(&self) -> Box<dyn Iterator<Item=String> + '_> {
+        Box::new(self.plugins.iter().map(|(name, _)| name.clone()))
+    }
+
+    fn descriptor(&self, plugin: &str) -> Option<PluginDescriptor> {
+        self.plugins.iter()
+            .any(|(registered, _)| registered == plugin)
+            .then(|| PluginDescriptor::new(plugin))
+    }
+
+    fn open<N: Into<String>>(&mut self, plugin: N) -> PluginResult<Self::PluginType> {
+        let name = plugin.into();
+        self.plugins.iter()
+            .find(|(registered, _)| registered == &name)
+            .map(|(_, plugin)| plugin.clone())
+            .ok_or(PluginError::InvalidPlugin(format!("{} is not registered", name)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::source::{RegistrySource, PluginSource, PluginDescriptor};
+    use crate::test_utils::{DummyPlugin, DummySource, VersionedSource};
+    use crate::PluginErrorKind;
+
+    #[test]
+    fn registry_source() {
+        let mut registry = RegistrySource::new();
+        registry.register("first", DummyPlugin {}).register("second", DummyPlugin {});
+        assert_eq!(registry.plugins(), vec!["first".to_string(), "second".to_string()]);
+        assert_eq!(registry.plugins_iter().skip(1).collect::<Vec<_>>(), vec!["second".to_string()]);
+        assert_eq!(registry.describe(), vec![PluginDescriptor::new("first"), PluginDescriptor::new("second")]);
+        assert_eq!(registry.descriptor("second"), Some(PluginDescriptor::new("second")));
+        assert_eq!(registry.descriptor("third"), None);
+        assert!(registry.open("first").is_ok());
+        assert!(registry.unregister("first").is_some());
+        assert_eq!(registry.open("first").err().map(|e| e.kind()), Some(PluginErrorKind::InvalidPlugin));
+    }
+
+    #[test]
+    fn describe_plugins() {
+        let versioned = VersionedSource { opens: Default::default(), version: Default::default() };
+        assert_eq!(versioned.describe(), vec![PluginDescriptor::new("alias")]);
+        assert_eq!(versioned.descriptor("alias"), Some(PluginDescriptor::new("alias")));
+        let described = DummySource {}.describe();
+        assert_eq!(described.iter().map(|descriptor| descriptor.version.as_deref()).collect::<Vec<_>>(), vec![Some("v1")]);
+        assert_eq!(DummySource {}.descriptor("test").and_then(|descriptor| descriptor.version), Some("v1".to_string()));
+    }
+}
@@ -0,0 +1,170 @@
+use crate::{Plugin, PluginData, PluginError, PluginResult};
+use crate::runtime::TaggedResult;
+use crate::subscribers::ResultSender;
+use futures_core::Stream;
+use futures_sink::Sink;
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+type PluginResultOf<P> = TaggedResult<<P as PluginData>::PluginCallResult>;
+
+struct Flow {
+    in_flight: usize,
+    sink_waker: Option<Waker>,
+    stream_waker: Option<Waker>,
+}
+
+/// Submits calls to a plugin, see `channel`.
+pub struct CallSink<P: PluginData> {
+    plugin: Plugin<P>,
+    result_sender: Option<Sender<PluginResultOf<P>>>,
+    flow: Arc<Mutex<Flow>>,
+    max_in_flight: usize,
+}
+
+/// Results of the calls submitted through the paired `CallSink`, tagged with their id, in the
+/// order they complete. Ends once the sink is closed or dropped and every result came in.
+pub struct ResultStream<P: PluginData> {
+    result_receiver: Receiver<PluginResultOf<P>>,
+    flow: Arc<Mutex<Flow>>,
+}
+
+// Nothing is structurally pinned, the types are only driven through `Pin<&mut Self>`.
+impl<P: PluginData> Unpin for CallSink<P> {}
+impl<P: PluginData> Unpin for ResultStream<P> {}
+
+/// Sink and stream pair over `plugin`. Backpressure comes from the stream: the sink is only
+/// ready while fewer than `max_in_flight` results are left to read, so a consumer that falls
+/// behind holds back new calls rather than letting results pile up.
+pub fn channel<P: PluginData>(plugin: &Plugin<P>, max_in_flight: usize) -> (CallSink<P>, ResultStream<P>) {
+    let (result_sender, result_receiver) = mpsc::channel();
+    let flow = Arc::new(Mutex::new(Flow {
+        in_flight: 0,
+        sink_waker: None,
+        stream_waker: None,
+    }));
+    let sink = CallSink {
+        plugin: plugin.clone(),
+        result_sender: Some(result_sender),
+        flow: flow.clone(),
+        max_in_flight: max_in_flight.max(1),
+    };
+    (sink, ResultStream { result_receiver, flow })
+}
+
+impl<P: PluginData> Sink<P::PluginCall> for CallSink<P> {
+    type Error = PluginError;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<PluginResult<()>> {
+        let mut flow = self.flow.lock().unwrap();
+        if flow.in_flight < self.max_in_flight {
+            return Poll::Ready(Ok(()));
+        }
+        flow.sink_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    fn start_send(self: Pin<&mut Self>, call: P::PluginCall) -> PluginResult<()> {
+        let result_sender = self.result_sender.clone().ok_or(PluginError::Disconnected)?;
+        let flow = self.flow.clone();
+        let notify = Arc::new(move || {
+            if let Some(waker) = flow.lock().unwrap().stream_waker.take() {
+                waker.wake();
+            }
+        });
+        self.flow.lock().unwrap().in_flight += 1;
+        self.plugin.dispatch(call, HashMap::new(), None, ResultSender::Notify(result_sender, notify), None).map(|_| ()).map_err(|err| {
+            self.flow.lock().unwrap().in_flight -= 1;
+            err
+        })
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<PluginResult<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<PluginResult<()>> {
+        self.result_sender.take();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<P: PluginData> ResultStream<P> {
+    fn received(&self, result: PluginResultOf<P>) -> Poll<Option<PluginResultOf<P>>> {
+        let mut flow = self.flow.lock().unwrap();
+        flow.in_flight -= 1;
+        if let Some(waker) = flow.sink_waker.take() {
+            waker.wake();
+        }
+        Poll::Ready(Some(result))
+    }
+}
+
+impl<P: PluginData> Stream for ResultStream<P> {
+    type Item = PluginResultOf<P>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match self.result_receiver.try_recv() {
+            Ok(result) => return self.received(result),
+            Err(TryRecvError::Disconnected) => return Poll::Ready(None),
+            Err(TryRecvError::Empty) => {}
+        }
+        self.flow.lock().unwrap().stream_waker = Some(cx.waker().clone());
+        // A result may have landed before the waker was registered.
+        match self.result_receiver.try_recv() {
+            Ok(result) => self.received(result),
+            Err(TryRecvError::Disconnected) => Poll::Ready(None),
+            Err(TryRecvError::Empty) => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tokio_utils::create_tokio_runtime;
+    use crate::test_utils::{build_dummy_runtime, dummy_event_loop, DummyPlugin};
+    use crate::stream::channel;
+    use futures_core::Stream;
+    use futures_sink::Sink;
+    use std::future::poll_fn;
+    use std::pin::Pin;
+
+    #[test]
+    fn sink_and_stream() {
+        let mut dummy_runtime = build_dummy_runtime();
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let handle2 = runtime.spawn(async move {
+            dummy_event_loop(handle)
+        });
+        let plugin = dummy_runtime.load_plugin(DummyPlugin {}).unwrap();
+        let (mut sink, mut stream) = channel(&plugin, 1);
+        let results = runtime.block_on(async move {
+            let send = async move {
+                for _ in 0..3 {
+                    poll_fn(|cx| Pin::new(&mut sink).poll_ready(cx)).await.unwrap();
+                    Pin::new(&mut sink).start_send(()).unwrap();
+                }
+                poll_fn(|cx| Pin::new(&mut sink).poll_close(cx)).await.unwrap();
+            };
+            let receive = async move {
+                let mut results = Vec::new();
+                while let Some((_id, result)) = poll_fn(|cx| Pin::new(&mut stream).poll_next(cx)).await {
+                    results.push(result.map(Into::into));
+                }
+                results
+            };
+            tokio::join!(send, receive).1
+        });
+        assert_eq!(results, vec![Ok(Ok::<String, String>("hello".to_string())); 3]);
+        drop(plugin);
+        drop(dummy_runtime);
+        let (_res1, _res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+    }
+}
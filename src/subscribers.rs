@@ -0,0 +1,404 @@
+use crate::{PluginCallResult, PluginError, PluginResult};
+use crate::runtime::{CallTiming, PendingPermit, PluginOpCallId, RuntimeResult, TaggedResult, TimedResult};
+use std::collections::HashMap;
+use std::sync::mpsc::{RecvTimeoutError, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// How many shards `SubscriberMap::with_capacity` splits pending calls into.
+pub const DEFAULT_SHARDS: usize = 16;
+
+/// Where a call's result goes: the call's own channel, or a channel shared by several calls
+/// whose results are tagged with their id. Either way it receives the plugin's result, or an
+/// error when the call was aborted by the runtime.
+pub enum ResultSender<P: PluginCallResult> {
+    Call(Sender<PluginResult<RuntimeResult<P>>>),
+    Tagged(Sender<TaggedResult<P>>),
+    /// Like `Tagged`, and runs the callback after every send, to wake up an async consumer.
+    Notify(Sender<TaggedResult<P>>, Arc<dyn Send + Sync + Fn()>),
+    /// Reusable single-result slot backing `Plugin::execute_pooled`.
+    Slot(SlotSender<P>),
+    /// Like `Call`, for a streamed call whose unread items are capped by the backlog.
+    Stream(Sender<PluginResult<RuntimeResult<P>>>, Arc<Backlog>),
+    /// Like `Call`, along with the call's `CallTiming`. Only the final result is timed, items
+    /// pushed before it come with a zero timing.
+    Timed(Sender<TimedResult<P>>),
+}
+
+impl<P: PluginCallResult> ResultSender<P> {
+    /// Returns false when the receiving end is gone.
+    pub fn send(&self, id: PluginOpCallId, result: PluginResult<RuntimeResult<P>>) -> bool {
+        self.send_timed(id, result, CallTiming::default())
+    }
+
+    fn send_timed(&self, id: PluginOpCallId, result: PluginResult<RuntimeResult<P>>, timing: CallTiming) -> bool {
+        match self {
+            ResultSender::Call(sender) | ResultSender::Stream(sender, _) => sender.send(result).is_ok(),
+            ResultSender::Tagged(sender) => sender.send((id, result)).is_ok(),
+            ResultSender::Notify(sender, notify) => {
+                let sent = sender.send((id, result)).is_ok();
+                notify();
+                sent
+            }
+            ResultSender::Slot(sender) => sender.send(result),
+            ResultSender::Timed(sender) => sender.send((result, timing)).is_ok(),
+        }
+    }
+}
+
+struct SlotState<P: PluginCallResult> {
+    result: Option<PluginResult<RuntimeResult<P>>>,
+    closed: bool,
+}
+
+/// Holds a single result, the allocation-free counterpart of a fresh channel per call. The
+/// caller keeps the slot and the subscriber gets a `SlotSender`, which closes the slot when
+/// dropped so a subscriber discarded without a result doesn't leave the caller waiting.
+pub(crate) struct Slot<P: PluginCallResult> {
+    state: Mutex<SlotState<P>>,
+    ready: Condvar,
+}
+
+pub struct SlotSender<P: PluginCallResult>(Arc<Slot<P>>);
+
+impl<P: PluginCallResult> Slot<P> {
+    pub(crate) fn new() -> Arc<Self> {
+        Arc::new(Slot {
+            state: Mutex::new(SlotState { result: None, closed: false }),
+            ready: Condvar::new(),
+        })
+    }
+
+    /// Opens the slot for a new call, the previous sender must be gone.
+    pub(crate) fn sender(self: &Arc<Self>) -> SlotSender<P> {
+        let mut state = self.state.lock().unwrap();
+        state.result = None;
+        state.closed = false;
+        SlotSender(self.clone())
+    }
+
+    /// Blocks until a result is sent, for at most `timeout` if given. Fails like
+    /// `Receiver::recv_timeout`, with `Disconnected` when the sender was dropped without one.
+    pub(crate) fn wait(&self, timeout: Option<Duration>) -> Result<PluginResult<RuntimeResult<P>>, RecvTimeoutError> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(result) = state.result.take() {
+                return Ok(result);
+            }
+            if state.closed {
+                return Err(RecvTimeoutError::Disconnected);
+            }
+            state = match deadline {
+                Some(deadline) => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    if remaining.is_zero() {
+                        return Err(RecvTimeoutError::Timeout);
+                    }
+                    self.ready.wait_timeout(state, remaining).unwrap().0
+                }
+                None => self.ready.wait(state).unwrap(),
+            };
+        }
+    }
+}
+
+impl<P: PluginCallResult> SlotSender<P> {
+    fn send(&self, result: PluginResult<RuntimeResult<P>>) -> bool {
+        self.0.state.lock().unwrap().result = Some(result);
+        self.0.ready.notify_all();
+        true
+    }
+}
+
+impl<P: PluginCallResult> Drop for SlotSender<P> {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.0.state.lock() {
+            state.closed = true;
+        }
+        self.0.ready.notify_all();
+    }
+}
+
+struct BacklogState {
+    buffered: usize,
+    closed: bool,
+}
+
+/// Counts the items pushed for a streamed call that its consumer hasn't read yet, so the event
+/// loop is held back once `max` of them wait, see `PluginRuntimeBuilder::max_buffered`.
+pub struct Backlog {
+    max: usize,
+    state: Mutex<BacklogState>,
+    drained: Condvar,
+}
+
+impl Backlog {
+    pub(crate) fn new(max: usize) -> Arc<Self> {
+        Arc::new(Backlog {
+            max: max.max(1),
+            state: Mutex::new(BacklogState { buffered: 0, closed: false }),
+            drained: Condvar::new(),
+        })
+    }
+
+    /// Counts one more item. When `max` items are unread, either blocks until the consumer reads
+    /// one or goes away, or returns false right away if `wait` is false.
+    pub(crate) fn admit(&self, wait: bool) -> bool {
+        let mut state = self.state.lock().unwrap();
+        while !state.closed && state.buffered >= self.max {
+            if !wait {
+                return false;
+            }
+            state = self.drained.wait(state).unwrap();
+        }
+        state.buffered += 1;
+        true
+    }
+
+    pub(crate) fn read(&self) {
+        if let Ok(mut state) = self.state.lock() {
+            state.buffered = state.buffered.saturating_sub(1);
+        }
+        self.drained.notify_one();
+    }
+
+    /// Lets every blocked push through, the consumer is gone.
+    pub(crate) fn close(&self) {
+        if let Ok(mut state) = self.state.lock() {
+            state.closed = true;
+        }
+        self.drained.notify_all();
+    }
+}
+
+/// Receives the progress an event loop reports for a call, see `Handle::progress`.
+pub type ProgressCallback = Arc<dyn Send + Sync + Fn(f32)>;
+
+/// Sees a call's final result and how long it took, see `CallObserver::after_result`.
+pub type ResultCallback<P> = Arc<dyn Send + Sync + Fn(PluginOpCallId, &PluginResult<RuntimeResult<P>>, Duration)>;
+
+pub struct Subscriber<P: PluginCallResult> {
+    pub plugin: String,
+    pub sender: ResultSender<P>,
+    pub progress: Option<ProgressCallback>,
+    /// When the call was registered, see `SubscriberMap::ages`.
+    pub since: Instant,
+    /// When an event loop first took the call, see `SubscriberMap::received`.
+    pub received: Option<Instant>,
+    /// Slot the call holds in the runtime's `max_pending`, freed with the subscriber.
+    pub permit: Option<PendingPermit>,
+    pub observer: Option<ResultCallback<P>>,
+}
+
+impl<P: PluginCallResult> Subscriber<P> {
+    /// Hands the call's final result to the observer, if any, then to the caller. Returns false
+    /// when the caller is gone.
+    pub fn complete(&self, id: PluginOpCallId, result: PluginResult<RuntimeResult<P>>) -> bool {
+        if let Some(ref observer) = self.observer {
+            observer(id, &result, self.since.elapsed());
+        }
+        self.sender.send_timed(id, result, self.timing())
+    }
+
+    /// Where the call's time went so far.
+    pub fn timing(&self) -> CallTiming {
+        let now = Instant::now();
+        let received = self.received.unwrap_or(self.since);
+        CallTiming {
+            queued: received.saturating_duration_since(self.since),
+            processing: now.saturating_duration_since(received),
+            total: now.saturating_duration_since(self.since),
+        }
+    }
+}
+
+/// Pending calls keyed by id, split into shards so callers registering a call and the result
+/// loop delivering another one rarely contend on the same lock.
+pub struct SubscriberMap<P: PluginCallResult> {
+    shards: Vec<Mutex<HashMap<PluginOpCallId, Subscriber<P>>>>,
+}
+
+impl<P: PluginCallResult> SubscriberMap<P> {
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self::with_shards(DEFAULT_SHARDS, capacity)
+    }
+
+    /// Splits pending calls into `shards` shards, at least one. A single shard is a plain map
+    /// behind one lock.
+    pub fn with_shards(shards: usize, capacity: usize) -> Self {
+        let shards = shards.max(1);
+        SubscriberMap {
+            shards: (0..shards).map(|_| Mutex::new(HashMap::with_capacity(capacity / shards))).collect(),
+        }
+    }
+
+    fn shard_index(&self, id: &PluginOpCallId) -> usize {
+        id.as_u128() as usize % self.shards.len()
+    }
+
+    fn shard(&self, id: &PluginOpCallId) -> &Mutex<HashMap<PluginOpCallId, Subscriber<P>>> {
+        &self.shards[self.shard_index(id)]
+    }
+
+    pub fn insert(&self, id: PluginOpCallId, subscriber: Subscriber<P>) -> PluginResult<()> {
+        self.shard(&id).lock()
+            .map_err(|e| PluginError::RuntimeError(e.to_string()))?
+            .insert(id, subscriber);
+        Ok(())
+    }
+
+    pub fn remove(&self, id: &PluginOpCallId) -> Option<Subscriber<P>> {
+        self.shard(id).lock().ok()?.remove(id)
+    }
+
+    /// Runs `f` on a subscriber while leaving it registered.
+    pub fn with<R, F: FnOnce(&Subscriber<P>) -> R>(&self, id: &PluginOpCallId, f: F) -> Option<R> {
+        self.shard(id).lock().ok()?.get(id).map(f)
+    }
+
+    /// Records that an event loop took the call, unless one already did, and returns how long
+    /// it waited for that. `None` for a call taken again after being deferred.
+    pub fn received(&self, id: &PluginOpCallId) -> Option<Duration> {
+        let mut shard = self.shard(id).lock().unwrap();
+        let subscriber = shard.get_mut(id)?;
+        if subscriber.received.is_some() {
+            return None;
+        }
+        let now = Instant::now();
+        subscriber.received = Some(now);
+        Some(now.saturating_duration_since(subscriber.since))
+    }
+
+    /// Removes several subscribers, locking each shard at most once.
+    pub fn remove_many(&self, ids: &[PluginOpCallId]) -> Vec<Option<Subscriber<P>>> {
+        let mut removed: Vec<Option<Subscriber<P>>> = ids.iter().map(|_| None).collect();
+        for (index, shard) in self.shards.iter().enumerate() {
+            let mut guard = None;
+            for (position, id) in ids.iter().enumerate() {
+                if self.shard_index(id) != index {
+                    continue;
+                }
+                let shard = guard.get_or_insert_with(|| shard.lock().unwrap());
+                removed[position] = shard.remove(id);
+            }
+        }
+        removed
+    }
+
+    pub fn retain<F: Fn(&PluginOpCallId, &Subscriber<P>) -> bool>(&self, keep: F) {
+        for shard in &self.shards {
+            shard.lock().unwrap().retain(|id, subscriber| keep(id, subscriber));
+        }
+    }
+
+    pub fn drain(&self) -> Vec<(PluginOpCallId, Subscriber<P>)> {
+        self.shards.iter()
+            .flat_map(|shard| shard.lock().unwrap().drain().collect::<Vec<_>>())
+            .collect()
+    }
+
+    /// Snapshot of the pending ids, each shard is only locked while it is copied.
+    pub fn ids(&self) -> Vec<PluginOpCallId> {
+        self.shards.iter()
+            .flat_map(|shard| shard.lock().unwrap().keys().copied().collect::<Vec<_>>())
+            .collect()
+    }
+
+    /// How long each pending call has been waiting, locking shards like `ids`.
+    pub fn ages(&self) -> Vec<(PluginOpCallId, Duration)> {
+        let now = Instant::now();
+        self.shards.iter()
+            .flat_map(|shard| shard.lock().unwrap().iter()
+                .map(|(id, subscriber)| (*id, now.saturating_duration_since(subscriber.since)))
+                .collect::<Vec<_>>())
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.lock().unwrap().len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Receives what is published to a topic, see `Plugin::subscribe`.
+pub struct TopicSubscriber<P: PluginCallResult> {
+    pub plugin: String,
+    pub sender: Sender<RuntimeResult<P>>,
+}
+
+/// Topic subscriptions, keyed by topic then by subscription id. Unlike calls they stay
+/// registered across results, until the caller drops its `Subscription`, the plugin it
+/// subscribed through fails or the runtime stops.
+pub struct TopicMap<P: PluginCallResult> {
+    topics: Mutex<HashMap<String, HashMap<PluginOpCallId, TopicSubscriber<P>>>>,
+}
+
+impl<P: PluginCallResult> Default for TopicMap<P> {
+    fn default() -> Self {
+        TopicMap {
+            topics: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<P: PluginCallResult> TopicMap<P> {
+    pub fn insert(&self, topic: String, id: PluginOpCallId, subscriber: TopicSubscriber<P>) -> PluginResult<()> {
+        self.topics.lock()
+            .map_err(|e| PluginError::RuntimeError(e.to_string()))?
+            .entry(topic)
+            .or_default()
+            .insert(id, subscriber);
+        Ok(())
+    }
+
+    /// Forgets the topic along with its last subscription.
+    pub fn remove(&self, topic: &str, id: &PluginOpCallId) -> Option<TopicSubscriber<P>> {
+        let mut topics = self.topics.lock().ok()?;
+        let subscribers = topics.get_mut(topic)?;
+        let removed = subscribers.remove(id);
+        if subscribers.is_empty() {
+            topics.remove(topic);
+        }
+        removed
+    }
+
+    /// Sends `result` to every subscription to `topic` and returns how many got it, dropping
+    /// the subscriptions whose receiver is gone.
+    pub fn publish(&self, topic: &str, result: RuntimeResult<P>) -> usize {
+        let mut topics = self.topics.lock().unwrap();
+        let subscribers = match topics.get_mut(topic) {
+            Some(subscribers) => subscribers,
+            None => return 0,
+        };
+        subscribers.retain(|_id, subscriber| subscriber.sender.send(result.clone()).is_ok());
+        let delivered = subscribers.len();
+        if delivered == 0 {
+            topics.remove(topic);
+        }
+        delivered
+    }
+
+    /// Ends the subscriptions made through `plugin`.
+    pub fn remove_plugin(&self, plugin: &str) {
+        let mut topics = self.topics.lock().unwrap();
+        for subscribers in topics.values_mut() {
+            subscribers.retain(|_id, subscriber| subscriber.plugin != plugin);
+        }
+        topics.retain(|_topic, subscribers| !subscribers.is_empty());
+    }
+
+    /// Ends every subscription.
+    pub fn clear(&self) {
+        if let Ok(mut topics) = self.topics.lock() {
+            topics.clear();
+        }
+    }
+
+    pub fn subscriptions(&self, topic: &str) -> usize {
+        self.topics.lock().unwrap().get(topic).map_or(0, HashMap::len)
+    }
+}
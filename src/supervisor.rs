@@ -0,0 +1,98 @@
+use crate::{PluginData, PluginError};
+use crate::runtime::Handle;
+use std::fmt::Debug;
+use std::panic::{catch_unwind, resume_unwind, AssertUnwindSafe};
+use std::time::Duration;
+
+/// How often a `Supervisor` restarts a dying event loop and how long it waits in between.
+/// The wait doubles after every restart.
+#[derive(Debug, Clone)]
+pub struct RestartPolicy {
+    pub max_restarts: usize,
+    pub backoff: Duration,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        RestartPolicy {
+            max_restarts: 3,
+            backoff: Duration::from_millis(100),
+        }
+    }
+}
+
+/// Keeps an event loop alive by restarting it when it errors or panics.
+///
+/// The loop is restarted on a clone of the same `Handle`, so plugins keep their channels.
+/// Calls in flight when the loop dies fail with `PluginError::Restarted`. The result loop only
+/// exits on shutdown, so it is never restarted.
+pub struct Supervisor {
+    policy: RestartPolicy,
+}
+
+impl Supervisor {
+    pub fn new(policy: RestartPolicy) -> Self {
+        Supervisor { policy }
+    }
+
+    /// Runs `event_loop` until it returns `Ok` or exhausts the restart policy, in which case the
+    /// last error is returned, or the last panic resumed.
+    pub fn supervise<P, F, E>(&self, handle: Handle<P>, mut event_loop: F) -> Result<(), E>
+        where P: PluginData, F: FnMut(Handle<P>) -> Result<(), E>, E: Debug {
+        let mut restarts = 0;
+        let mut backoff = self.policy.backoff;
+        loop {
+            let reason = match catch_unwind(AssertUnwindSafe(|| event_loop(handle.clone()))) {
+                Ok(Ok(())) => return Ok(()),
+                Ok(Err(e)) if restarts >= self.policy.max_restarts => return Err(e),
+                Err(panic) if restarts >= self.policy.max_restarts => resume_unwind(panic),
+                Ok(Err(e)) => format!("{:?}", e),
+                Err(_) => "event loop panicked".to_string(),
+            };
+            eprintln!("restarting event loop: {}", reason);
+            handle.abort_pending(PluginError::Restarted(reason));
+            restarts += 1;
+            std::thread::sleep(backoff);
+            backoff *= 2;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tokio_utils::create_tokio_runtime;
+    use crate::test_utils::{dummy_event_loop, DummyPlugin};
+    use crate::runtime::PluginRuntime;
+    use crate::supervisor::{RestartPolicy, Supervisor};
+    use crate::PluginErrorKind;
+    use std::time::Duration;
+
+    #[test]
+    fn restart_event_loop() {
+        let mut dummy_runtime = PluginRuntime::new();
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let handle2 = runtime.spawn(async move {
+            let supervisor = Supervisor::new(RestartPolicy { max_restarts: 1, backoff: Duration::from_millis(1) });
+            let mut crashed = false;
+            supervisor.supervise(handle, move |handle| {
+                if !crashed {
+                    crashed = true;
+                    handle.receive().map_err(|e| e.to_string())?;
+                    return Err("crashed".to_string());
+                }
+                dummy_event_loop(handle)
+            })
+        });
+        let plugin = dummy_runtime.load_plugin(DummyPlugin {}).unwrap();
+        assert_eq!(plugin.execute(()).err().map(|e| e.kind()), Some(PluginErrorKind::Restarted));
+        assert_eq!(plugin.execute(()).unwrap(), Ok("hello".to_string()));
+        drop(plugin);
+        drop(dummy_runtime);
+        let (_res1, res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+        assert_eq!(res2.unwrap(), Ok(()));
+    }
+}
@@ -1,6 +1,9 @@
-use crate::{PluginCallResult, PluginData, PluginResult};
-use crate::runtime::{PluginRuntime, Handle};
-use crate::source::PluginSource;
+use crate::{PluginCallResult, PluginData, PluginInit, PluginResult, PluginError};
+use crate::runtime::{PluginRuntime, Handle, ReceiveError, RuntimeOptions};
+use crate::source::{PluginDescriptor, PluginSource};
+use std::borrow::Cow;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 #[derive(Clone)]
 pub(crate) struct DummyPlugin {}
@@ -20,6 +23,16 @@ impl PluginData for DummyPlugin {
     fn name(&self) -> String {
         "test".to_string()
     }
+
+    fn name_ref(&self) -> Cow<'_, str> {
+        Cow::Borrowed("test")
+    }
+}
+
+impl PluginInit for DummyPlugin {
+    type InitParams = String;
+
+    fn init_call(&self, _init: Self::InitParams) -> Self::PluginCall {}
 }
 
 pub(crate) struct DummySource {}
@@ -33,17 +46,445 @@ impl PluginSource for DummySource {
     fn open<P: Into<String>>(&mut self, _plugin: P) -> PluginResult<Self::PluginType> {
         Ok(DummyPlugin {})
     }
+
+    fn digest(&self, _plugin: &str) -> Option<String> {
+        Some("v1".to_string())
+    }
+
+    fn describe(&self) -> Vec<PluginDescriptor> {
+        vec![PluginDescriptor {
+            version: Some("v1".to_string()),
+            ..PluginDescriptor::new("test")
+        }]
+    }
+}
+
+pub(crate) struct FlakySource {}
+impl PluginSource for FlakySource {
+    type PluginType = DummyPlugin;
+
+    fn plugins(&self) -> Vec<String> {
+        vec!["test".to_string(), "broken".to_string()]
+    }
+
+    fn open<P: Into<String>>(&mut self, plugin: P) -> PluginResult<Self::PluginType> {
+        match plugin.into().as_str() {
+            "broken" => Err(PluginError::InvalidPlugin("broken".to_string())),
+            _ => Ok(DummyPlugin {}),
+        }
+    }
+}
+
+/// Lists `test` twice, and `alias`, which opens a plugin named `test` as well.
+pub(crate) struct DuplicateSource {}
+impl PluginSource for DuplicateSource {
+    type PluginType = DummyPlugin;
+
+    fn plugins(&self) -> Vec<String> {
+        vec!["alias".to_string(), "test".to_string(), "test".to_string()]
+    }
+
+    fn open<P: Into<String>>(&mut self, _plugin: P) -> PluginResult<Self::PluginType> {
+        Ok(DummyPlugin {})
+    }
+}
+
+/// Opens `test` at most `max_opens` times, then reports it as invalid.
+pub(crate) struct ExhaustibleSource {
+    pub(crate) opens: Arc<AtomicUsize>,
+    pub(crate) max_opens: usize,
+}
+
+impl PluginSource for ExhaustibleSource {
+    type PluginType = DummyPlugin;
+
+    fn plugins(&self) -> Vec<String> {
+        vec!["test".to_string()]
+    }
+
+    fn open<P: Into<String>>(&mut self, plugin: P) -> PluginResult<Self::PluginType> {
+        if self.opens.fetch_add(1, Ordering::SeqCst) >= self.max_opens {
+            return Err(PluginError::InvalidPlugin(plugin.into()));
+        }
+        Ok(DummyPlugin {})
+    }
+}
+
+/// Lists `alias`, which opens a plugin named `test`, at the digest in `version`.
+pub(crate) struct VersionedSource {
+    pub(crate) opens: Arc<AtomicUsize>,
+    pub(crate) version: Arc<Mutex<String>>,
+}
+
+impl PluginSource for VersionedSource {
+    type PluginType = DummyPlugin;
+
+    fn plugins(&self) -> Vec<String> {
+        vec!["alias".to_string()]
+    }
+
+    fn open<P: Into<String>>(&mut self, plugin: P) -> PluginResult<Self::PluginType> {
+        match plugin.into().as_str() {
+            "alias" => {
+                self.opens.fetch_add(1, Ordering::SeqCst);
+                Ok(DummyPlugin {})
+            }
+            other => Err(PluginError::InvalidPlugin(other.to_string())),
+        }
+    }
+
+    fn digest(&self, _plugin: &str) -> Option<String> {
+        Some(self.version.lock().unwrap().clone())
+    }
+}
+
+pub(crate) struct PanickySource {}
+impl PluginSource for PanickySource {
+    type PluginType = DummyPlugin;
+
+    fn plugins(&self) -> Vec<String> {
+        vec!["panicky".to_string(), "test".to_string()]
+    }
+
+    fn open<P: Into<String>>(&mut self, plugin: P) -> PluginResult<Self::PluginType> {
+        match plugin.into().as_str() {
+            "panicky" => panic!("bad manifest"),
+            _ => Ok(DummyPlugin {}),
+        }
+    }
 }
 
 pub(crate) fn dummy_event_loop(handle: Handle<DummyPlugin>) -> Result<(), String> {
+    loop {
+        match handle.receive() {
+            Ok(r) => handle.resolve(r.call_id, "hello".to_string()),
+            Err(ReceiveError::Disconnected) => return Ok(()),
+            Err(e) => return Err(e.to_string()),
+        }
+    }
+}
+
+/// Leaves the first call unanswered and answers the second with "fresh" if it came with a new
+/// id and the first was cancelled, "stale" otherwise. Rejects the third with "nope", then
+/// resolves every later call with "hello".
+pub(crate) fn retry_event_loop(handle: Handle<DummyPlugin>) -> Result<(), String> {
+    let first = handle.receive().map_err(|e| e.to_string())?.id();
+    let second = handle.receive().map_err(|e| e.to_string())?.id();
+    let fresh = second != first && handle.is_cancelled(first);
+    handle.resolve(second, if fresh { "fresh" } else { "stale" }.to_string());
+    let third = handle.receive().map_err(|e| e.to_string())?.id();
+    handle.reject(third, "nope".to_string());
+    dummy_event_loop(handle)
+}
+
+/// Resolves its first call with "late" after 30ms, then every other one with "hello" right
+/// away. Expects no load call.
+pub(crate) fn late_first_event_loop(handle: Handle<DummyPlugin>) -> Result<(), String> {
+    if let Ok(r) = handle.receive() {
+        std::thread::sleep(std::time::Duration::from_millis(30));
+        handle.resolve(r.id(), "late".to_string());
+    }
+    dummy_event_loop(handle)
+}
+
+/// Resolves calls with "hello" after a delay cycling from none to 1.5ms, so results land
+/// around short caller timeouts.
+pub(crate) fn jittery_event_loop(handle: Handle<DummyPlugin>) -> Result<(), String> {
+    let mut served = 0u64;
+    while let Ok(r) = handle.receive() {
+        std::thread::sleep(std::time::Duration::from_micros(served % 4 * 500));
+        handle.resolve(r.id(), "hello".to_string());
+        served += 1;
+    }
+    Ok(())
+}
+
+pub(crate) fn header_event_loop(handle: Handle<DummyPlugin>, header: &str) -> Result<(), String> {
+    while let Ok(r) = handle.receive() {
+        let value = r.headers.get(header).cloned().unwrap_or_default();
+        handle.resolve(r.call_id, value);
+    }
+    Ok(())
+}
+
+pub(crate) fn load_only_event_loop(handle: Handle<DummyPlugin>) -> Result<(), String> {
+    if let Ok(r) = handle.receive() {
+        handle.resolve(r.call_id, "loaded".to_string());
+    }
+    while handle.receive().is_ok() {}
+    Ok(())
+}
+
+pub(crate) fn cancellable_event_loop(handle: Handle<DummyPlugin>) -> Result<(), String> {
+    if let Ok(r) = handle.receive() {
+        handle.resolve(r.call_id, "loaded".to_string());
+    }
+    while let Ok(r) = handle.receive() {
+        while !handle.is_cancelled(r.call_id) {
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        handle.reject(r.call_id, "cancelled".to_string());
+    }
+    Ok(())
+}
+
+pub(crate) fn deadline_event_loop(handle: Handle<DummyPlugin>) -> Result<(), String> {
+    if let Ok(r) = handle.receive() {
+        handle.resolve(r.call_id, "loaded".to_string());
+    }
+    while let Ok(r) = handle.receive() {
+        match r.deadline() {
+            None => handle.resolve(r.call_id, "no deadline".to_string()),
+            Some(_) if r.is_expired() => handle.reject(r.call_id, "expired".to_string()),
+            Some(_) => handle.resolve(r.call_id, "in time".to_string()),
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn streaming_event_loop(handle: Handle<DummyPlugin>, items: usize) -> Result<(), String> {
+    if let Ok(r) = handle.receive() {
+        handle.resolve(r.call_id, "loaded".to_string());
+    }
+    while let Ok(r) = handle.receive() {
+        for item in 0..items {
+            handle.push(r.call_id, item.to_string());
+        }
+        handle.end(r.call_id);
+    }
+    Ok(())
+}
+
+/// Pushes two items, reports on `refused` whether a third one was refused, then pushes it
+/// anyway along with a fourth and resolves with "done".
+pub(crate) fn lagging_stream_event_loop(handle: Handle<DummyPlugin>, refused: std::sync::mpsc::Sender<bool>) -> Result<(), String> {
+    while let Ok(r) = handle.receive() {
+        handle.push(r.call_id, "0".to_string());
+        handle.push(r.call_id, "1".to_string());
+        let _ = refused.send(handle.try_push(r.call_id, "2".to_string()).is_err());
+        handle.push(r.call_id, "2".to_string());
+        handle.push(r.call_id, "3".to_string());
+        handle.resolve(r.call_id, "done".to_string());
+    }
+    Ok(())
+}
+
+pub(crate) fn progress_event_loop(handle: Handle<DummyPlugin>) -> Result<(), String> {
+    if let Ok(r) = handle.receive() {
+        handle.resolve(r.call_id, "loaded".to_string());
+    }
+    while let Ok(r) = handle.receive() {
+        for fraction in [-1.0, f32::NAN, 0.5, 2.0].iter() {
+            handle.progress(r.call_id, *fraction);
+        }
+        handle.resolve(r.call_id, "done".to_string());
+        handle.progress(r.call_id, 1.0);
+    }
+    Ok(())
+}
+
+/// Answers calls in groups of `size`, last received first, with the position they came in at.
+pub(crate) fn reversing_event_loop(handle: Handle<DummyPlugin>, size: usize) -> Result<(), String> {
+    if let Ok(r) = handle.receive() {
+        handle.resolve(r.call_id, "loaded".to_string());
+    }
+    loop {
+        let mut ids = Vec::with_capacity(size);
+        while ids.len() < size {
+            match handle.receive() {
+                Ok(r) => ids.push(r.call_id),
+                Err(_) => return Ok(()),
+            }
+        }
+        for (position, id) in ids.into_iter().enumerate().rev() {
+            handle.resolve(id, position.to_string());
+        }
+    }
+}
+
+pub(crate) fn failing_event_loop(handle: Handle<DummyPlugin>) -> Result<(), String> {
+    if let Ok(r) = handle.receive() {
+        handle.resolve(r.call_id, "loaded".to_string());
+    }
     while let Ok(r) = handle.receive() {
-        handle.resolve(r.call_id, "hello".to_string());
+        handle.fail(&r.plugin_data, "unrecoverable");
     }
     Ok(())
 }
 
 pub(crate) fn build_dummy_runtime() -> PluginRuntime<DummyPlugin> {
-    PluginRuntime::builder()
-        .plugin_loader(Box::new(|_plugin| ()))
-        .build()
-}
\ No newline at end of file
+    PluginRuntime::with_options(RuntimeOptions {
+        plugin_loader: Some(Box::new(|_plugin| ())),
+        ..Default::default()
+    })
+}
+
+#[derive(Clone)]
+pub(crate) enum KindPlugin {
+    Greeter,
+    Counter(usize),
+}
+
+impl PluginData for KindPlugin {
+    type PluginCall = ();
+    type PluginCallResult = DummyResult;
+
+    fn name(&self) -> String {
+        match self {
+            KindPlugin::Greeter => "greeter".to_string(),
+            KindPlugin::Counter(_) => "counter".to_string(),
+        }
+    }
+
+    fn capabilities(&self) -> Vec<String> {
+        match self {
+            KindPlugin::Greeter => vec!["greet".to_string()],
+            KindPlugin::Counter(_) => vec!["count".to_string()],
+        }
+    }
+}
+
+pub(crate) fn kind_event_loop(handle: Handle<KindPlugin>) -> Result<(), String> {
+    while let Ok(r) = handle.receive() {
+        match r.plugin() {
+            KindPlugin::Greeter => handle.resolve(r.id(), "hello".to_string()),
+            KindPlugin::Counter(start) => handle.resolve(r.id(), start.to_string()),
+        }
+    }
+    Ok(())
+}
+/// Lists the greeter in the "greetings" group and leaves the counter ungrouped.
+pub(crate) struct KindSource {}
+impl PluginSource for KindSource {
+    type PluginType = KindPlugin;
+
+    fn plugins(&self) -> Vec<String> {
+        vec!["greeter".to_string(), "counter".to_string()]
+    }
+
+    fn open<P: Into<String>>(&mut self, plugin: P) -> PluginResult<Self::PluginType> {
+        match plugin.into().as_str() {
+            "greeter" => Ok(KindPlugin::Greeter),
+            "counter" => Ok(KindPlugin::Counter(0)),
+            name => Err(PluginError::InvalidPlugin(name.to_string())),
+        }
+    }
+
+    fn describe(&self) -> Vec<PluginDescriptor> {
+        vec![
+            PluginDescriptor {
+                group: Some("greetings".to_string()),
+                timeout: Some(std::time::Duration::from_secs(5)),
+                ..PluginDescriptor::new("greeter")
+            },
+            PluginDescriptor::new("counter"),
+        ]
+    }
+}
+
+/// Lists both kinds in the "replicas" group, as interchangeable plugins.
+pub(crate) struct ReplicaSource {}
+impl PluginSource for ReplicaSource {
+    type PluginType = KindPlugin;
+
+    fn plugins(&self) -> Vec<String> {
+        KindSource {}.plugins()
+    }
+
+    fn open<P: Into<String>>(&mut self, plugin: P) -> PluginResult<Self::PluginType> {
+        KindSource {}.open(plugin)
+    }
+
+    fn describe(&self) -> Vec<PluginDescriptor> {
+        self.plugins().into_iter().map(|name| PluginDescriptor {
+            group: Some("replicas".to_string()),
+            ..PluginDescriptor::new(name)
+        }).collect()
+    }
+}
+
+/// Like `kind_event_loop`, with the counter taking `lag` to answer.
+pub(crate) fn lagging_kind_event_loop(handle: Handle<KindPlugin>, lag: std::time::Duration) -> Result<(), String> {
+    while let Ok(r) = handle.receive() {
+        if let KindPlugin::Counter(_) = r.plugin() {
+            std::thread::sleep(lag);
+        }
+        match r.plugin() {
+            KindPlugin::Greeter => handle.resolve(r.id(), "hello".to_string()),
+            KindPlugin::Counter(start) => handle.resolve(r.id(), start.to_string()),
+        }
+    }
+    Ok(())
+}
+
+/// Logs its host-side hooks and the calls it receives, to check their ordering.
+#[derive(Clone)]
+pub(crate) struct HookPlugin {
+    pub(crate) log: Arc<Mutex<Vec<String>>>,
+}
+
+impl PluginData for HookPlugin {
+    type PluginCall = &'static str;
+    type PluginCallResult = DummyResult;
+
+    fn name(&self) -> String {
+        "hooked".to_string()
+    }
+
+    fn on_load(&self) {
+        self.log.lock().unwrap().push("on_load".to_string());
+    }
+
+    fn on_unload(&self) {
+        self.log.lock().unwrap().push("on_unload".to_string());
+    }
+
+    fn validate(&self) -> PluginResult<()> {
+        self.log.lock().unwrap().push("validate".to_string());
+        Ok(())
+    }
+}
+
+pub(crate) struct HookSource {
+    pub(crate) log: Arc<Mutex<Vec<String>>>,
+}
+
+impl PluginSource for HookSource {
+    type PluginType = HookPlugin;
+
+    fn plugins(&self) -> Vec<String> {
+        vec!["hooked".to_string()]
+    }
+
+    fn open<P: Into<String>>(&mut self, _plugin: P) -> PluginResult<Self::PluginType> {
+        Ok(HookPlugin { log: self.log.clone() })
+    }
+}
+
+pub(crate) fn hook_event_loop(handle: Handle<HookPlugin>) -> Result<(), String> {
+    while let Ok(r) = handle.receive() {
+        r.plugin().log.lock().unwrap().push(r.call.to_string());
+        handle.resolve(r.id(), "done".to_string());
+    }
+    Ok(())
+}
+
+/// Serves calls with `Handle::serve`: "load" resets a counter, any other call bumps it and
+/// answers with it, but the handler panics whenever the counter reaches 2.
+pub(crate) fn serving_event_loop(handle: Handle<HookPlugin>) -> Result<(), String> {
+    let mut count = 0;
+    while let Ok(r) = handle.receive() {
+        handle.serve(r, |call| {
+            if *call.call() == "load" {
+                count = 0;
+                return Ok("loaded".to_string());
+            }
+            count += 1;
+            if count == 2 {
+                panic!("corrupted");
+            }
+            Ok(count.to_string())
+        });
+    }
+    Ok(())
+}
@@ -0,0 +1,98 @@
+use crate::{PluginCallOutcome, PluginData, PluginError, PluginResult};
+use crate::runtime::{PluginOpCall, PluginRuntime, ResultLoop, RuntimeMessage};
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+
+/// Result loop driven by hand, see `PluginRuntime::run_manual`.
+///
+/// Nothing runs in the background: calls wait in the call channel until the test takes them
+/// from the `Handle`, and results wait in the result channel until `step` delivers them. Pair
+/// it with `Plugin::execute_into` so the test thread never blocks on a result.
+pub struct ResultStepper<P: PluginData> {
+    result_receiver: Receiver<RuntimeMessage<P::PluginCallResult>>,
+    result_loop: ResultLoop<P>,
+    stopped: bool,
+}
+
+impl<P: PluginData> ResultStepper<P> {
+    pub(crate) fn new(result_receiver: Receiver<RuntimeMessage<P::PluginCallResult>>, result_loop: ResultLoop<P>) -> Self {
+        ResultStepper {
+            result_receiver,
+            result_loop,
+            stopped: false,
+        }
+    }
+
+    /// Delivers every message queued so far, in order, and returns how many were handled.
+    /// Does nothing once a shutdown was delivered.
+    pub fn step(&mut self) -> usize {
+        let mut handled = 0;
+        while !self.stopped {
+            match self.result_receiver.try_recv() {
+                Ok(message) => {
+                    handled += 1;
+                    self.stopped = !self.result_loop.process(vec![message]);
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => self.stopped = true,
+            }
+        }
+        handled
+    }
+
+    /// Whether the loop would have exited: a shutdown was delivered or every sender is gone.
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+}
+
+/// Round trip of a single call on the calling thread: loads `plugin_data` on a fresh runtime,
+/// sends it `call`, answers with `handler` and returns what `Plugin::execute` would have.
+pub fn run_once<P, H>(plugin_data: P, handler: H, call: P::PluginCall) -> PluginResult<PluginCallOutcome<P>>
+    where P: 'static + PluginData, H: FnOnce(&PluginOpCall<P>) -> PluginCallOutcome<P> {
+    let mut runtime = PluginRuntime::new();
+    let (mut stepper, handle) = runtime.run_manual();
+    let plugin = runtime.load_plugin(plugin_data)?;
+    let (result_sender, result_receiver) = channel();
+    plugin.execute_into(call, result_sender)?;
+    let received = handle.try_receive()
+        .map_err(|e| PluginError::RuntimeError(e.to_string()))?
+        .ok_or(PluginError::Disconnected)?;
+    handle.complete(received.id(), handler(&received));
+    stepper.step();
+    let (_id, result) = result_receiver.try_recv().map_err(|_| PluginError::Disconnected)?;
+    result.map(Into::into)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::runtime::PluginRuntime;
+    use crate::test_utils::DummyPlugin;
+    use crate::testing::run_once;
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn step_results_by_hand() {
+        let mut dummy_runtime = PluginRuntime::new();
+        let (mut stepper, handle) = dummy_runtime.run_manual();
+        let plugin = dummy_runtime.load_plugin(DummyPlugin {}).unwrap();
+        let (result_sender, result_receiver) = channel();
+        let id = plugin.execute_into((), result_sender).unwrap();
+        let call = handle.try_receive().unwrap().unwrap();
+        assert_eq!(call.id(), id);
+        handle.resolve(id, "hello");
+        assert!(result_receiver.try_recv().is_err());
+        assert_eq!(stepper.step(), 1);
+        let (result_id, result) = result_receiver.try_recv().unwrap();
+        assert_eq!(result_id, id);
+        assert_eq!(result.map(Into::into), Ok(Ok::<String, String>("hello".to_string())));
+        handle.shutdown(Ok("bye".to_string()));
+        assert_eq!(stepper.step(), 1);
+        assert!(stepper.is_stopped());
+    }
+
+    #[test]
+    fn round_trip_once() {
+        assert_eq!(run_once(DummyPlugin {}, |call| Ok(call.plugin_name().into_owned()), ()), Ok(Ok("test".to_string())));
+        assert_eq!(run_once(DummyPlugin {}, |_call| Err("nope".to_string()), ()), Ok(Err("nope".to_string())));
+    }
+}
@@ -1,4 +1,4 @@
 #[cfg(test)]
 pub(crate) fn create_tokio_runtime() -> tokio::runtime::Runtime {
-    tokio::runtime::Builder::new_multi_thread().build().unwrap()
+    tokio::runtime::Builder::new_multi_thread().worker_threads(4).build().unwrap()
 }
\ No newline at end of file
@@ -0,0 +1,139 @@
+use crate::{Plugin, PluginCallOutcome, PluginCallResult, PluginData, PluginResult};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TraceRecord<C, O, E> {
+    pub timestamp_ms: u128,
+    pub plugin: String,
+    pub call: C,
+    pub result: Result<O, E>,
+    pub latency: Duration,
+}
+
+pub type PluginTraceRecord<P> = TraceRecord<
+    <P as PluginData>::PluginCall,
+    <<P as PluginData>::PluginCallResult as PluginCallResult>::Ok,
+    <<P as PluginData>::PluginCallResult as PluginCallResult>::Err,
+>;
+
+/// Bounded recording of calls and their results, oldest records are evicted first.
+pub struct CallTrace<P: PluginData> {
+    capacity: usize,
+    enabled: AtomicBool,
+    records: Mutex<VecDeque<PluginTraceRecord<P>>>,
+}
+
+impl<P: PluginData> CallTrace<P> where P::PluginCall: Clone {
+    pub fn new(capacity: usize) -> Self {
+        CallTrace {
+            capacity,
+            enabled: AtomicBool::new(true),
+            records: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    pub fn from_records(capacity: usize, records: Vec<PluginTraceRecord<P>>) -> Self {
+        let trace = CallTrace::new(capacity);
+        for record in records {
+            trace.push(record);
+        }
+        trace
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::SeqCst);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::SeqCst)
+    }
+
+    pub fn records(&self) -> Vec<PluginTraceRecord<P>> {
+        self.records.lock().unwrap().iter().cloned().collect()
+    }
+
+    pub fn clear(&self) {
+        self.records.lock().unwrap().clear();
+    }
+
+    pub fn execute(&self, plugin: &Plugin<P>, call: P::PluginCall) -> PluginResult<PluginCallOutcome<P>> {
+        if !self.is_enabled() {
+            return plugin.execute(call);
+        }
+        let timestamp_ms = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+        let started = Instant::now();
+        let result = plugin.execute(call.clone())?;
+        self.push(TraceRecord {
+            timestamp_ms,
+            plugin: plugin.name(),
+            call,
+            result: result.clone(),
+            latency: started.elapsed(),
+        });
+        Ok(result)
+    }
+
+    /// Re-executes every recorded call against `plugin`, in recording order.
+    pub fn replay(&self, plugin: &Plugin<P>) -> Vec<PluginResult<PluginCallOutcome<P>>> {
+        self.records().into_iter()
+            .map(|record| plugin.execute(record.call))
+            .collect()
+    }
+
+    fn push(&self, record: PluginTraceRecord<P>) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut records = self.records.lock().unwrap();
+        if records.len() == self.capacity {
+            records.pop_front();
+        }
+        records.push_back(record);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::loader::PluginLoader;
+    use crate::test_utils::{build_dummy_runtime, dummy_event_loop, DummySource};
+    use crate::tokio_utils::create_tokio_runtime;
+    use crate::trace::CallTrace;
+
+    #[test]
+    fn record_and_replay() {
+        let mut dummy_runtime = build_dummy_runtime();
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let handle2 = runtime.spawn(async move {
+            dummy_event_loop(handle)
+        });
+        let mut dummy_loader = PluginLoader::new(DummySource{}, dummy_runtime);
+        let plugins = dummy_loader.load_plugins(vec![]);
+        let plugin = plugins.first().unwrap();
+        let trace = CallTrace::new(2);
+        for _ in 0..3 {
+            trace.execute(plugin, ()).unwrap().unwrap();
+        }
+        trace.set_enabled(false);
+        trace.execute(plugin, ()).unwrap().unwrap();
+        let records = trace.records();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].plugin, "test");
+        assert_eq!(records[0].result, Ok("hello".to_string()));
+        let replayed = CallTrace::from_records(2, records).replay(plugin);
+        assert_eq!(replayed.len(), 2);
+        for res in replayed {
+            assert_eq!(res.unwrap(), Ok("hello".to_string()));
+        }
+        drop(plugins);
+        drop(dummy_loader);
+        let (_res1, _res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+    }
+}
\ No newline at end of file
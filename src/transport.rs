@@ -0,0 +1,300 @@
+use crate::{PluginCallOutcome, PluginData, PluginError, PluginResult};
+use crate::framing::{write_frame, FrameReader};
+use crate::runtime::{Handle, PluginOpCall, ReceiveError};
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::io::{Read, Write};
+
+/// Version of the handshake `Remote` opens connections with, see `Hello`.
+pub const PROTOCOL_VERSION: ProtocolVersion = ProtocolVersion { major: 1, minor: 0 };
+
+/// Version of the protocol a host or plugin speaks. Two sides understand each other when they
+/// share the major version: minor versions only add to the protocol, e.g. capabilities.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProtocolVersion {
+    pub major: u32,
+    pub minor: u32,
+}
+
+impl ProtocolVersion {
+    pub fn is_compatible(&self, other: &ProtocolVersion) -> bool {
+        self.major == other.major
+    }
+}
+
+impl Display for ProtocolVersion {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+/// First message each side sends on a new connection, see `Remote::handshake`. On the wire
+/// it is UTF-8 text: `plao <major>.<minor>` on the first line, then one capability per line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hello {
+    pub version: ProtocolVersion,
+    pub capabilities: Vec<String>,
+}
+
+impl Hello {
+    pub fn new(capabilities: Vec<String>) -> Self {
+        Hello {
+            version: PROTOCOL_VERSION,
+            capabilities,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut lines = vec![format!("plao {}", self.version)];
+        lines.extend(self.capabilities.iter().cloned());
+        lines.join("\n").into_bytes()
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Hello, String> {
+        let text = std::str::from_utf8(bytes).map_err(|e| e.to_string())?;
+        let mut lines = text.split('\n');
+        let version = lines.next()
+            .and_then(|line| line.strip_prefix("plao "))
+            .ok_or_else(|| "not a plao hello".to_string())?;
+        let (major, minor) = version.split_once('.').ok_or_else(|| format!("bad version {}", version))?;
+        let parse = |part: &str| part.parse::<u32>().map_err(|_| format!("bad version {}", version));
+        Ok(Hello {
+            version: ProtocolVersion { major: parse(major)?, minor: parse(minor)? },
+            capabilities: lines.filter(|line| !line.is_empty()).map(str::to_string).collect(),
+        })
+    }
+}
+
+/// Turns calls into bytes and bytes back into results for a plugin living in another process,
+/// typically by forwarding to serde or a hand-written wire format.
+pub trait Codec<P: PluginData>: Send {
+    fn encode_call(&self, call: &P::PluginCall) -> Result<Vec<u8>, String>;
+
+    /// The plugin's own result, `Err` included; fail only when the bytes can't be read.
+    fn decode_result(&self, bytes: &[u8]) -> Result<PluginCallOutcome<P>, String>;
+}
+
+/// Carries one encoded call to a plugin and its encoded result back.
+pub trait Transport: Send {
+    /// `Ok(None)` when the plugin closed the connection instead of answering.
+    fn exchange(&mut self, request: &[u8]) -> PluginResult<Option<Vec<u8>>>;
+}
+
+/// Transport over a byte stream, such as a child process' stdio or a socket, with one frame
+/// each way per call, see `framing`.
+pub struct FramedTransport<R: Read, W: Write> {
+    reader: FrameReader<R>,
+    writer: W,
+}
+
+impl<R: Read, W: Write> FramedTransport<R, W> {
+    pub fn new(reader: R, writer: W) -> Self {
+        Self::with_reader(FrameReader::new(reader), writer)
+    }
+
+    /// Takes a reader configured with its own frame limit.
+    pub fn with_reader(reader: FrameReader<R>, writer: W) -> Self {
+        FramedTransport { reader, writer }
+    }
+}
+
+impl<R: Read + Send, W: Write + Send> Transport for FramedTransport<R, W> {
+    fn exchange(&mut self, request: &[u8]) -> PluginResult<Option<Vec<u8>>> {
+        write_frame(&mut self.writer, request)?;
+        self.writer.flush().map_err(|e| PluginError::RuntimeError(e.to_string()))?;
+        self.reader.read_frame()
+    }
+}
+
+type Connect<P, T> = Box<dyn Send + FnMut(&P) -> PluginResult<T>>;
+
+/// Event loop answering every call through a transport, so callers keep the in-process
+/// `Plugin::execute` whichever process the plugin runs in.
+///
+/// Each plugin gets its own connection, opened with `connect` on its first call. A call that
+/// can't cross the boundary fails with a `PluginError` rather than the plugin's error type:
+///
+/// * `connect` failing fails the call with its error,
+/// * a call `encode_call` rejects fails with `RuntimeError`,
+/// * a transport error fails with the transport's error, `RuntimeError` for I/O and
+///   `InvalidPlugin` for an oversized frame with `FramedTransport`,
+/// * the plugin closing the connection fails with `Disconnected`,
+/// * a result `decode_result` rejects fails with `InvalidPlugin`.
+///
+/// After a transport error or a closed connection, the next call to that plugin reconnects.
+pub struct Remote<P: PluginData, C: Codec<P>, T: Transport> {
+    codec: C,
+    connect: Connect<P, T>,
+    connections: HashMap<String, T>,
+    hello: Option<Hello>,
+}
+
+impl<P: PluginData, C: Codec<P>, T: Transport> Remote<P, C, T> {
+    pub fn new<F: 'static + Send + FnMut(&P) -> PluginResult<T>>(codec: C, connect: F) -> Self {
+        Remote {
+            codec,
+            connect: Box::new(connect),
+            connections: HashMap::new(),
+            hello: None,
+        }
+    }
+
+    /// Opens every connection with a handshake: the host sends a `Hello` with
+    /// `PROTOCOL_VERSION` and `capabilities`, and the plugin answers with its own. The
+    /// connection is dropped and the call fails with `PluginError::InvalidPlugin` when the
+    /// plugin speaks another major version, or lacks any of the capabilities its
+    /// `PluginData::capabilities` declares. Since connecting happens on the first call, a
+    /// runtime with a `plugin_loader` fails the load itself.
+    pub fn handshake(mut self, capabilities: Vec<String>) -> Self {
+        self.hello = Some(Hello::new(capabilities));
+        self
+    }
+
+    /// Serves calls until the runtime disconnects.
+    pub fn run(mut self, handle: Handle<P>) -> Result<(), ReceiveError> {
+        loop {
+            let call = match handle.receive() {
+                Ok(call) => call,
+                Err(ReceiveError::Disconnected) => return Ok(()),
+                Err(e) => return Err(e),
+            };
+            match self.forward(&call) {
+                Ok(result) => handle.complete(call.id(), result),
+                Err(e) => handle.error(call.id(), e),
+            }
+        }
+    }
+
+    fn forward(&mut self, call: &PluginOpCall<P>) -> PluginResult<PluginCallOutcome<P>> {
+        let name = call.plugin_name().into_owned();
+        let request = self.codec.encode_call(call.call())
+            .map_err(|e| PluginError::RuntimeError(format!("could not encode call to {}: {}", name, e)))?;
+        if !self.connections.contains_key(&name) {
+            let mut connection = (self.connect)(call.plugin())?;
+            if let Some(ref hello) = self.hello {
+                greet(&mut connection, hello, call.plugin())?;
+            }
+            self.connections.insert(name.clone(), connection);
+        }
+        let response = match self.connections.get_mut(&name).unwrap().exchange(&request) {
+            Ok(Some(response)) => response,
+            Ok(None) => {
+                self.connections.remove(&name);
+                return Err(PluginError::Disconnected);
+            }
+            Err(e) => {
+                self.connections.remove(&name);
+                return Err(e);
+            }
+        };
+        self.codec.decode_result(&response)
+            .map_err(|e| PluginError::InvalidPlugin(format!("malformed result from {}: {}", name, e)))
+    }
+}
+
+fn greet<P: PluginData, T: Transport>(connection: &mut T, hello: &Hello, plugin: &P) -> PluginResult<()> {
+    let name = plugin.name_ref();
+    let response = connection.exchange(&hello.encode())?.ok_or(PluginError::Disconnected)?;
+    let theirs = Hello::decode(&response)
+        .map_err(|e| PluginError::InvalidPlugin(format!("malformed hello from {}: {}", name, e)))?;
+    if !hello.version.is_compatible(&theirs.version) {
+        return Err(PluginError::InvalidPlugin(format!("protocol mismatch: host speaks {}, {} speaks {}", hello.version, name, theirs.version)));
+    }
+    if let Some(missing) = plugin.capabilities().into_iter().find(|capability| !theirs.capabilities.contains(capability)) {
+        return Err(PluginError::InvalidPlugin(format!("protocol mismatch: {} lacks {}", name, missing)));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tokio_utils::create_tokio_runtime;
+    use crate::test_utils::DummyPlugin;
+    use crate::framing::write_frame;
+    use crate::runtime::PluginRuntime;
+    use crate::transport::{Codec, FramedTransport, Hello, ProtocolVersion, Remote};
+    use crate::{PluginCallOutcome, PluginError};
+    use std::io::Cursor;
+
+    struct TaggedCodec;
+
+    impl Codec<DummyPlugin> for TaggedCodec {
+        fn encode_call(&self, _call: &()) -> Result<Vec<u8>, String> {
+            Ok(b"call".to_vec())
+        }
+
+        fn decode_result(&self, bytes: &[u8]) -> Result<PluginCallOutcome<DummyPlugin>, String> {
+            let text = String::from_utf8_lossy(bytes.get(1..).unwrap_or_default()).into_owned();
+            match bytes.first() {
+                Some(b'+') => Ok(Ok(text)),
+                Some(b'-') => Ok(Err(text)),
+                _ => Err("missing tag".to_string()),
+            }
+        }
+    }
+
+    #[test]
+    fn execute_over_transport() {
+        let mut dummy_runtime = PluginRuntime::new();
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let remote = Remote::new(TaggedCodec, |_plugin: &DummyPlugin| {
+            let mut replies = Vec::new();
+            for reply in [&b"+hello"[..], b"-nope", b"?"] {
+                write_frame(&mut replies, reply).unwrap();
+            }
+            Ok(FramedTransport::new(Cursor::new(replies), Vec::new()))
+        });
+        let handle2 = runtime.spawn(async move {
+            remote.run(handle)
+        });
+        let plugin = dummy_runtime.load_plugin(DummyPlugin {}).unwrap();
+        assert_eq!(plugin.execute(()), Ok(Ok("hello".to_string())));
+        assert_eq!(plugin.execute(()), Ok(Err("nope".to_string())));
+        assert_eq!(plugin.execute(()), Err(PluginError::InvalidPlugin("malformed result from test: missing tag".to_string())));
+        assert_eq!(plugin.execute(()), Err(PluginError::Disconnected));
+        assert_eq!(plugin.execute(()), Ok(Ok("hello".to_string())));
+        drop(plugin);
+        drop(dummy_runtime);
+        let (_res1, res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+        assert_eq!(res2.unwrap(), Ok(()));
+    }
+
+    #[test]
+    fn negotiate_protocol() {
+        let hello = Hello::new(vec!["greet".to_string()]);
+        assert_eq!(Hello::decode(&hello.encode()), Ok(hello));
+        assert!(Hello::decode(b"hello").is_err());
+
+        let mut dummy_runtime = PluginRuntime::new();
+        let (fut1, handle) = dummy_runtime.run();
+        let runtime = create_tokio_runtime();
+        let handle1 = runtime.spawn(fut1);
+        let mut majors = vec![2, 1].into_iter();
+        let remote = Remote::new(TaggedCodec, move |_plugin: &DummyPlugin| {
+            let theirs = Hello {
+                version: ProtocolVersion { major: majors.next().unwrap_or(1), minor: 3 },
+                capabilities: Vec::new(),
+            };
+            let mut replies = Vec::new();
+            write_frame(&mut replies, &theirs.encode()).unwrap();
+            write_frame(&mut replies, b"+hello").unwrap();
+            Ok(FramedTransport::new(Cursor::new(replies), Vec::new()))
+        }).handshake(Vec::new());
+        let handle2 = runtime.spawn(async move {
+            remote.run(handle)
+        });
+        let plugin = dummy_runtime.load_plugin(DummyPlugin {}).unwrap();
+        assert_eq!(plugin.execute(()), Err(PluginError::InvalidPlugin("protocol mismatch: host speaks 1.0, test speaks 2.3".to_string())));
+        assert_eq!(plugin.execute(()), Ok(Ok("hello".to_string())));
+        drop(plugin);
+        drop(dummy_runtime);
+        let (_res1, res2) = runtime.block_on(async move {
+            tokio::join!(handle1, handle2)
+        });
+        assert_eq!(res2.unwrap(), Ok(()));
+    }
+}